@@ -0,0 +1,7 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Older protoc releases (e.g. Debian's) need this to accept proto3 optional fields
+    tonic_build::configure()
+        .protoc_arg("--experimental_allow_proto3_optional")
+        .compile(&["proto/geyser.proto"], &["proto"])?;
+    Ok(())
+}
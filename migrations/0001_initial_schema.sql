@@ -0,0 +1,70 @@
+-- The schema previously created by setup_database. Everything is IF NOT EXISTS so databases
+-- created before migrations existed can adopt it.
+
+CREATE TABLE IF NOT EXISTS candles (
+    id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    market_name text,
+    start_time timestamptz,
+    end_time timestamptz,
+    resolution text,
+    open double precision,
+    close double precision,
+    high double precision,
+    low double precision,
+    volume double precision,
+    complete bool
+);
+
+CREATE INDEX IF NOT EXISTS idx_market_time_resolution ON candles (market_name, start_time, resolution);
+
+DO $$
+    BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'unique_candles') THEN
+        ALTER TABLE candles ADD CONSTRAINT unique_candles UNIQUE (market_name, start_time, resolution);
+    END IF;
+END $$;
+
+CREATE TABLE IF NOT EXISTS fills (
+    signature text not null,
+    time timestamptz not null,
+    market text not null,
+    open_orders text not null,
+    open_orders_owner text not null,
+    bid bool not null,
+    maker bool not null,
+    native_qty_paid double precision not null,
+    native_qty_received double precision not null,
+    native_fee_or_rebate double precision not null,
+    fee_tier text not null,
+    order_id text not null,
+    log_index int4 not null,
+    CONSTRAINT fills_pk PRIMARY KEY (signature, log_index)
+);
+
+CREATE INDEX IF NOT EXISTS idx_market_time ON fills (market, time);
+CREATE INDEX IF NOT EXISTS idx_open_orders_time ON fills (open_orders, time);
+
+CREATE TABLE IF NOT EXISTS transactions (
+    signature text NOT NULL,
+    program_pk text NOT NULL,
+    block_datetime timestamptz NOT NULL,
+    slot bigint NOT NULL,
+    err bool NOT NULL,
+    processed bool NOT NULL,
+    worker_partition int4 NOT NULL,
+    CONSTRAINT transactions_pk PRIMARY KEY (signature, worker_partition)
+) PARTITION BY LIST (worker_partition);
+
+CREATE INDEX IF NOT EXISTS transactions_processed_err_idx ON ONLY transactions (signature) WHERE processed IS NOT TRUE and err IS NOT TRUE;
+CREATE INDEX IF NOT EXISTS transactions_program_pk_idx ON ONLY transactions USING btree (program_pk, slot DESC);
+
+CREATE TABLE IF NOT EXISTS transactions_0 PARTITION OF transactions FOR VALUES IN (0);
+CREATE TABLE IF NOT EXISTS transactions_1 PARTITION OF transactions FOR VALUES IN (1);
+CREATE TABLE IF NOT EXISTS transactions_2 PARTITION OF transactions FOR VALUES IN (2);
+CREATE TABLE IF NOT EXISTS transactions_3 PARTITION OF transactions FOR VALUES IN (3);
+CREATE TABLE IF NOT EXISTS transactions_4 PARTITION OF transactions FOR VALUES IN (4);
+CREATE TABLE IF NOT EXISTS transactions_5 PARTITION OF transactions FOR VALUES IN (5);
+CREATE TABLE IF NOT EXISTS transactions_6 PARTITION OF transactions FOR VALUES IN (6);
+CREATE TABLE IF NOT EXISTS transactions_7 PARTITION OF transactions FOR VALUES IN (7);
+CREATE TABLE IF NOT EXISTS transactions_8 PARTITION OF transactions FOR VALUES IN (8);
+CREATE TABLE IF NOT EXISTS transactions_9 PARTITION OF transactions FOR VALUES IN (9);
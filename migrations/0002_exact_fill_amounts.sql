@@ -0,0 +1,9 @@
+-- Native amounts are u64 and order ids u128, so store them exactly instead of as doubles/text.
+-- Amounts above 2^53 that were already rounded by double precision can't be recovered.
+
+ALTER TABLE fills
+    ALTER COLUMN native_qty_paid TYPE numeric(20, 0) USING round(native_qty_paid::numeric),
+    ALTER COLUMN native_qty_received TYPE numeric(20, 0) USING round(native_qty_received::numeric),
+    ALTER COLUMN native_fee_or_rebate TYPE numeric(20, 0) USING round(native_fee_or_rebate::numeric),
+    ALTER COLUMN order_id TYPE numeric(39, 0) USING order_id::numeric,
+    ALTER COLUMN fee_tier TYPE int2 USING fee_tier::int2;
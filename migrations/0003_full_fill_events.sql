@@ -0,0 +1,9 @@
+-- The rest of the fill event, plus the slot it landed in. Null for fills stored before this.
+
+ALTER TABLE fills
+    ADD COLUMN IF NOT EXISTS client_order_id numeric(20, 0),
+    ADD COLUMN IF NOT EXISTS referrer_rebate numeric(20, 0),
+    ADD COLUMN IF NOT EXISTS owner_slot int2,
+    ADD COLUMN IF NOT EXISTS slot bigint;
+
+CREATE INDEX IF NOT EXISTS idx_market_client_order_id ON fills (market, client_order_id) WHERE client_order_id IS NOT NULL;
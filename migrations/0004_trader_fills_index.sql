@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS idx_open_orders_owner_time ON fills (open_orders_owner, time);
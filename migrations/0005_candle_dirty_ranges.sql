@@ -0,0 +1,11 @@
+-- Minutes that got fills after their candles were already complete. The batcher rebuilds
+-- the candles covering each range and then deletes it.
+CREATE TABLE IF NOT EXISTS candle_dirty_ranges (
+    id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    market_name text NOT NULL,
+    start_time timestamptz NOT NULL,
+    end_time timestamptz NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX IF NOT EXISTS idx_candle_dirty_ranges_market ON candle_dirty_ranges (market_name);
@@ -0,0 +1,26 @@
+-- The newest signature of each program the signature scraper has caught up to
+CREATE TABLE IF NOT EXISTS scrape_cursors (
+    program_id text PRIMARY KEY,
+    signature text NOT NULL,
+    slot int8 NOT NULL,
+    block_datetime timestamptz NOT NULL,
+    updated_at timestamptz NOT NULL DEFAULT current_timestamp
+);
+
+-- Signatures older than before_signature and newer than until_signature that haven't been
+-- scraped yet, recorded when more signatures arrived between two polls than were paged through.
+-- The scraper moves before_signature back as it pages and sets closed_at once it reaches until.
+CREATE TABLE IF NOT EXISTS scrape_gaps (
+    id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    program_id text NOT NULL,
+    before_signature text NOT NULL,
+    before_slot int8 NOT NULL,
+    until_signature text NOT NULL,
+    until_slot int8 NOT NULL,
+    until_datetime timestamptz NOT NULL,
+    signatures_found int8 NOT NULL DEFAULT 0,
+    detected_at timestamptz NOT NULL DEFAULT current_timestamp,
+    closed_at timestamptz
+);
+
+CREATE INDEX IF NOT EXISTS idx_scrape_gaps_open ON scrape_gaps (program_id, until_slot) WHERE closed_at IS NULL;
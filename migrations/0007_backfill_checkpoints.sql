@@ -0,0 +1,24 @@
+-- How far back each named trade backfill has paged, so an interrupted run resumes from
+-- before_signature. Either the time or the slot range is set.
+CREATE TABLE IF NOT EXISTS backfill_checkpoints (
+    name text PRIMARY KEY,
+    start_time timestamptz,
+    end_time timestamptz,
+    start_slot int8,
+    end_slot int8,
+    markets text[] NOT NULL,
+    before_signature text,
+    before_slot int8,
+    before_datetime timestamptz,
+    signatures_found int8 NOT NULL DEFAULT 0,
+    completed_at timestamptz,
+    created_at timestamptz NOT NULL DEFAULT current_timestamp,
+    updated_at timestamptz NOT NULL DEFAULT current_timestamp
+);
+
+-- Signatures a backfill inserted are only processed by its own fill workers, the scraper's
+-- have no backfill_name. Failed getTransaction calls are counted, and transactions are given
+-- up on after a few attempts.
+ALTER TABLE transactions ADD COLUMN IF NOT EXISTS backfill_name text;
+ALTER TABLE transactions ADD COLUMN IF NOT EXISTS fetch_attempts int4 NOT NULL DEFAULT 0;
+ALTER TABLE transactions ADD COLUMN IF NOT EXISTS fetch_error text;
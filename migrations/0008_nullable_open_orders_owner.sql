@@ -0,0 +1,6 @@
+-- OpenBook v2 doesn't log who owns an open orders account, so its fills are stored without
+-- an owner. They were stored with the default pubkey before, which lumped every v2 trader
+-- together.
+ALTER TABLE fills ALTER COLUMN open_orders_owner DROP NOT NULL;
+UPDATE fills SET open_orders_owner = NULL
+ WHERE open_orders_owner = '11111111111111111111111111111111';
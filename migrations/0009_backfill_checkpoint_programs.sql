@@ -0,0 +1,8 @@
+-- Backfills page through each market program's signatures separately, so a checkpoint is
+-- kept per program. Existing checkpoints were all of OpenBook.
+ALTER TABLE backfill_checkpoints
+    ADD COLUMN IF NOT EXISTS program_id text NOT NULL DEFAULT 'srmqPvymJeFKQ4zGQed1GFppgkRHL9kaELCbyksJtPX';
+ALTER TABLE backfill_checkpoints ALTER COLUMN program_id DROP DEFAULT;
+
+ALTER TABLE backfill_checkpoints DROP CONSTRAINT IF EXISTS backfill_checkpoints_pkey;
+ALTER TABLE backfill_checkpoints ADD PRIMARY KEY (name, program_id);
@@ -1,13 +1,15 @@
 use deadpool_postgres::Object;
 
 use openbook_candles::{
-    database::{initialize::connect_to_database, insert::build_candles_upsert_statement},
+    database::{
+        initialize::connect_to_database, insert::upsert_candles, migrations::check_migrations,
+    },
     structs::{
         candle::Candle,
         markets::{fetch_market_infos, load_markets},
         resolution::Resolution,
     },
-    utils::{AnyhowWrap, Config},
+    utils::Config,
     worker::candle_batching::{
         higher_order_candles::backfill_batch_higher_order_candles,
         minute_candles::backfill_batch_1m_candles,
@@ -33,6 +35,7 @@ async fn main() -> anyhow::Result<()> {
     println!("Backfilling candles for {:?}", markets);
 
     let pool = connect_to_database().await?;
+    check_migrations(&pool).await?;
     for market in market_infos.into_iter() {
         let client = pool.get().await?;
         let minute_candles = backfill_batch_1m_candles(&pool, &market).await?;
@@ -53,11 +56,7 @@ async fn main() -> anyhow::Result<()> {
 
 async fn save_candles(candles: Vec<Candle>, client: Object) -> anyhow::Result<()> {
     if !candles.is_empty() {
-        let upsert_statement = build_candles_upsert_statement(&candles);
-        client
-            .execute(&upsert_statement, &[])
-            .await
-            .map_err_anyhow()?;
+        upsert_candles(&client, &candles).await?;
     }
     Ok(())
 }
@@ -1,141 +1,489 @@
 use anchor_lang::prelude::Pubkey;
-use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use clap::Parser;
 use deadpool_postgres::Pool;
-use log::debug;
 use openbook_candles::{
     database::{
-        initialize::{connect_to_database, setup_database},
-        insert::build_transactions_insert_statement,
+        fetch::fetch_backfill_checkpoints,
+        initialize::connect_to_database,
+        insert::{
+            delete_backfill_checkpoints, insert_backfill_signatures, upsert_backfill_checkpoint,
+        },
+        migrations::check_migrations,
     },
     structs::{
-        markets::{fetch_market_infos, load_markets},
-        transaction::{PgTransaction, NUM_TRANSACTION_PARTITIONS},
+        markets::{fetch_market_infos, load_markets, market_programs, MarketInfo},
+        transaction::{
+            BackfillCheckpoint, BackfillRange, PgTransaction, NUM_TRANSACTION_PARTITIONS,
+        },
+    },
+    utils::Config,
+    worker::trade_fetching::{
+        archive::backfill_from_archive,
+        scrape::scrape_fills_batch,
+        signatures::{RpcSignatureSource, SignatureSource, SIGNATURE_PAGE_LIMIT},
     },
-    utils::{AnyhowWrap, Config, OPENBOOK_KEY},
-    worker::trade_fetching::scrape::scrape_fills,
 };
-use solana_client::{
-    nonblocking::rpc_client::RpcClient, rpc_client::GetConfirmedSignaturesForAddress2Config,
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::commitment_config::CommitmentConfig;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration as WaitDuration, Instant},
 };
-use solana_sdk::{commitment_config::CommitmentConfig, signature::Signature};
-use std::{collections::HashMap, env, str::FromStr};
+
+/// Backfills OpenBook trades over a time or slot range. Progress is checkpointed under
+/// `--name` for each market program, so running the same command again resumes an
+/// interrupted backfill.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Path to the JSON file with the markets to backfill
+    markets_json_path: String,
+
+    /// Oldest block time to backfill, as RFC 3339 or unix seconds. Defaults to a day before --end
+    #[arg(long, value_parser = parse_time)]
+    start: Option<DateTime<Utc>>,
+
+    /// Newest block time to backfill, as RFC 3339 or unix seconds. Defaults to now
+    #[arg(long, value_parser = parse_time)]
+    end: Option<DateTime<Utc>>,
+
+    /// Oldest slot to backfill, instead of a time range
+    #[arg(long, conflicts_with_all = ["start", "end"])]
+    start_slot: Option<u64>,
+
+    /// Newest slot to backfill. Defaults to the current slot
+    #[arg(long, requires = "start_slot")]
+    end_slot: Option<u64>,
+
+    /// Only backfill this market, by name. Can be repeated, defaults to every market in the file
+    #[arg(long = "market")]
+    markets: Vec<String>,
+
+    /// Checkpoint name. Runs with the same name resume where the last one stopped
+    #[arg(long, default_value = "backfill")]
+    name: String,
+
+    /// Discard the checkpoint with this name and start from the end of the range again
+    #[arg(long)]
+    restart: bool,
+
+    /// Read transactions from JSONL archives instead of RPC, optionally `.gz` or `.zst`
+    /// compressed. Directories are read file by file. Can be repeated
+    #[arg(long, conflicts_with_all = ["start", "end", "start_slot", "end_slot", "restart"])]
+    archive: Vec<PathBuf>,
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(secs) = s.parse::<i64>() {
+        return Utc
+            .timestamp_opt(secs, 0)
+            .single()
+            .ok_or_else(|| format!("invalid timestamp: {}", s));
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| format!("invalid time {}: {}", s, e))
+}
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
-    let args: Vec<String> = env::args().collect();
-    assert!(args.len() == 2);
-
-    let path_to_markets_json = &args[1];
-    // let num_days = args[2].parse::<i64>().unwrap(); // TODO: implement
-    let num_days = 1;
+    let args = Args::parse();
     let rpc_url: String = dotenv::var("RPC_URL").unwrap();
 
-    let config = Config {
-        rpc_url: rpc_url.clone(),
-    };
-    let markets = load_markets(path_to_markets_json);
-    let market_infos = fetch_market_infos(&config, markets.clone()).await?;
-    let mut target_markets = HashMap::new();
-    for m in market_infos.clone() {
-        target_markets.insert(Pubkey::from_str(&m.address)?, m.name);
+    let pool = connect_to_database().await?;
+    check_migrations(&pool).await?;
+
+    if !args.archive.is_empty() {
+        let (target_markets, programs) =
+            load_target_markets(&args.markets_json_path, &args.markets, &rpc_url).await?;
+        return backfill_archives(&pool, &args.archive, &target_markets, &programs).await;
     }
-    println!("{:?}", target_markets);
 
-    let pool = connect_to_database().await?;
-    setup_database(&pool).await?;
+    let mut checkpoints = fetch_backfill_checkpoints(&pool, &args.name).await?;
+    if args.restart {
+        let client = pool.get().await?;
+        delete_backfill_checkpoints(&client, &args.name).await?;
+        checkpoints.clear();
+    }
+    // Every program's checkpoint has the same range and markets
+    let (range, markets) = match checkpoints.first() {
+        Some(checkpoint) => {
+            check_resumable(&args, checkpoint)?;
+            (checkpoint.range, checkpoint.markets.clone())
+        }
+        None => (backfill_range(&args, &rpc_url).await?, args.markets.clone()),
+    };
+
+    let (target_markets, programs) =
+        load_target_markets(&args.markets_json_path, &markets, &rpc_url).await?;
+
+    let mut program_checkpoints = vec![];
+    for program_id in programs {
+        let program = program_id.to_string();
+        let checkpoint = match checkpoints.iter().position(|c| c.program_id == program) {
+            Some(idx) => {
+                let checkpoint = checkpoints.swap_remove(idx);
+                println!(
+                    "Resuming backfill {} of {} for {} at {:.1}%",
+                    checkpoint.name,
+                    checkpoint.range,
+                    program,
+                    checkpoint.progress() * 100.0
+                );
+                checkpoint
+            }
+            None => {
+                let checkpoint = BackfillCheckpoint::new(
+                    args.name.clone(),
+                    program.clone(),
+                    range,
+                    markets.clone(),
+                );
+                let client = pool.get().await?;
+                upsert_backfill_checkpoint(&client, &checkpoint).await?;
+                println!(
+                    "Starting backfill {} of {} for {}",
+                    checkpoint.name, checkpoint.range, program
+                );
+                checkpoint
+            }
+        };
+        program_checkpoints.push((program_id, checkpoint));
+    }
 
     let mut handles = vec![];
+    let signatures_done = Arc::new(AtomicBool::new(false));
 
-    let rpc_clone = rpc_url.clone();
+    // Programs are paged through concurrently, fills are only done once all of them are
     let pool_clone = pool.clone();
+    let rpc_url_clone = rpc_url.clone();
+    let done_clone = signatures_done.clone();
     handles.push(tokio::spawn(async move {
-        fetch_signatures(rpc_clone, &pool_clone, num_days)
-            .await
-            .unwrap();
+        let results =
+            futures::future::join_all(program_checkpoints.into_iter().map(|(program_id, c)| {
+                let source = RpcSignatureSource::new(rpc_url_clone.clone(), program_id);
+                let pool = pool_clone.clone();
+                async move { fetch_signatures(&source, &pool, c).await }
+            }))
+            .await;
+        done_clone.store(true, Ordering::SeqCst);
+        results.into_iter().collect::<anyhow::Result<()>>()
     }));
 
-    // Low priority improvement: batch fills into 1000's per worker
+    // Without --market every market in the file is parsed
+    let all_markets = markets.is_empty();
     for id in 0..NUM_TRANSACTION_PARTITIONS {
-        let rpc_clone = rpc_url.clone();
+        let rpc_url_clone = rpc_url.clone();
         let pool_clone = pool.clone();
         let markets_clone = target_markets.clone();
+        let done_clone = signatures_done.clone();
+        let name = args.name.clone();
         handles.push(tokio::spawn(async move {
-            scrape_fills(id as i32, rpc_clone, &pool_clone, &markets_clone)
-                .await
-                .unwrap();
+            scrape_backfill_fills(
+                id as i32,
+                &name,
+                all_markets,
+                rpc_url_clone,
+                &pool_clone,
+                &markets_clone,
+                &done_clone,
+            )
+            .await
         }));
     }
 
-    // TODO: spawn status thread
+    for result in futures::future::join_all(handles).await {
+        result??;
+    }
+    println!("Backfill {} complete", args.name);
+    Ok(())
+}
+
+/// Maps the address of each market to backfill to its name, along with the programs that own
+/// them. No names means every market
+async fn load_target_markets(
+    markets_json_path: &str,
+    names: &[String],
+    rpc_url: &str,
+) -> anyhow::Result<(HashMap<Pubkey, MarketInfo>, Vec<Pubkey>)> {
+    let config = Config {
+        rpc_url: rpc_url.to_string(),
+    };
+    let mut markets = load_markets(markets_json_path);
+    if !names.is_empty() {
+        if let Some(missing) = names
+            .iter()
+            .find(|name| !markets.iter().any(|m| &m.name == *name))
+        {
+            anyhow::bail!("market {} is not in the markets file", missing);
+        }
+        markets.retain(|m| names.contains(&m.name));
+    }
+    let market_infos = fetch_market_infos(&config, markets).await?;
+    let programs = market_programs(&market_infos);
+    let mut target_markets = HashMap::new();
+    for m in market_infos {
+        println!("{}: {}", m.name, m.address);
+        target_markets.insert(Pubkey::from_str(&m.address)?, m);
+    }
+    Ok((target_markets, programs))
+}
+
+async fn backfill_archives(
+    pool: &Pool,
+    paths: &[PathBuf],
+    target_markets: &HashMap<Pubkey, MarketInfo>,
+    programs: &[Pubkey],
+) -> anyhow::Result<()> {
+    let mut files = vec![];
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = std::fs::read_dir(path)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<Result<Vec<PathBuf>, _>>()?;
+            entries.retain(|p| p.is_file());
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(path.clone());
+        }
+    }
 
-    futures::future::join_all(handles).await;
+    let started = Instant::now();
+    for (idx, file) in files.iter().enumerate() {
+        let stats = backfill_from_archive(pool, file, target_markets, programs).await?;
+        println!(
+            "{}/{} {}: {} transactions, {} fills, {} skipped",
+            idx + 1,
+            files.len(),
+            file.display(),
+            stats.transactions,
+            stats.fills,
+            stats.skipped
+        );
+        let eta = estimate_time_left(
+            0.0,
+            (idx + 1) as f64 / files.len() as f64,
+            started.elapsed(),
+        );
+        if let Some(eta) = eta {
+            println!(
+                "{} minutes ~ {} days remaining",
+                eta.num_minutes(),
+                eta.num_days()
+            );
+        }
+    }
+    println!("Backfilled {} archive files", files.len());
     Ok(())
 }
 
-pub async fn fetch_signatures(rpc_url: String, pool: &Pool, num_days: i64) -> anyhow::Result<()> {
-    let mut before_sig: Option<Signature> = None;
-    let mut now_time = Utc::now().timestamp();
-    let end_time = (Utc::now() - Duration::days(num_days)).timestamp();
-    let rpc_client = RpcClient::new_with_commitment(rpc_url.clone(), CommitmentConfig::confirmed());
-
-    while now_time > end_time {
-        let rpc_config = GetConfirmedSignaturesForAddress2Config {
-            before: before_sig,
-            until: None,
-            limit: None,
-            commitment: Some(CommitmentConfig::confirmed()),
-        };
+/// Like the worker's `scrape_fills`, but only for the transactions the backfill inserted, and
+/// stops once the signatures are fetched and all of their transactions are processed, or
+/// failed too many times
+async fn scrape_backfill_fills(
+    worker_id: i32,
+    name: &str,
+    all_markets: bool,
+    rpc_url: String,
+    pool: &Pool,
+    target_markets: &HashMap<Pubkey, MarketInfo>,
+    signatures_done: &AtomicBool,
+) -> anyhow::Result<()> {
+    let rpc_client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
+    loop {
+        let done = signatures_done.load(Ordering::SeqCst);
+        let num_txns = scrape_fills_batch(
+            worker_id,
+            Some(name),
+            all_markets,
+            &rpc_client,
+            pool,
+            target_markets,
+        )
+        .await?;
+        if num_txns == 0 {
+            if done {
+                return Ok(());
+            }
+            tokio::time::sleep(WaitDuration::from_secs(1)).await;
+        }
+    }
+}
+
+async fn backfill_range(args: &Args, rpc_url: &str) -> anyhow::Result<BackfillRange> {
+    let range = match args.start_slot {
+        Some(start) => {
+            let end = match args.end_slot {
+                Some(end) => end,
+                None => {
+                    RpcClient::new_with_commitment(
+                        rpc_url.to_string(),
+                        CommitmentConfig::confirmed(),
+                    )
+                    .get_slot()
+                    .await?
+                }
+            };
+            if start > end {
+                anyhow::bail!("--start-slot {} is after --end-slot {}", start, end);
+            }
+            BackfillRange::Slot { start, end }
+        }
+        None => {
+            let end = args.end.unwrap_or_else(Utc::now);
+            let start = args.start.unwrap_or(end - Duration::days(1));
+            if start > end {
+                anyhow::bail!("--start {} is after --end {}", start, end);
+            }
+            BackfillRange::Time { start, end }
+        }
+    };
+    Ok(range)
+}
 
-        let sigs = match rpc_client
-            .get_signatures_for_address_with_config(&OPENBOOK_KEY, rpc_config)
+/// A checkpoint only resumes runs that ask for the same range and markets, or don't say
+fn check_resumable(args: &Args, checkpoint: &BackfillCheckpoint) -> anyhow::Result<()> {
+    let same_range = match checkpoint.range {
+        BackfillRange::Time { start, end } => {
+            args.start_slot.is_none()
+                && args.start.map_or(true, |s| s == start)
+                && args.end.map_or(true, |e| e == end)
+        }
+        BackfillRange::Slot { start, end } => {
+            args.start.is_none()
+                && args.end.is_none()
+                && args.start_slot.map_or(true, |s| s == start)
+                && args.end_slot.map_or(true, |e| e == end)
+        }
+    };
+    let mut requested = args.markets.clone();
+    let mut checkpointed = checkpoint.markets.clone();
+    requested.sort();
+    checkpointed.sort();
+    let same_markets = requested.is_empty() || requested == checkpointed;
+    if !same_range || !same_markets {
+        anyhow::bail!(
+            "backfill {} is of {} for markets {:?}, pass --restart to replace it or pick another --name",
+            checkpoint.name,
+            checkpoint.range,
+            checkpoint.markets
+        );
+    }
+    Ok(())
+}
+
+/// Pages back through a program's signatures from its checkpoint, writing those in the range
+async fn fetch_signatures(
+    source: &dyn SignatureSource,
+    pool: &Pool,
+    mut checkpoint: BackfillCheckpoint,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let initial_progress = checkpoint.progress();
+    let program_id = Pubkey::from_str(&checkpoint.program_id)?;
+
+    while !checkpoint.completed {
+        let sigs = match source
+            .get_signatures(
+                checkpoint.before_signature.as_deref(),
+                None,
+                SIGNATURE_PAGE_LIMIT,
+            )
             .await
         {
             Ok(sigs) => sigs,
             Err(e) => {
-                println!("Error fetching signatures: {}", e);
+                println!(
+                    "Error fetching signatures of {}: {}",
+                    checkpoint.program_id, e
+                );
+                tokio::time::sleep(WaitDuration::from_secs(1)).await;
                 continue;
             }
         };
-        if sigs.is_empty() {
-            println!("No signatures found, trying again");
-            continue;
-        }
-        let last = sigs.last().unwrap();
-        let last_time = last.block_time.unwrap();
-        let last_signature = last.signature.clone();
         let transactions = sigs
             .into_iter()
-            .map(PgTransaction::from_rpc_confirmed_transaction)
+            .map(|s| PgTransaction::from_rpc_confirmed_transaction(s, &program_id))
             .collect::<Vec<PgTransaction>>();
 
-        if transactions.is_empty() {
-            println!("No transactions found, trying again");
+        // An empty page means the program's first signature was reached
+        let range = checkpoint.range;
+        checkpoint.completed = transactions
+            .last()
+            .map_or(true, |t| range.starts_after(t.slot, t.block_datetime));
+        if let Some(oldest) = transactions.last() {
+            checkpoint.page_back_to(oldest);
         }
-        debug!("writing: {:?} txns to DB\n", transactions.len());
-        let upsert_statement = build_transactions_insert_statement(transactions);
-        let client = pool.get().await?;
-        client
-            .execute(&upsert_statement, &[])
-            .await
-            .map_err_anyhow()?;
+        let in_range = transactions
+            .into_iter()
+            .filter(|t| range.contains(t.slot, t.block_datetime))
+            .collect::<Vec<PgTransaction>>();
+        checkpoint.signatures_found += in_range.len() as i64;
+        insert_backfill_signatures(pool, &in_range, &checkpoint).await?;
 
-        now_time = last_time;
-        before_sig = Some(Signature::from_str(&last_signature)?);
-        let time_left = backfill_time_left(now_time, end_time);
+        let progress = checkpoint.progress();
+        let eta = estimate_time_left(initial_progress, progress, started.elapsed())
+            .map_or("unknown".to_string(), |d| {
+                format!("{} minutes ~ {} days", d.num_minutes(), d.num_days())
+            });
         println!(
-            "{} minutes ~ {} days remaining in the backfill\n",
-            time_left.num_minutes(),
-            time_left.num_days()
+            "{}: {:.1}% of {}, {} signatures found, {} remaining",
+            checkpoint.program_id,
+            progress * 100.0,
+            range,
+            checkpoint.signatures_found,
+            eta
         );
     }
+    println!(
+        "Fetched all {} signatures of {}",
+        checkpoint.signatures_found, checkpoint.program_id
+    );
     Ok(())
 }
 
-fn backfill_time_left(current_time: i64, backfill_end: i64) -> Duration {
-    let naive_cur = NaiveDateTime::from_timestamp_millis(current_time * 1000).unwrap();
-    let naive_bf = NaiveDateTime::from_timestamp_millis(backfill_end * 1000).unwrap();
-    let cur_date = DateTime::<Utc>::from_utc(naive_cur, Utc);
-    let bf_date = DateTime::<Utc>::from_utc(naive_bf, Utc);
-    cur_date - bf_date
+/// Extrapolates from the progress made since this run started
+fn estimate_time_left(
+    initial_progress: f64,
+    progress: f64,
+    elapsed: WaitDuration,
+) -> Option<Duration> {
+    let made = progress - initial_progress;
+    if made <= 0.0 {
+        return None;
+    }
+    let secs_left = elapsed.as_secs_f64() * (1.0 - progress) / made;
+    Some(Duration::seconds(secs_left.round() as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_left_extrapolates_from_this_run() {
+        // Resumed at 20%, and got to 30% in 10 minutes
+        let eta = estimate_time_left(0.2, 0.3, WaitDuration::from_secs(600)).unwrap();
+        assert_eq!(eta.num_minutes(), 70);
+        assert_eq!(
+            estimate_time_left(0.2, 0.2, WaitDuration::from_secs(600)),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_rfc3339_and_unix_times() {
+        let expected = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        assert_eq!(parse_time("2023-03-01T00:00:00Z").unwrap(), expected);
+        assert_eq!(parse_time("1677628800").unwrap(), expected);
+        assert!(parse_time("March").is_err());
+    }
 }
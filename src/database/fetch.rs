@@ -1,13 +1,16 @@
 use crate::structs::{
-    candle::Candle,
+    candle::{Candle, CandleDirtyRange},
     coingecko::{PgCoinGecko24HighLow, PgCoinGecko24HourVolume},
     openbook::PgOpenBookFill,
     resolution::Resolution,
-    trader::PgTrader,
-    transaction::PgTransaction,
+    trader::{PgTrader, PgTraderFill, PgUsdTrader, UsdValuation},
+    trades::{FillFilter, PgTrade, TradeCursor},
+    transaction::{BackfillCheckpoint, PgTransaction, ScrapeCursor, ScrapeGap, MAX_FETCH_ATTEMPTS},
 };
 use chrono::{DateTime, Utc};
 use deadpool_postgres::{GenericClient, Pool};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
 
 pub async fn fetch_earliest_fill(
     pool: &Pool,
@@ -21,7 +24,11 @@ pub async fn fetch_earliest_fill(
         maker as "maker!",
         native_qty_paid as "native_qty_paid!",
         native_qty_received as "native_qty_received!",
-        native_fee_or_rebate as "native_fee_or_rebate!" 
+        native_fee_or_rebate as "native_fee_or_rebate!",
+        client_order_id,
+        referrer_rebate,
+        owner_slot,
+        slot
         from fills 
         where market = $1 
         and maker = true
@@ -30,7 +37,7 @@ pub async fn fetch_earliest_fill(
     let row = client.query_opt(stmt, &[&market_address_string]).await?;
 
     match row {
-        Some(r) => Ok(Some(PgOpenBookFill::from_row(r))),
+        Some(r) => Ok(Some(PgOpenBookFill::from_row(r)?)),
         None => Ok(None),
     }
 }
@@ -49,7 +56,11 @@ pub async fn fetch_fills_from(
          maker as "maker!",
          native_qty_paid as "native_qty_paid!",
          native_qty_received as "native_qty_received!",
-         native_fee_or_rebate as "native_fee_or_rebate!" 
+         native_fee_or_rebate as "native_fee_or_rebate!",
+         client_order_id,
+         referrer_rebate,
+         owner_slot,
+         slot
          from fills 
          where market = $1
          and time >= $2::timestamptz
@@ -60,7 +71,209 @@ pub async fn fetch_fills_from(
     let rows = client
         .query(stmt, &[&market_address_string, &start_time, &end_time])
         .await?;
-    Ok(rows.into_iter().map(PgOpenBookFill::from_row).collect())
+    rows.into_iter().map(PgOpenBookFill::from_row).collect()
+}
+
+/// Fills for a market, newest first, starting after `before` if given. Only taker fills are
+/// returned unless the filter asks for a `client_order_id`, which could be on either side.
+pub async fn fetch_trades(
+    pool: &Pool,
+    market_address_string: &str,
+    limit: i64,
+    before: Option<&TradeCursor>,
+    filter: &FillFilter,
+) -> anyhow::Result<Vec<PgTrade>> {
+    let client = pool.get().await?;
+
+    let stmt = r#"SELECT 
+        time as "time!",
+        bid as "bid!",
+        maker as "maker!",
+        native_qty_paid as "native_qty_paid!",
+        native_qty_received as "native_qty_received!",
+        native_fee_or_rebate as "native_fee_or_rebate!",
+        client_order_id,
+        referrer_rebate,
+        owner_slot,
+        slot,
+        signature,
+        log_index
+        from fills 
+        where market = $1
+        and ($2::timestamptz IS NULL OR (time, signature, log_index) < ($2, $3, $4))
+        and ($5::numeric IS NULL OR client_order_id = $5)
+        and ($5::numeric IS NOT NULL OR maker = false)
+        and ($6::int2 IS NULL OR owner_slot = $6)
+        and ($7::bool IS NULL OR (coalesce(referrer_rebate, 0) > 0) = $7)
+        ORDER BY time desc, signature desc, log_index desc
+        LIMIT $8"#;
+
+    let rows = client
+        .query(
+            stmt,
+            &[
+                &market_address_string,
+                &before.map(|c| c.time()),
+                &before.map(|c| c.signature.clone()),
+                &before.map(|c| c.log_index),
+                &filter.client_order_id.map(Decimal::from),
+                &filter.owner_slot.map(|s| s as i16),
+                &filter.referred,
+                &limit,
+            ],
+        )
+        .await?;
+    rows.into_iter().map(PgTrade::from_row).collect()
+}
+
+/// Taker fills for a market in a time range, newest first. `bid` filters by the taker's side.
+pub async fn fetch_trades_between(
+    pool: &Pool,
+    market_address_string: &str,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    bid: Option<bool>,
+    limit: i64,
+) -> anyhow::Result<Vec<PgTrade>> {
+    let client = pool.get().await?;
+
+    let stmt = r#"SELECT 
+        time as "time!",
+        bid as "bid!",
+        maker as "maker!",
+        native_qty_paid as "native_qty_paid!",
+        native_qty_received as "native_qty_received!",
+        native_fee_or_rebate as "native_fee_or_rebate!",
+        client_order_id,
+        referrer_rebate,
+        owner_slot,
+        slot,
+        signature,
+        log_index
+        from fills 
+        where market = $1
+        and time >= $2::timestamptz
+        and time < $3::timestamptz
+        and maker = false
+        and ($4::bool IS NULL OR bid = $4)
+        ORDER BY time desc, signature desc, log_index desc
+        LIMIT $5"#;
+
+    let rows = client
+        .query(
+            stmt,
+            &[&market_address_string, &start_time, &end_time, &bid, &limit],
+        )
+        .await?;
+    rows.into_iter().map(PgTrade::from_row).collect()
+}
+
+/// Fills of a trader, matched on either their owner or open orders account, newest first
+pub async fn fetch_trader_fills(
+    pool: &Pool,
+    owner: &str,
+    market_address_string: Option<&str>,
+    start_time: Option<DateTime<Utc>>,
+    end_time: Option<DateTime<Utc>>,
+    before: Option<&TradeCursor>,
+    limit: i64,
+) -> anyhow::Result<Vec<PgTraderFill>> {
+    let client = pool.get().await?;
+
+    let stmt = r#"SELECT 
+        time as "time!",
+        bid as "bid!",
+        maker as "maker!",
+        native_qty_paid as "native_qty_paid!",
+        native_qty_received as "native_qty_received!",
+        native_fee_or_rebate as "native_fee_or_rebate!",
+        client_order_id,
+        referrer_rebate,
+        owner_slot,
+        slot,
+        signature,
+        log_index,
+        market,
+        open_orders
+        from fills 
+        where (open_orders_owner = $1 OR open_orders = $1)
+        and ($2::text IS NULL OR market = $2)
+        and ($3::timestamptz IS NULL OR time >= $3)
+        and ($4::timestamptz IS NULL OR time < $4)
+        and ($5::timestamptz IS NULL OR (time, signature, log_index) < ($5, $6, $7))
+        ORDER BY time desc, signature desc, log_index desc
+        LIMIT $8"#;
+
+    let rows = client
+        .query(
+            stmt,
+            &[
+                &owner,
+                &market_address_string,
+                &start_time,
+                &end_time,
+                &before.map(|c| c.time()),
+                &before.map(|c| c.signature.clone()),
+                &before.map(|c| c.log_index),
+                &limit,
+            ],
+        )
+        .await?;
+    rows.into_iter().map(PgTraderFill::from_row).collect()
+}
+
+/// Fills of a trader in a time range, oldest first, starting after `after` if given
+pub async fn fetch_trader_fills_between(
+    pool: &Pool,
+    owner: &str,
+    market_address_string: Option<&str>,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    after: Option<&TradeCursor>,
+    limit: i64,
+) -> anyhow::Result<Vec<PgTraderFill>> {
+    let client = pool.get().await?;
+
+    let stmt = r#"SELECT 
+        time as "time!",
+        bid as "bid!",
+        maker as "maker!",
+        native_qty_paid as "native_qty_paid!",
+        native_qty_received as "native_qty_received!",
+        native_fee_or_rebate as "native_fee_or_rebate!",
+        client_order_id,
+        referrer_rebate,
+        owner_slot,
+        slot,
+        signature,
+        log_index,
+        market,
+        open_orders
+        from fills 
+        where (open_orders_owner = $1 OR open_orders = $1)
+        and ($2::text IS NULL OR market = $2)
+        and time >= $3
+        and time < $4
+        and ($5::timestamptz IS NULL OR (time, signature, log_index) > ($5, $6, $7))
+        ORDER BY time asc, signature asc, log_index asc
+        LIMIT $8"#;
+
+    let rows = client
+        .query(
+            stmt,
+            &[
+                &owner,
+                &market_address_string,
+                &start_time,
+                &end_time,
+                &after.map(|c| c.time()),
+                &after.map(|c| c.signature.clone()),
+                &after.map(|c| c.log_index),
+                &limit,
+            ],
+        )
+        .await?;
+    rows.into_iter().map(PgTraderFill::from_row).collect()
 }
 
 pub async fn fetch_latest_finished_candle(
@@ -97,6 +310,24 @@ pub async fn fetch_latest_finished_candle(
     }
 }
 
+pub async fn fetch_candle_dirty_ranges(
+    pool: &Pool,
+    market_name: &str,
+) -> anyhow::Result<Vec<CandleDirtyRange>> {
+    let client = pool.get().await?;
+
+    let stmt = r#"SELECT 
+        id as "id!",
+        start_time as "start_time!",
+        end_time as "end_time!"
+        from candle_dirty_ranges
+        where market_name = $1
+        ORDER BY start_time asc"#;
+
+    let rows = client.query(stmt, &[&market_name]).await?;
+    Ok(rows.into_iter().map(CandleDirtyRange::from_row).collect())
+}
+
 /// Fetches all of the candles for the given market and resoultion, starting from the earliest.
 /// Note that this function will fetch ALL candles.
 pub async fn fetch_earliest_candles(
@@ -171,11 +402,48 @@ pub async fn fetch_candles_from(
     Ok(rows.into_iter().map(Candle::from_row).collect())
 }
 
+/// Fetches the latest candle that starts before the given time
+pub async fn fetch_candle_before(
+    pool: &Pool,
+    market_name: &str,
+    resolution: Resolution,
+    time: DateTime<Utc>,
+) -> anyhow::Result<Option<Candle>> {
+    let client = pool.get().await?;
+
+    let stmt = r#"SELECT 
+        market_name as "market_name!",
+        start_time as "start_time!",
+        end_time as "end_time!",
+        resolution as "resolution!",
+        open as "open!",
+        close as "close!",
+        high as "high!",
+        low as "low!",
+        volume as "volume!",
+        complete as "complete!"
+        from candles
+        where market_name = $1
+        and resolution = $2
+        and start_time < $3
+        ORDER BY start_time desc LIMIT 1"#;
+
+    let row = client
+        .query_opt(stmt, &[&market_name, &resolution.to_string(), &time])
+        .await?;
+
+    match row {
+        Some(r) => Ok(Some(Candle::from_row(r))),
+        None => Ok(None),
+    }
+}
+
 pub async fn fetch_top_traders_by_base_volume_from(
     pool: &Pool,
     market_address_string: &str,
     start_time: DateTime<Utc>,
     end_time: DateTime<Utc>,
+    filter: &FillFilter,
 ) -> anyhow::Result<Vec<PgTrader>> {
     let client = pool.get().await?;
 
@@ -186,11 +454,16 @@ pub async fn fetch_top_traders_by_base_volume_from(
             ) as "raw_ask_size!",
             sum(
             native_qty_received * CASE bid WHEN true THEN 1 WHEN false THEN 0 END
-            ) as "raw_bid_size!"
+            ) as "raw_bid_size!",
+            sum(coalesce(referrer_rebate, 0)) as "raw_referrer_rebate!"
         FROM fills
     WHERE  market = $1
             AND time >= $2
             AND time < $3
+            AND ($4::numeric IS NULL OR client_order_id = $4)
+            AND ($5::int2 IS NULL OR owner_slot = $5)
+            AND ($6::bool IS NULL OR (coalesce(referrer_rebate, 0) > 0) = $6)
+            AND open_orders_owner IS NOT NULL
     GROUP  BY open_orders_owner
     ORDER  BY 
         sum(native_qty_paid * CASE bid WHEN true THEN 0 WHEN false THEN 1 END) 
@@ -200,7 +473,17 @@ pub async fn fetch_top_traders_by_base_volume_from(
     LIMIT 10000"#;
 
     let rows = client
-        .query(stmt, &[&market_address_string, &start_time, &end_time])
+        .query(
+            stmt,
+            &[
+                &market_address_string,
+                &start_time,
+                &end_time,
+                &filter.client_order_id.map(Decimal::from),
+                &filter.owner_slot.map(|s| s as i16),
+                &filter.referred,
+            ],
+        )
         .await?;
 
     Ok(rows.into_iter().map(PgTrader::from_row).collect())
@@ -211,6 +494,7 @@ pub async fn fetch_top_traders_by_quote_volume_from(
     market_address_string: &str,
     start_time: DateTime<Utc>,
     end_time: DateTime<Utc>,
+    filter: &FillFilter,
 ) -> anyhow::Result<Vec<PgTrader>> {
     let client = pool.get().await?;
 
@@ -221,11 +505,16 @@ pub async fn fetch_top_traders_by_quote_volume_from(
             ) as "raw_ask_size!",
             sum(
                 native_qty_paid * CASE bid WHEN true THEN 1 WHEN false THEN 0 END
-            ) as "raw_bid_size!"
+            ) as "raw_bid_size!",
+            sum(coalesce(referrer_rebate, 0)) as "raw_referrer_rebate!"
           FROM fills
      WHERE  market = $1
             AND time >= $2
             AND time < $3
+            AND ($4::numeric IS NULL OR client_order_id = $4)
+            AND ($5::int2 IS NULL OR owner_slot = $5)
+            AND ($6::bool IS NULL OR (coalesce(referrer_rebate, 0) > 0) = $6)
+            AND open_orders_owner IS NOT NULL
      GROUP  BY open_orders_owner
      ORDER  BY 
         sum(native_qty_received * CASE bid WHEN true THEN 0 WHEN false THEN 1 END) 
@@ -235,12 +524,119 @@ pub async fn fetch_top_traders_by_quote_volume_from(
     LIMIT 10000"#;
 
     let rows = client
-        .query(stmt, &[&market_address_string, &start_time, &end_time])
+        .query(
+            stmt,
+            &[
+                &market_address_string,
+                &start_time,
+                &end_time,
+                &filter.client_order_id.map(Decimal::from),
+                &filter.owner_slot.map(|s| s as i16),
+                &filter.referred,
+            ],
+        )
         .await?;
 
     Ok(rows.into_iter().map(PgTrader::from_row).collect())
 }
 
+/// Top traders across markets by quote volume in USD. Each hour of a trader's volume is
+/// converted at the latest 1H close of the market's reference market, if it has one.
+#[allow(clippy::too_many_arguments)]
+pub async fn fetch_top_traders_by_usd_volume_from(
+    pool: &Pool,
+    valuations: &[UsdValuation<'_>],
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    maker: Option<bool>,
+    filter: &FillFilter,
+    limit: i64,
+    offset: i64,
+) -> anyhow::Result<Vec<PgUsdTrader>> {
+    let client = pool.get().await?;
+
+    let stmt = r#"WITH trader_hours AS (
+            SELECT
+                open_orders_owner,
+                market,
+                date_trunc('hour', time) as hour,
+                sum(
+                    CASE bid WHEN true THEN native_qty_paid ELSE native_qty_received END
+                ) as raw_quote_size,
+                sum(coalesce(referrer_rebate, 0)) as raw_referrer_rebate
+              FROM fills
+             WHERE market = any($1)
+                AND time >= $4
+                AND time < $5
+                AND ($6::bool IS NULL OR maker = $6)
+                AND ($7::numeric IS NULL OR client_order_id = $7)
+                AND ($8::int2 IS NULL OR owner_slot = $8)
+                AND ($9::bool IS NULL OR (coalesce(referrer_rebate, 0) > 0) = $9)
+                AND open_orders_owner IS NOT NULL
+             GROUP BY open_orders_owner, market, hour
+        )
+        SELECT
+            t.open_orders_owner,
+            sum(
+                (t.raw_quote_size / power(10::numeric, m.quote_decimals))::float8
+                * coalesce(c.close, 1)
+            ) as "usd_volume!",
+            sum(
+                (t.raw_referrer_rebate / power(10::numeric, m.quote_decimals))::float8
+                * coalesce(c.close, 1)
+            ) as "usd_referrer_rebate!"
+          FROM trader_hours t
+          JOIN unnest($1::text[], $2::int2[], $3::text[])
+            AS m(market, quote_decimals, reference_market) ON m.market = t.market
+          LEFT JOIN LATERAL (
+            SELECT close FROM candles
+             WHERE market_name = m.reference_market
+                AND resolution = $10
+                AND start_time <= t.hour
+             ORDER BY start_time desc
+             LIMIT 1
+          ) c ON true
+         WHERE m.reference_market IS NULL OR c.close IS NOT NULL
+         GROUP BY t.open_orders_owner
+         ORDER BY 2 DESC, t.open_orders_owner
+         LIMIT $11 OFFSET $12"#;
+
+    let addresses: Vec<&str> = valuations
+        .iter()
+        .map(|v| v.market.address.as_str())
+        .collect();
+    let quote_decimals: Vec<i16> = valuations
+        .iter()
+        .map(|v| v.market.quote_decimals as i16)
+        .collect();
+    let reference_markets: Vec<Option<&str>> = valuations
+        .iter()
+        .map(|v| v.reference_market.as_deref())
+        .collect();
+
+    let rows = client
+        .query(
+            stmt,
+            &[
+                &addresses,
+                &quote_decimals,
+                &reference_markets,
+                &start_time,
+                &end_time,
+                &maker,
+                &filter.client_order_id.map(Decimal::from),
+                &filter.owner_slot.map(|s| s as i16),
+                &filter.referred,
+                &Resolution::R1h.to_string(),
+                &limit,
+                &offset,
+            ],
+        )
+        .await?;
+
+    Ok(rows.into_iter().map(PgUsdTrader::from_row).collect())
+}
+
 pub async fn fetch_coingecko_24h_volume(
     pool: &Pool,
     market_address_strings: &Vec<&str>,
@@ -322,10 +718,35 @@ pub async fn fetch_coingecko_24h_high_low(
         .collect())
 }
 
+/// The close of each market's last minute candle from at least a day ago, keyed by market name.
+/// Markets without a candle that old are left out.
+pub async fn fetch_closes_24h_ago(
+    pool: &Pool,
+    market_names: &Vec<&str>,
+) -> anyhow::Result<HashMap<String, f64>> {
+    let client = pool.get().await?;
+
+    let stmt = r#"SELECT DISTINCT ON (market_name)
+            market_name as "market_name!",
+            "close" as "close!"
+        FROM candles
+        WHERE "resolution" = '1M'
+        AND market_name = any($1)
+        AND start_time <= current_timestamp - interval '1 day'
+        ORDER BY market_name, start_time desc"#;
+
+    let rows = client.query(stmt, &[&market_names]).await?;
+
+    Ok(rows.into_iter().map(|r| (r.get(0), r.get(1))).collect())
+}
+
 /// Fetches unprocessed, non-error transactions for the specified worker partition.
 /// Pulls at most 50 transactions at a time.
+/// Unprocessed transactions of the partition that were inserted by the named backfill, or by
+/// the scraper if there's no name
 pub async fn fetch_worker_transactions(
     worker_id: i32,
+    backfill_name: Option<&str>,
     pool: &Pool,
 ) -> anyhow::Result<Vec<PgTransaction>> {
     let client = pool.get().await?;
@@ -335,9 +756,81 @@ pub async fn fetch_worker_transactions(
             where worker_partition = $1
             and err = false 
             and processed = false
+            and backfill_name IS NOT DISTINCT FROM $2
+            and fetch_attempts < $3
             LIMIT 50"#;
 
-    let rows = client.query(stmt, &[&worker_id]).await?;
+    let rows = client
+        .query(stmt, &[&worker_id, &backfill_name, &MAX_FETCH_ATTEMPTS])
+        .await?;
 
     Ok(rows.into_iter().map(PgTransaction::from_row).collect())
 }
+
+pub async fn fetch_scrape_cursor(
+    pool: &Pool,
+    program_id: &str,
+) -> anyhow::Result<Option<ScrapeCursor>> {
+    let client = pool.get().await?;
+
+    let stmt = r#"SELECT program_id, signature, slot, block_datetime
+            FROM scrape_cursors
+            WHERE program_id = $1"#;
+
+    let row = client.query_opt(stmt, &[&program_id]).await?;
+    Ok(row.map(ScrapeCursor::from_row))
+}
+
+/// Gaps that are still being paged through, oldest first
+pub async fn fetch_open_scrape_gaps(
+    pool: &Pool,
+    program_id: &str,
+) -> anyhow::Result<Vec<ScrapeGap>> {
+    let client = pool.get().await?;
+
+    let stmt = r#"SELECT id, program_id, before_signature, before_slot, until_signature, until_slot, until_datetime, signatures_found
+            FROM scrape_gaps
+            WHERE program_id = $1
+            AND closed_at IS NULL
+            ORDER BY until_slot asc"#;
+
+    let rows = client.query(stmt, &[&program_id]).await?;
+    Ok(rows.into_iter().map(ScrapeGap::from_row).collect())
+}
+
+/// The checkpoints of a backfill, one per program
+pub async fn fetch_backfill_checkpoints(
+    pool: &Pool,
+    name: &str,
+) -> anyhow::Result<Vec<BackfillCheckpoint>> {
+    let client = pool.get().await?;
+
+    let stmt = r#"SELECT name, start_time, end_time, start_slot, end_slot, markets, before_signature, before_slot, before_datetime, signatures_found, completed_at IS NOT NULL, program_id
+            FROM backfill_checkpoints
+            WHERE name = $1
+            ORDER BY program_id"#;
+
+    let rows = client.query(stmt, &[&name]).await?;
+    Ok(rows.into_iter().map(BackfillCheckpoint::from_row).collect())
+}
+
+/// Maps open orders accounts to their owners, using the most recent fill of each account that
+/// has one. v2 fills don't record the owner.
+pub async fn fetch_open_orders_owners(
+    pool: &Pool,
+    open_orders: &[String],
+) -> anyhow::Result<HashMap<String, String>> {
+    let client = pool.get().await?;
+
+    let stmt = r#"SELECT DISTINCT ON (open_orders)
+            open_orders as "open_orders!",
+            open_orders_owner as "open_orders_owner!"
+        FROM fills
+        WHERE open_orders = any($1)
+            AND open_orders_owner IS NOT NULL
+        ORDER BY open_orders, time desc"#;
+
+    let rows = client.query(stmt, &[&open_orders]).await?;
+
+    Ok(rows.into_iter().map(|r| (r.get(0), r.get(1))).collect())
+}
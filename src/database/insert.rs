@@ -1,102 +1,837 @@
-use deadpool_postgres::Pool;
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use deadpool_postgres::{GenericClient, Pool};
+use rust_decimal::Decimal;
+use solana_sdk::pubkey::Pubkey;
+use std::{collections::HashMap, str::FromStr};
+use tokio_postgres::{types::ToSql, Row};
 
 use crate::{
-    structs::{candle::Candle, openbook::OpenBookFillEvent, transaction::PgTransaction},
-    utils::{to_timestampz, AnyhowWrap},
+    structs::{
+        candle::Candle,
+        markets::MarketInfo,
+        openbook::OpenBookFillEvent,
+        resolution::Resolution,
+        transaction::{BackfillCheckpoint, BackfillRange, PgTransaction, ScrapeCursor, ScrapeGap},
+    },
+    utils::to_timestampz,
 };
 
+/// Postgres allows at most this many parameters in a single statement
+const MAX_STATEMENT_PARAMS: usize = 65535;
+
+const FILLS_ON_CONFLICT: &str = "ON CONFLICT DO NOTHING";
+/// Fills parsed from a fetched transaction carry its block time, which replaces the time a
+/// streamed fill was received at
+const FETCHED_FILLS_ON_CONFLICT: &str = "ON CONFLICT (signature, log_index)
+    DO UPDATE SET time = excluded.time
+    WHERE fills.time <> excluded.time";
+pub(crate) const CANDLES_ON_CONFLICT: &str = "ON CONFLICT (market_name, start_time, resolution)
+    DO UPDATE SET
+    open=excluded.open,
+    close=excluded.close,
+    high=excluded.high,
+    low=excluded.low,
+    volume=excluded.volume,
+    complete=excluded.complete";
+const TRANSACTIONS_ON_CONFLICT: &str = "ON CONFLICT DO NOTHING";
+
+/// A row of a multi-row insert
+trait InsertRow {
+    const TABLE: &'static str;
+    const COLUMNS: &'static [&'static str];
+    /// Casts for values that are bound as a different type than their column
+    const CASTS: &'static [(&'static str, &'static str)] = &[];
+
+    /// Values in the same order as `COLUMNS`
+    fn values(&self) -> Vec<&(dyn ToSql + Sync)>;
+}
+
+struct FillRow {
+    signature: String,
+    time: DateTime<Utc>,
+    market: String,
+    open_orders: String,
+    open_orders_owner: Option<String>,
+    bid: bool,
+    maker: bool,
+    native_qty_paid: Decimal,
+    native_qty_received: Decimal,
+    native_fee_or_rebate: Decimal,
+    fee_tier: i16,
+    /// Bound as text since a u128 doesn't always fit a Decimal
+    order_id: String,
+    log_index: i32,
+    client_order_id: Option<Decimal>,
+    referrer_rebate: Option<Decimal>,
+    owner_slot: i16,
+    slot: i64,
+}
+impl From<&OpenBookFillEvent> for FillRow {
+    fn from(fill: &OpenBookFillEvent) -> Self {
+        FillRow {
+            signature: fill.signature.clone(),
+            time: to_timestampz(fill.block_time as u64),
+            market: fill.market.to_string(),
+            open_orders: fill.open_orders.to_string(),
+            open_orders_owner: fill.open_orders_owner.map(|o| o.to_string()),
+            bid: fill.bid,
+            maker: fill.maker,
+            native_qty_paid: Decimal::from(fill.native_qty_paid),
+            native_qty_received: Decimal::from(fill.native_qty_received),
+            native_fee_or_rebate: Decimal::from_i128_with_scale(fill.native_fee_or_rebate, 0),
+            fee_tier: fill.fee_tier as i16,
+            order_id: fill.order_id.to_string(),
+            log_index: fill.log_index as i32,
+            client_order_id: fill.client_order_id.map(Decimal::from),
+            referrer_rebate: fill.referrer_rebate.map(Decimal::from),
+            owner_slot: fill.owner_slot as i16,
+            slot: fill.slot as i64,
+        }
+    }
+}
+impl InsertRow for FillRow {
+    const TABLE: &'static str = "fills";
+    const COLUMNS: &'static [&'static str] = &[
+        "signature",
+        "time",
+        "market",
+        "open_orders",
+        "open_orders_owner",
+        "bid",
+        "maker",
+        "native_qty_paid",
+        "native_qty_received",
+        "native_fee_or_rebate",
+        "fee_tier",
+        "order_id",
+        "log_index",
+        "client_order_id",
+        "referrer_rebate",
+        "owner_slot",
+        "slot",
+    ];
+    const CASTS: &'static [(&'static str, &'static str)] = &[("order_id", "text::numeric")];
+
+    fn values(&self) -> Vec<&(dyn ToSql + Sync)> {
+        vec![
+            &self.signature,
+            &self.time,
+            &self.market,
+            &self.open_orders,
+            &self.open_orders_owner,
+            &self.bid,
+            &self.maker,
+            &self.native_qty_paid,
+            &self.native_qty_received,
+            &self.native_fee_or_rebate,
+            &self.fee_tier,
+            &self.order_id,
+            &self.log_index,
+            &self.client_order_id,
+            &self.referrer_rebate,
+            &self.owner_slot,
+            &self.slot,
+        ]
+    }
+}
+
+impl InsertRow for Candle {
+    const TABLE: &'static str = "candles";
+    const COLUMNS: &'static [&'static str] = &[
+        "market_name",
+        "start_time",
+        "end_time",
+        "resolution",
+        "open",
+        "close",
+        "high",
+        "low",
+        "volume",
+        "complete",
+    ];
+
+    fn values(&self) -> Vec<&(dyn ToSql + Sync)> {
+        vec![
+            &self.market_name,
+            &self.start_time,
+            &self.end_time,
+            &self.resolution,
+            &self.open,
+            &self.close,
+            &self.high,
+            &self.low,
+            &self.volume,
+            &self.complete,
+        ]
+    }
+}
+
+struct TransactionRow {
+    signature: String,
+    program_pk: String,
+    block_datetime: DateTime<Utc>,
+    slot: i64,
+    err: bool,
+    processed: bool,
+    worker_partition: i32,
+}
+impl From<&PgTransaction> for TransactionRow {
+    fn from(txn: &PgTransaction) -> Self {
+        TransactionRow {
+            signature: txn.signature.clone(),
+            program_pk: txn.program_pk.clone(),
+            block_datetime: txn.block_datetime,
+            slot: txn.slot as i64,
+            err: txn.err,
+            processed: txn.processed,
+            worker_partition: txn.worker_partition,
+        }
+    }
+}
+impl InsertRow for TransactionRow {
+    const TABLE: &'static str = "transactions";
+    const COLUMNS: &'static [&'static str] = &[
+        "signature",
+        "program_pk",
+        "block_datetime",
+        "slot",
+        "err",
+        "processed",
+        "worker_partition",
+    ];
+
+    fn values(&self) -> Vec<&(dyn ToSql + Sync)> {
+        vec![
+            &self.signature,
+            &self.program_pk,
+            &self.block_datetime,
+            &self.slot,
+            &self.err,
+            &self.processed,
+            &self.worker_partition,
+        ]
+    }
+}
+
+/// Inserts the fills parsed from a worker's transactions and marks them processed. If only
+/// some of their markets were parsed, the transactions are handed back to the scraper instead.
 pub async fn insert_fills_atomically(
     pool: &Pool,
     worker_id: i32,
     fills: Vec<OpenBookFillEvent>,
     signatures: Vec<String>,
+    all_markets: bool,
+    markets: &HashMap<Pubkey, MarketInfo>,
 ) -> anyhow::Result<()> {
     let mut client = pool.get().await?;
 
     let db_txn = client.build_transaction().start().await?;
 
-    // 1. Insert fills
-    if !fills.is_empty() {
-        let fills_statement = build_fills_upsert_statement(fills);
-        db_txn
-            .execute(&fills_statement, &[])
-            .await
-            .map_err_anyhow()
-            .unwrap();
-    }
+    // 1. Insert fills, moving any that were streamed to the block time. The minutes they
+    // were streamed at need their candles rebuilt too.
+    let moved_fill_times = fetch_moved_fill_times(&db_txn, &fills).await?;
+    upsert_fills(&db_txn, &fills, markets, FETCHED_FILLS_ON_CONFLICT).await?;
+    mark_dirty_candle_ranges(&db_txn, &moved_fill_times, markets).await?;
 
     // 2. Update txns table as processed
-    let transactions_statement =
-        build_transactions_processed_update_statement(worker_id, signatures);
+    if all_markets {
+        mark_transactions_processed(&db_txn, worker_id, &signatures).await?;
+    } else {
+        release_backfill_transactions(&db_txn, worker_id, &signatures).await?;
+    }
+
+    db_txn.commit().await?;
+
+    Ok(())
+}
+
+/// Inserts the newest signatures of a poll and moves the cursor up to them if it's given,
+/// along with the gap left behind if the poll didn't page all the way back to the previous
+/// cursor
+pub async fn insert_head_signatures(
+    pool: &Pool,
+    transactions: &[PgTransaction],
+    cursor: Option<&ScrapeCursor>,
+    gap: Option<&ScrapeGap>,
+) -> anyhow::Result<u64> {
+    let mut client = pool.get().await?;
+
+    let db_txn = client.build_transaction().start().await?;
+    let num_txns = insert_transactions(&db_txn, transactions).await?;
+    if let Some(gap) = gap {
+        insert_scrape_gap(&db_txn, gap).await?;
+    }
+    if let Some(cursor) = cursor {
+        upsert_scrape_cursor(&db_txn, cursor).await?;
+    }
+    db_txn.commit().await?;
+
+    Ok(num_txns)
+}
+
+/// Inserts signatures paged from a gap and records how far back it has been paged
+pub async fn insert_gap_signatures(
+    pool: &Pool,
+    transactions: &[PgTransaction],
+    gap: &ScrapeGap,
+    closed: bool,
+) -> anyhow::Result<u64> {
+    let mut client = pool.get().await?;
+
+    let db_txn = client.build_transaction().start().await?;
+    let num_txns = insert_transactions(&db_txn, transactions).await?;
+    update_scrape_gap(&db_txn, gap, closed).await?;
+    db_txn.commit().await?;
+
+    Ok(num_txns)
+}
+
+/// Inserts a page of backfilled signatures and moves the checkpoint back past them. The new
+/// ones are left to the backfill's own fill workers, ones the scraper already has are left to it.
+pub async fn insert_backfill_signatures(
+    pool: &Pool,
+    transactions: &[PgTransaction],
+    checkpoint: &BackfillCheckpoint,
+) -> anyhow::Result<u64> {
+    let mut client = pool.get().await?;
+
+    let db_txn = client.build_transaction().start().await?;
+    let rows = transactions
+        .iter()
+        .map(TransactionRow::from)
+        .collect::<Vec<TransactionRow>>();
+    let inserted =
+        insert_rows_returning(&db_txn, &rows, TRANSACTIONS_ON_CONFLICT, "signature").await?;
+    let signatures = inserted
+        .iter()
+        .map(|row| row.get("signature"))
+        .collect::<Vec<String>>();
     db_txn
-        .execute(&transactions_statement, &[])
-        .await
-        .map_err_anyhow()
-        .unwrap();
+        .execute(
+            "UPDATE transactions SET backfill_name = $1 WHERE signature = any($2)",
+            &[&checkpoint.name, &signatures],
+        )
+        .await?;
+    upsert_backfill_checkpoint(&db_txn, checkpoint).await?;
+    db_txn.commit().await?;
+
+    Ok(inserted.len() as u64)
+}
+
+/// Inserts fills together with the transactions they came from, for fills that were
+/// parsed without going through the signature scraper. Transactions that are already
+/// processed, such as archived ones, are marked as processed even if the scraper inserted
+/// them first, so it doesn't fetch them again. Streamed ones are left for the scraper.
+pub async fn insert_streamed_fills(
+    pool: &Pool,
+    fills: Vec<OpenBookFillEvent>,
+    transactions: Vec<PgTransaction>,
+    markets: &HashMap<Pubkey, MarketInfo>,
+) -> anyhow::Result<()> {
+    if transactions.is_empty() {
+        return Ok(());
+    }
+    let mut client = pool.get().await?;
+
+    let db_txn = client.build_transaction().start().await?;
+
+    // 1. Insert fills
+    upsert_fills(&db_txn, &fills, markets, FILLS_ON_CONFLICT).await?;
+
+    // 2. Insert txns, and mark any the scraper got to first as processed
+    let mut partitions: HashMap<i32, Vec<String>> = HashMap::new();
+    for txn in transactions.iter().filter(|t| t.processed) {
+        partitions
+            .entry(txn.worker_partition)
+            .or_default()
+            .push(txn.signature.clone());
+    }
+    insert_transactions(&db_txn, &transactions).await?;
+    for (worker_partition, signatures) in partitions {
+        mark_transactions_processed(&db_txn, worker_partition, &signatures).await?;
+    }
 
     db_txn.commit().await?;
 
     Ok(())
 }
 
-fn build_fills_upsert_statement(fills: Vec<OpenBookFillEvent>) -> String {
-    let mut stmt = String::from("INSERT INTO fills (signature, time, market, open_orders, open_orders_owner, bid, maker, native_qty_paid, native_qty_received, native_fee_or_rebate, fee_tier, order_id, log_index) VALUES");
-    for (idx, fill) in fills.iter().enumerate() {
-        let val_str = format!(
-            "(\'{}\', \'{}\', \'{}\', \'{}\', \'{}\', {}, {}, {}, {}, {}, {}, {}, {})",
-            fill.signature,
-            to_timestampz(fill.block_time as u64).to_rfc3339(),
-            fill.market,
-            fill.open_orders,
-            fill.open_orders_owner,
-            fill.bid,
-            fill.maker,
-            fill.native_qty_paid,
-            fill.native_qty_received,
-            fill.native_fee_or_rebate,
-            fill.fee_tier,
-            fill.order_id,
-            fill.log_index,
-        );
+async fn upsert_fills(
+    client: &impl GenericClient,
+    fills: &[OpenBookFillEvent],
+    markets: &HashMap<Pubkey, MarketInfo>,
+    on_conflict: &str,
+) -> anyhow::Result<u64> {
+    let rows = fills.iter().map(FillRow::from).collect::<Vec<FillRow>>();
+    // Only fills that were written change their candles, not ones that were already stored
+    let written = insert_rows_returning(client, &rows, on_conflict, "market, time").await?;
+    let mut fill_times = vec![];
+    for row in written.iter() {
+        let market: String = row.get("market");
+        fill_times.push((Pubkey::from_str(&market)?, row.get("time")));
+    }
+    mark_dirty_candle_ranges(client, &fill_times, markets).await?;
+    Ok(written.len() as u64)
+}
+
+/// The markets and times of stored fills that `fills` restamp with a different time
+async fn fetch_moved_fill_times(
+    client: &impl GenericClient,
+    fills: &[OpenBookFillEvent],
+) -> anyhow::Result<Vec<(Pubkey, DateTime<Utc>)>> {
+    if fills.is_empty() {
+        return Ok(vec![]);
+    }
+    let new_times: HashMap<(&str, i32), DateTime<Utc>> = fills
+        .iter()
+        .map(|f| {
+            (
+                (f.signature.as_str(), f.log_index as i32),
+                to_timestampz(f.block_time as u64),
+            )
+        })
+        .collect();
+    let signatures = fills
+        .iter()
+        .map(|f| f.signature.clone())
+        .collect::<Vec<String>>();
+    let stmt = "SELECT signature, log_index, market, time FROM fills WHERE signature = any($1)";
+    let rows = client.query(stmt, &[&signatures]).await?;
 
-        if idx == 0 {
-            stmt = format!("{} {}", &stmt, val_str);
-        } else {
-            stmt = format!("{}, {}", &stmt, val_str);
+    let mut moved = vec![];
+    for row in rows {
+        let signature: String = row.get("signature");
+        let log_index: i32 = row.get("log_index");
+        let time: DateTime<Utc> = row.get("time");
+        match new_times.get(&(signature.as_str(), log_index)) {
+            Some(new_time) if *new_time != time => {
+                let market: String = row.get("market");
+                moved.push((Pubkey::from_str(&market)?, time));
+            }
+            _ => {}
         }
     }
+    Ok(moved)
+}
+
+/// The minutes spanned by each market's fills, given by market and time, from the start of
+/// the first fill's minute to the end of the last one's
+fn fill_minute_ranges(
+    fill_times: &[(Pubkey, DateTime<Utc>)],
+    markets: &HashMap<Pubkey, MarketInfo>,
+) -> HashMap<String, (DateTime<Utc>, DateTime<Utc>)> {
+    let minute = Duration::minutes(1);
+    let mut ranges: HashMap<String, (DateTime<Utc>, DateTime<Utc>)> = HashMap::new();
+    for (market, time) in fill_times {
+        let market_name = match markets.get(market) {
+            Some(market) => &market.name,
+            None => continue,
+        };
+        let start = time.duration_trunc(minute).unwrap();
+        let end = start + minute;
+        ranges
+            .entry(market_name.clone())
+            .and_modify(|(s, e)| {
+                *s = (*s).min(start);
+                *e = (*e).max(end);
+            })
+            .or_insert((start, end));
+    }
+    ranges
+}
+
+/// Records the minutes of any fills that are older than a market's latest complete 1M candle,
+/// since the batcher only moves forward and would otherwise never see them
+async fn mark_dirty_candle_ranges(
+    client: &impl GenericClient,
+    fill_times: &[(Pubkey, DateTime<Utc>)],
+    markets: &HashMap<Pubkey, MarketInfo>,
+) -> anyhow::Result<u64> {
+    let ranges = fill_minute_ranges(fill_times, markets);
+    if ranges.is_empty() {
+        return Ok(0);
+    }
+    let mut names = vec![];
+    let mut start_times = vec![];
+    let mut end_times = vec![];
+    for (name, (start, end)) in ranges {
+        names.push(name);
+        start_times.push(start);
+        end_times.push(end);
+    }
 
-    let handle_conflict = "ON CONFLICT DO NOTHING";
+    let stmt = "INSERT INTO candle_dirty_ranges (market_name, start_time, end_time)
+        SELECT r.market_name, r.start_time, r.end_time
+          FROM unnest($1::text[], $2::timestamptz[], $3::timestamptz[])
+            AS r(market_name, start_time, end_time)
+         WHERE r.start_time < (
+            SELECT c.end_time FROM candles c
+             WHERE c.market_name = r.market_name
+                AND c.resolution = $4
+                AND c.complete = true
+             ORDER BY c.start_time DESC
+             LIMIT 1
+         )";
+    let dirty = client
+        .execute(
+            stmt,
+            &[
+                &names,
+                &start_times,
+                &end_times,
+                &Resolution::R1m.to_string(),
+            ],
+        )
+        .await?;
+    Ok(dirty)
+}
 
-    stmt = format!("{} {}", stmt, handle_conflict);
-    stmt
+/// Returns the number of candles written
+pub async fn upsert_candles(
+    client: &impl GenericClient,
+    candles: &[Candle],
+) -> anyhow::Result<u64> {
+    insert_rows(client, candles, CANDLES_ON_CONFLICT).await
+}
+
+/// Returns the number of new transactions, existing signatures are skipped
+pub async fn insert_transactions(
+    client: &impl GenericClient,
+    transactions: &[PgTransaction],
+) -> anyhow::Result<u64> {
+    let rows = transactions
+        .iter()
+        .map(TransactionRow::from)
+        .collect::<Vec<TransactionRow>>();
+    insert_rows(client, &rows, TRANSACTIONS_ON_CONFLICT).await
+}
+
+pub async fn mark_transactions_processed(
+    client: &impl GenericClient,
+    worker_partition: i32,
+    processed_signatures: &[String],
+) -> anyhow::Result<u64> {
+    if processed_signatures.is_empty() {
+        return Ok(0);
+    }
+    let stmt = "UPDATE transactions
+    SET processed = true
+    WHERE transactions.signature = any($1) AND worker_partition = $2";
+    Ok(client
+        .execute(stmt, &[&processed_signatures, &worker_partition])
+        .await?)
+}
+
+/// Hands transactions a backfill only parsed some of the markets of over to the scraper, which
+/// parses every market
+async fn release_backfill_transactions(
+    client: &impl GenericClient,
+    worker_partition: i32,
+    signatures: &[String],
+) -> anyhow::Result<u64> {
+    if signatures.is_empty() {
+        return Ok(0);
+    }
+    let stmt = "UPDATE transactions
+    SET backfill_name = NULL
+    WHERE transactions.signature = any($1) AND worker_partition = $2";
+    Ok(client
+        .execute(stmt, &[&signatures, &worker_partition])
+        .await?)
+}
+
+/// Counts a failed getTransaction call for each signature, along with its error
+pub async fn record_failed_fetches(
+    pool: &Pool,
+    worker_partition: i32,
+    failures: &[(String, String)],
+) -> anyhow::Result<u64> {
+    if failures.is_empty() {
+        return Ok(0);
+    }
+    let (signatures, errors): (Vec<String>, Vec<String>) = failures.iter().cloned().unzip();
+    let client = pool.get().await?;
+    let stmt = "UPDATE transactions
+    SET fetch_attempts = fetch_attempts + 1, fetch_error = f.error
+    FROM unnest($1::text[], $2::text[]) AS f(signature, error)
+    WHERE transactions.signature = f.signature AND worker_partition = $3";
+    Ok(client
+        .execute(stmt, &[&signatures, &errors, &worker_partition])
+        .await?)
+}
+
+/// Called once the candles covering the ranges have been rebuilt
+pub async fn delete_candle_dirty_ranges(
+    client: &impl GenericClient,
+    ids: &[i64],
+) -> anyhow::Result<u64> {
+    let deleted = client
+        .execute(
+            "DELETE FROM candle_dirty_ranges WHERE id = any($1)",
+            &[&ids],
+        )
+        .await?;
+    Ok(deleted)
 }
 
-pub fn build_candles_upsert_statement(candles: &Vec<Candle>) -> String {
-    let mut stmt = String::from("INSERT INTO candles (market_name, start_time, end_time, resolution, open, close, high, low, volume, complete) VALUES");
-    for (idx, candle) in candles.iter().enumerate() {
-        let val_str = format!(
-            "(\'{}\', \'{}\', \'{}\', \'{}\', {}, {}, {}, {}, {}, {})",
-            candle.market_name,
-            candle.start_time.to_rfc3339(),
-            candle.end_time.to_rfc3339(),
-            candle.resolution,
-            candle.open,
-            candle.close,
-            candle.high,
-            candle.low,
-            candle.volume,
-            candle.complete,
+pub async fn upsert_scrape_cursor(
+    client: &impl GenericClient,
+    cursor: &ScrapeCursor,
+) -> anyhow::Result<u64> {
+    let stmt = "INSERT INTO scrape_cursors (program_id, signature, slot, block_datetime)
+    VALUES ($1, $2, $3, $4)
+    ON CONFLICT (program_id) DO UPDATE SET
+    signature=excluded.signature,
+    slot=excluded.slot,
+    block_datetime=excluded.block_datetime,
+    updated_at=current_timestamp";
+    Ok(client
+        .execute(
+            stmt,
+            &[
+                &cursor.program_id,
+                &cursor.signature,
+                &(cursor.slot as i64),
+                &cursor.block_datetime,
+            ],
+        )
+        .await?)
+}
+
+pub async fn insert_scrape_gap(
+    client: &impl GenericClient,
+    gap: &ScrapeGap,
+) -> anyhow::Result<u64> {
+    let stmt = "INSERT INTO scrape_gaps (program_id, before_signature, before_slot, until_signature, until_slot, until_datetime)
+    VALUES ($1, $2, $3, $4, $5, $6)";
+    Ok(client
+        .execute(
+            stmt,
+            &[
+                &gap.program_id,
+                &gap.before_signature,
+                &(gap.before_slot as i64),
+                &gap.until_signature,
+                &(gap.until_slot as i64),
+                &gap.until_datetime,
+            ],
+        )
+        .await?)
+}
+
+/// Records how far back a gap has been paged, closing it once it reached `until_signature`
+pub async fn update_scrape_gap(
+    client: &impl GenericClient,
+    gap: &ScrapeGap,
+    closed: bool,
+) -> anyhow::Result<u64> {
+    let stmt = "UPDATE scrape_gaps
+    SET before_signature = $2,
+    before_slot = $3,
+    signatures_found = $4,
+    closed_at = CASE WHEN $5 THEN current_timestamp END
+    WHERE id = $1";
+    Ok(client
+        .execute(
+            stmt,
+            &[
+                &gap.id,
+                &gap.before_signature,
+                &(gap.before_slot as i64),
+                &gap.signatures_found,
+                &closed,
+            ],
+        )
+        .await?)
+}
+
+pub async fn upsert_backfill_checkpoint(
+    client: &impl GenericClient,
+    checkpoint: &BackfillCheckpoint,
+) -> anyhow::Result<u64> {
+    let (start_time, end_time, start_slot, end_slot) = match checkpoint.range {
+        BackfillRange::Time { start, end } => (Some(start), Some(end), None, None),
+        BackfillRange::Slot { start, end } => (None, None, Some(start as i64), Some(end as i64)),
+    };
+    let stmt = "INSERT INTO backfill_checkpoints (name, start_time, end_time, start_slot, end_slot, markets, before_signature, before_slot, before_datetime, signatures_found, completed_at, program_id)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, CASE WHEN $11 THEN current_timestamp END, $12)
+    ON CONFLICT (name, program_id) DO UPDATE SET
+    start_time=excluded.start_time,
+    end_time=excluded.end_time,
+    start_slot=excluded.start_slot,
+    end_slot=excluded.end_slot,
+    markets=excluded.markets,
+    before_signature=excluded.before_signature,
+    before_slot=excluded.before_slot,
+    before_datetime=excluded.before_datetime,
+    signatures_found=excluded.signatures_found,
+    completed_at=excluded.completed_at,
+    updated_at=current_timestamp";
+    Ok(client
+        .execute(
+            stmt,
+            &[
+                &checkpoint.name,
+                &start_time,
+                &end_time,
+                &start_slot,
+                &end_slot,
+                &checkpoint.markets,
+                &checkpoint.before_signature,
+                &checkpoint.before_slot.map(|s| s as i64),
+                &checkpoint.before_datetime,
+                &checkpoint.signatures_found,
+                &checkpoint.completed,
+                &checkpoint.program_id,
+            ],
+        )
+        .await?)
+}
+
+/// Removes every checkpoint of a backfill, so it starts over
+pub async fn delete_backfill_checkpoints(
+    client: &impl GenericClient,
+    name: &str,
+) -> anyhow::Result<u64> {
+    let stmt = "DELETE FROM backfill_checkpoints WHERE name = $1";
+    Ok(client.execute(stmt, &[&name]).await?)
+}
+
+/// Inserts rows with as few statements as the parameter limit allows
+async fn insert_rows<R: InsertRow + Sync>(
+    client: &impl GenericClient,
+    rows: &[R],
+    on_conflict: &str,
+) -> anyhow::Result<u64> {
+    let mut num_rows = 0;
+    for chunk in rows.chunks(MAX_STATEMENT_PARAMS / R::COLUMNS.len()) {
+        let stmt = build_insert_statement(R::TABLE, R::COLUMNS, R::CASTS, chunk.len(), on_conflict);
+        let params = chunk
+            .iter()
+            .flat_map(|row| row.values())
+            .collect::<Vec<&(dyn ToSql + Sync)>>();
+        num_rows += client.execute(stmt.as_str(), &params).await?;
+    }
+    Ok(num_rows)
+}
+
+/// Like `insert_rows`, returning the `returning` columns of each row that was written
+async fn insert_rows_returning<R: InsertRow + Sync>(
+    client: &impl GenericClient,
+    rows: &[R],
+    on_conflict: &str,
+    returning: &str,
+) -> anyhow::Result<Vec<Row>> {
+    let mut written = vec![];
+    for chunk in rows.chunks(MAX_STATEMENT_PARAMS / R::COLUMNS.len()) {
+        let stmt = format!(
+            "{} RETURNING {}",
+            build_insert_statement(R::TABLE, R::COLUMNS, R::CASTS, chunk.len(), on_conflict),
+            returning
         );
+        let params = chunk
+            .iter()
+            .flat_map(|row| row.values())
+            .collect::<Vec<&(dyn ToSql + Sync)>>();
+        written.extend(client.query(stmt.as_str(), &params).await?);
+    }
+    Ok(written)
+}
+
+/// Builds `INSERT INTO table (columns) VALUES ($1, $2, ...), ... on_conflict` for `num_rows` rows
+fn build_insert_statement(
+    table: &str,
+    columns: &[&str],
+    casts: &[(&str, &str)],
+    num_rows: usize,
+    on_conflict: &str,
+) -> String {
+    let mut stmt = format!("INSERT INTO {} ({}) VALUES ", table, columns.join(", "));
+    for row in 0..num_rows {
+        if row > 0 {
+            stmt.push_str(", ");
+        }
+        let placeholders = columns
+            .iter()
+            .enumerate()
+            .map(|(col, name)| {
+                let param = row * columns.len() + col + 1;
+                match casts.iter().find(|(c, _)| c == name) {
+                    Some((_, cast)) => format!("${}::{}", param, cast),
+                    None => format!("${}", param),
+                }
+            })
+            .collect::<Vec<String>>();
+        stmt.push('(');
+        stmt.push_str(&placeholders.join(", "));
+        stmt.push(')');
+    }
+    stmt.push(' ');
+    stmt.push_str(on_conflict);
+    stmt
+}
 
-        if idx == 0 {
-            stmt = format!("{} {}", &stmt, val_str);
-        } else {
-            stmt = format!("{}, {}", &stmt, val_str);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::markets::test_market;
+    use chrono::TimeZone;
+
+    // Copies of the string builders the parameterized inserts replaced, to check the real
+    // statements and values write the same rows
+
+    fn legacy_fills_upsert_statement(fills: &[OpenBookFillEvent]) -> String {
+        let mut stmt = String::from("INSERT INTO fills (signature, time, market, open_orders, open_orders_owner, bid, maker, native_qty_paid, native_qty_received, native_fee_or_rebate, fee_tier, order_id, log_index) VALUES");
+        for (idx, fill) in fills.iter().enumerate() {
+            let val_str = format!(
+                "(\'{}\', \'{}\', \'{}\', \'{}\', \'{}\', {}, {}, {}, {}, {}, {}, {}, {})",
+                fill.signature,
+                to_timestampz(fill.block_time as u64).to_rfc3339(),
+                fill.market,
+                fill.open_orders,
+                fill.open_orders_owner.unwrap(),
+                fill.bid,
+                fill.maker,
+                fill.native_qty_paid,
+                fill.native_qty_received,
+                fill.native_fee_or_rebate,
+                fill.fee_tier,
+                fill.order_id,
+                fill.log_index,
+            );
+            if idx == 0 {
+                stmt = format!("{} {}", &stmt, val_str);
+            } else {
+                stmt = format!("{}, {}", &stmt, val_str);
+            }
         }
+        let handle_conflict = "ON CONFLICT DO NOTHING";
+        format!("{} {}", stmt, handle_conflict)
     }
 
-    let handle_conflict = "ON CONFLICT (market_name, start_time, resolution) 
+    fn legacy_candles_upsert_statement(candles: &[Candle]) -> String {
+        let mut stmt = String::from("INSERT INTO candles (market_name, start_time, end_time, resolution, open, close, high, low, volume, complete) VALUES");
+        for (idx, candle) in candles.iter().enumerate() {
+            let val_str = format!(
+                "(\'{}\', \'{}\', \'{}\', \'{}\', {}, {}, {}, {}, {}, {})",
+                candle.market_name,
+                candle.start_time.to_rfc3339(),
+                candle.end_time.to_rfc3339(),
+                candle.resolution,
+                candle.open,
+                candle.close,
+                candle.high,
+                candle.low,
+                candle.volume,
+                candle.complete,
+            );
+            if idx == 0 {
+                stmt = format!("{} {}", &stmt, val_str);
+            } else {
+                stmt = format!("{}, {}", &stmt, val_str);
+            }
+        }
+        let handle_conflict = "ON CONFLICT (market_name, start_time, resolution) 
     DO UPDATE SET 
     open=excluded.open, 
     close=excluded.close, 
@@ -105,58 +840,285 @@ pub fn build_candles_upsert_statement(candles: &Vec<Candle>) -> String {
     volume=excluded.volume,
     complete=excluded.complete
     ";
+        format!("{} {}", stmt, handle_conflict)
+    }
 
-    stmt = format!("{} {}", stmt, handle_conflict);
-    stmt
-}
+    fn legacy_transactions_insert_statement(transactions: &[PgTransaction]) -> String {
+        let mut stmt = String::from("INSERT INTO transactions (signature, program_pk, block_datetime, slot, err, processed, worker_partition) VALUES");
+        for (idx, txn) in transactions.iter().enumerate() {
+            let val_str = format!(
+                "(\'{}\', \'{}\', \'{}\', \'{}\', {}, {}, {})",
+                txn.signature,
+                txn.program_pk,
+                txn.block_datetime.to_rfc3339(),
+                txn.slot,
+                txn.err,
+                txn.processed,
+                txn.worker_partition,
+            );
+            if idx == 0 {
+                stmt = format!("{} {}", &stmt, val_str);
+            } else {
+                stmt = format!("{}, {}", &stmt, val_str);
+            }
+        }
+        let handle_conflict = "ON CONFLICT DO NOTHING";
+        format!("{} {}", stmt, handle_conflict)
+    }
+
+    /// Substitutes the bound values into a statement as SQL literals, the way the legacy
+    /// builders wrote them. Values bound as text and cast are written unquoted.
+    fn inline_params(stmt: &str, params: &[&(dyn ToSql + Sync)]) -> String {
+        let mut inlined = String::new();
+        let mut rest = stmt;
+        while let Some(i) = rest.find('$') {
+            inlined.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let param = params[rest[..digits].parse::<usize>().unwrap() - 1];
+            rest = &rest[digits..];
 
-pub fn build_transactions_insert_statement(transactions: Vec<PgTransaction>) -> String {
-    let mut stmt = String::from("INSERT INTO transactions (signature, program_pk, block_datetime, slot, err, processed, worker_partition) VALUES");
-    for (idx, txn) in transactions.iter().enumerate() {
-        let val_str = format!(
-            "(\'{}\', \'{}\', \'{}\', \'{}\', {}, {}, {})",
-            txn.signature,
-            txn.program_pk,
-            txn.block_datetime.to_rfc3339(),
-            txn.slot,
-            txn.err,
-            txn.processed,
-            txn.worker_partition,
+            let value = format!("{:?}", param);
+            let value = match value.strip_prefix("Some(") {
+                Some(v) => v.strip_suffix(')').unwrap().to_string(),
+                None if value == "None" => "NULL".to_string(),
+                None => value,
+            };
+            let cast = rest.starts_with("::");
+            if cast {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != ':')
+                    .unwrap_or(rest.len());
+                rest = &rest[end..];
+            }
+            let literal = if let Some(text) = value.strip_prefix('"') {
+                let text = text.strip_suffix('"').unwrap();
+                if cast {
+                    text.to_string()
+                } else {
+                    format!("'{}'", text)
+                }
+            } else if let Ok(time) = DateTime::parse_from_rfc3339(&value) {
+                format!("'{}'", time.to_rfc3339())
+            } else {
+                value
+            };
+            inlined.push_str(&literal);
+        }
+        inlined.push_str(rest);
+        inlined
+    }
+
+    /// The statement and values `insert_rows` would send for `rows`, limited to the first
+    /// `num_columns` columns
+    fn inlined_insert<R: InsertRow>(rows: &[R], num_columns: usize, on_conflict: &str) -> String {
+        let stmt = build_insert_statement(
+            R::TABLE,
+            &R::COLUMNS[..num_columns],
+            R::CASTS,
+            rows.len(),
+            on_conflict,
         );
+        let params = rows
+            .iter()
+            .flat_map(|row| row.values().into_iter().take(num_columns))
+            .collect::<Vec<&(dyn ToSql + Sync)>>();
+        inline_params(&stmt, &params)
+    }
 
-        if idx == 0 {
-            stmt = format!("{} {}", &stmt, val_str);
-        } else {
-            stmt = format!("{}, {}", &stmt, val_str);
+    fn normalize_whitespace(stmt: &str) -> String {
+        stmt.split_whitespace().collect::<Vec<&str>>().join(" ")
+    }
+
+    fn test_fill(log_index: usize) -> OpenBookFillEvent {
+        OpenBookFillEvent {
+            signature: "5ZFsFt9jTi9iYXq8Ksgf9d6L8MGH4rASbA9Ma9gsDeJuBuTf1DQZUHuN7e3JK6ECPe1sTUusQMx5xAmU6Y6X8o2v".to_string(),
+            slot: 193912847,
+            market: Pubkey::new_unique(),
+            open_orders: Pubkey::new_unique(),
+            open_orders_owner: Some(Pubkey::new_unique()),
+            bid: log_index % 2 == 0,
+            maker: log_index % 3 == 0,
+            native_qty_paid: 20_785_000,
+            native_qty_received: 1_000_000_000,
+            native_fee_or_rebate: 4157,
+            order_id: 383415932958516985429671,
+            owner_slot: 3,
+            fee_tier: 6,
+            client_order_id: Some(1683596210291),
+            referrer_rebate: None,
+            block_time: 1683596210,
+            log_index,
         }
     }
 
-    let handle_conflict = "ON CONFLICT DO NOTHING";
+    #[test]
+    fn fill_inserts_match_legacy_statement() {
+        let fills = (0..4).map(test_fill).collect::<Vec<OpenBookFillEvent>>();
+        let rows = fills.iter().map(FillRow::from).collect::<Vec<FillRow>>();
+        // The legacy builder wrote the first 13 columns
+        assert_eq!(
+            inlined_insert(&rows, 13, FILLS_ON_CONFLICT),
+            legacy_fills_upsert_statement(&fills)
+        );
+    }
 
-    stmt = format!("{} {}", stmt, handle_conflict);
-    stmt
-}
+    /// The legacy rendering of a fill, with the fill event columns added after it appended
+    fn legacy_fill_event_values(fill: &OpenBookFillEvent) -> String {
+        let legacy_values = legacy_fills_upsert_statement(std::slice::from_ref(fill));
+        let legacy_values = legacy_values
+            .split_once(" VALUES ")
+            .unwrap()
+            .1
+            .strip_suffix(") ON CONFLICT DO NOTHING")
+            .unwrap();
+        let or_null = |v: Option<u64>| v.map_or("NULL".to_string(), |v| v.to_string());
+        format!(
+            "{}, {}, {}, {}, {})",
+            legacy_values,
+            or_null(fill.client_order_id),
+            or_null(fill.referrer_rebate),
+            fill.owner_slot,
+            fill.slot,
+        )
+    }
 
-pub fn build_transactions_processed_update_statement(
-    worker_id: i32,
-    processed_signatures: Vec<String>,
-) -> String {
-    let mut stmt = String::from(
-        "UPDATE transactions
-    SET processed = true
-    WHERE transactions.signature IN (",
-    );
-    for (idx, sig) in processed_signatures.iter().enumerate() {
-        let val_str = if idx == processed_signatures.len() - 1 {
-            format!("\'{}\'", sig,)
-        } else {
-            format!("\'{}\',", sig,)
-        };
-        stmt = format!("{} {}", &stmt, val_str);
+    #[test]
+    fn fill_inserts_write_the_fill_event_columns() {
+        let mut fills = (0..4).map(test_fill).collect::<Vec<OpenBookFillEvent>>();
+        fills[1].client_order_id = None;
+        fills[2].referrer_rebate = Some(830);
+        fills[3].owner_slot = 0;
+        fills[3].slot = u32::MAX as u64 + 1;
+        let rows = fills.iter().map(FillRow::from).collect::<Vec<FillRow>>();
+        assert_eq!(
+            inlined_insert(&rows, FillRow::COLUMNS.len(), FILLS_ON_CONFLICT),
+            format!(
+                "INSERT INTO fills ({}) VALUES {} {}",
+                FillRow::COLUMNS.join(", "),
+                fills
+                    .iter()
+                    .map(legacy_fill_event_values)
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                FILLS_ON_CONFLICT
+            )
+        );
     }
 
-    let worker_stmt = format!(") AND worker_partition = {} ", worker_id);
+    #[test]
+    fn candle_inserts_match_legacy_statement() {
+        let candles = [("SOL/USDC", 1683596040), ("BONK/SOL", 1683596100)]
+            .into_iter()
+            .map(|(market_name, start)| Candle {
+                market_name: market_name.to_string(),
+                start_time: Utc.timestamp_opt(start, 0).unwrap(),
+                end_time: Utc.timestamp_opt(start + 60, 0).unwrap(),
+                resolution: Resolution::R1m.to_string(),
+                open: 20.785,
+                close: 20.79,
+                high: 20.8125,
+                low: 20.7,
+                volume: 4.2,
+                complete: start % 120 == 0,
+            })
+            .collect::<Vec<Candle>>();
+        assert_eq!(
+            normalize_whitespace(&inlined_insert(
+                &candles,
+                Candle::COLUMNS.len(),
+                CANDLES_ON_CONFLICT
+            )),
+            normalize_whitespace(&legacy_candles_upsert_statement(&candles))
+        );
+    }
 
-    stmt = format!("{} {}", stmt, worker_stmt);
-    stmt
+    #[test]
+    fn transaction_inserts_match_legacy_statement() {
+        let txns = [(true, 7), (false, 2)]
+            .into_iter()
+            .map(|(processed, worker_partition)| PgTransaction {
+                signature: test_fill(0).signature,
+                program_pk: crate::utils::OPENBOOK_KEY.to_string(),
+                block_datetime: Utc.timestamp_opt(1683596210, 0).unwrap(),
+                slot: 193912847,
+                err: false,
+                processed,
+                worker_partition,
+            })
+            .collect::<Vec<PgTransaction>>();
+        let rows = txns
+            .iter()
+            .map(TransactionRow::from)
+            .collect::<Vec<TransactionRow>>();
+        // The legacy builder quoted the slot, which Postgres reads as the same bigint
+        assert_eq!(
+            inlined_insert(
+                &rows,
+                TransactionRow::COLUMNS.len(),
+                TRANSACTIONS_ON_CONFLICT
+            ),
+            legacy_transactions_insert_statement(&txns).replace("'193912847'", "193912847")
+        );
+    }
+
+    #[test]
+    fn rows_have_a_value_per_column() {
+        let fill = FillRow::from(&test_fill(1));
+        assert_eq!(fill.values().len(), FillRow::COLUMNS.len());
+        let txn = TransactionRow::from(&PgTransaction::from_logs_notification(
+            test_fill(0).signature,
+            193912847,
+            1683596210,
+            false,
+            &crate::utils::OPENBOOK_KEY,
+        ));
+        assert_eq!(txn.values().len(), TransactionRow::COLUMNS.len());
+        // Candles are bound directly from their fields
+        let candle = Candle::create_empty_candle("SOL/USDC".to_string(), Resolution::R1m);
+        assert_eq!(candle.values().len(), Candle::COLUMNS.len());
+    }
+
+    #[test]
+    fn insert_statement_numbers_params_by_row() {
+        assert_eq!(
+            build_insert_statement("t", &["a", "b", "c"], &[], 2, "ON CONFLICT DO NOTHING"),
+            "INSERT INTO t (a, b, c) VALUES ($1, $2, $3), ($4, $5, $6) ON CONFLICT DO NOTHING"
+        );
+        assert_eq!(
+            build_insert_statement("t", &["a", "b"], &[("b", "text::numeric")], 1, ""),
+            "INSERT INTO t (a, b) VALUES ($1, $2::text::numeric) "
+        );
+    }
+
+    #[test]
+    fn fill_minute_ranges_cover_each_market() {
+        let sol_usdc = Pubkey::new_unique();
+        let mut fills: Vec<OpenBookFillEvent> = (0..3).map(test_fill).collect();
+        for (fill, block_time) in fills.iter_mut().zip([1683596210, 1683596050, 1683596399]) {
+            fill.market = sol_usdc;
+            fill.block_time = block_time;
+        }
+        // A market that isn't tracked is ignored
+        fills.push(test_fill(3));
+        let markets = HashMap::from([(sol_usdc, test_market("SOL/USDC"))]);
+
+        let fill_times = fills
+            .iter()
+            .map(|f| (f.market, to_timestampz(f.block_time as u64)))
+            .collect::<Vec<(Pubkey, DateTime<Utc>)>>();
+        let ranges = fill_minute_ranges(&fill_times, &markets);
+        assert_eq!(
+            ranges,
+            HashMap::from([(
+                "SOL/USDC".to_string(),
+                (
+                    Utc.timestamp_opt(1683596040, 0).unwrap(),
+                    Utc.timestamp_opt(1683596400, 0).unwrap()
+                )
+            )])
+        );
+    }
 }
@@ -0,0 +1,231 @@
+use deadpool_postgres::{GenericClient, Pool};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+impl Migration {
+    /// Hex sha256 of the SQL, recorded when the migration is applied
+    pub fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.sql.as_bytes()))
+    }
+}
+
+/// Every migration in the order it is applied. Never edit one that has been released, add a new one.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        sql: include_str!("../../migrations/0001_initial_schema.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "exact_fill_amounts",
+        sql: include_str!("../../migrations/0002_exact_fill_amounts.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "full_fill_events",
+        sql: include_str!("../../migrations/0003_full_fill_events.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "trader_fills_index",
+        sql: include_str!("../../migrations/0004_trader_fills_index.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "candle_dirty_ranges",
+        sql: include_str!("../../migrations/0005_candle_dirty_ranges.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "scrape_cursors",
+        sql: include_str!("../../migrations/0006_scrape_cursors.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "backfill_checkpoints",
+        sql: include_str!("../../migrations/0007_backfill_checkpoints.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "nullable_open_orders_owner",
+        sql: include_str!("../../migrations/0008_nullable_open_orders_owner.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "backfill_checkpoint_programs",
+        sql: include_str!("../../migrations/0009_backfill_checkpoint_programs.sql"),
+    },
+];
+
+/// Arbitrary key for the advisory lock held while migrating, so concurrent runs wait for each other
+const MIGRATION_LOCK_KEY: i64 = 0x6f70656e626f6f6b;
+
+const CREATE_MIGRATIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (
+    version int4 PRIMARY KEY,
+    name text NOT NULL,
+    checksum text NOT NULL,
+    applied_at timestamptz NOT NULL DEFAULT current_timestamp
+)";
+
+/// Applies any pending migrations, each in its own transaction
+pub async fn run_migrations(pool: &Pool) -> anyhow::Result<()> {
+    let mut client = pool.get().await?;
+
+    for migration in MIGRATIONS {
+        let db_txn = client.build_transaction().start().await?;
+        db_txn
+            .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_KEY])
+            .await?;
+        db_txn.execute(CREATE_MIGRATIONS_TABLE, &[]).await?;
+        let applied = fetch_applied_migrations(&db_txn).await?;
+        match applied.get(&migration.version) {
+            Some(checksum) => verify_checksum(migration, checksum)?,
+            None => {
+                info!(
+                    "Applying migration {} {}",
+                    migration.version, migration.name
+                );
+                db_txn.batch_execute(migration.sql).await?;
+                db_txn
+                    .execute(
+                        "INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)",
+                        &[&migration.version, &migration.name, &migration.checksum()],
+                    )
+                    .await?;
+            }
+        }
+        db_txn.commit().await?;
+    }
+    println!("Database migrations are up to date");
+    Ok(())
+}
+
+/// Errors if any migration is pending, or an applied migration no longer matches its SQL
+pub async fn check_migrations(pool: &Pool) -> anyhow::Result<()> {
+    let client = pool.get().await?;
+    let table_exists: bool = client
+        .query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])
+        .await?
+        .get(0);
+    let applied = if table_exists {
+        fetch_applied_migrations(&client).await?
+    } else {
+        HashMap::new()
+    };
+    verify_applied_migrations(MIGRATIONS, &applied)
+}
+
+/// Checks the applied migrations, by version with their checksums, against `migrations`
+fn verify_applied_migrations(
+    migrations: &[Migration],
+    applied: &HashMap<i32, String>,
+) -> anyhow::Result<()> {
+    let mut pending = vec![];
+    for migration in migrations {
+        match applied.get(&migration.version) {
+            Some(checksum) => verify_checksum(migration, checksum)?,
+            None => pending.push(migration.version),
+        }
+    }
+    if !pending.is_empty() {
+        return Err(anyhow::anyhow!(
+            "database has pending migrations {:?}, run `worker migrate` first",
+            pending
+        ));
+    }
+    // Expected while a deploy is rolling out, as long as migrations stay backwards compatible
+    let latest_version = migrations.last().map(|m| m.version).unwrap_or(0);
+    if let Some(version) = applied.keys().filter(|v| **v > latest_version).max() {
+        warn!(
+            "database is at migration {} but this build only knows up to {}",
+            version, latest_version
+        );
+    }
+    Ok(())
+}
+
+async fn fetch_applied_migrations(
+    client: &impl GenericClient,
+) -> anyhow::Result<HashMap<i32, String>> {
+    let rows = client
+        .query("SELECT version, checksum FROM schema_migrations", &[])
+        .await?;
+    Ok(rows.into_iter().map(|r| (r.get(0), r.get(1))).collect())
+}
+
+fn verify_checksum(migration: &Migration, applied_checksum: &str) -> anyhow::Result<()> {
+    if migration.checksum() != applied_checksum {
+        return Err(anyhow::anyhow!(
+            "migration {} {} was changed after it was applied",
+            migration.version,
+            migration.name
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_ordered() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i32 + 1);
+        }
+    }
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            name: "first",
+            sql: "CREATE TABLE a (id int4)",
+        },
+        Migration {
+            version: 2,
+            name: "second",
+            sql: "CREATE TABLE b (id int4)",
+        },
+    ];
+
+    fn applied(migrations: &[Migration]) -> HashMap<i32, String> {
+        migrations
+            .iter()
+            .map(|m| (m.version, m.checksum()))
+            .collect()
+    }
+
+    #[test]
+    fn up_to_date_databases_pass() {
+        assert!(verify_applied_migrations(TEST_MIGRATIONS, &applied(TEST_MIGRATIONS)).is_ok());
+        // A newer build already migrated the database
+        let mut newer = applied(TEST_MIGRATIONS);
+        newer.insert(3, "checksum".to_string());
+        assert!(verify_applied_migrations(TEST_MIGRATIONS, &newer).is_ok());
+    }
+
+    #[test]
+    fn pending_migrations_are_errors() {
+        let err = verify_applied_migrations(TEST_MIGRATIONS, &applied(&TEST_MIGRATIONS[..1]))
+            .unwrap_err();
+        assert!(err.to_string().contains("pending migrations [2]"));
+        let err = verify_applied_migrations(TEST_MIGRATIONS, &HashMap::new()).unwrap_err();
+        assert!(err.to_string().contains("pending migrations [1, 2]"));
+    }
+
+    #[test]
+    fn checksum_mismatches_are_errors() {
+        let mut changed = applied(TEST_MIGRATIONS);
+        changed.insert(1, applied(&TEST_MIGRATIONS[1..])[&2].clone());
+        let err = verify_applied_migrations(TEST_MIGRATIONS, &changed).unwrap_err();
+        assert!(err.to_string().contains("migration 1 first was changed"));
+    }
+}
@@ -1,3 +1,4 @@
 pub mod fetch;
 pub mod initialize;
 pub mod insert;
+pub mod migrations;
@@ -1,6 +1,13 @@
+use std::str::FromStr;
+
 use openbook_candles::{
-    database::fetch::fetch_candles_from,
-    structs::{markets::valid_market, resolution::Resolution, tradingview::TvResponse},
+    database::fetch::{fetch_candle_before, fetch_candles_from, fetch_fills_from},
+    structs::{
+        candle::{aggregate_candles, candles_from_fills},
+        markets::MarketInfo,
+        resolution::{CandleSource, ChartResolution},
+        tradingview::TvResponse,
+    },
     utils::{to_timestampz, WebContext},
 };
 
@@ -11,11 +18,15 @@ use {
     serde::Deserialize,
 };
 
+/// Candles under a minute are built from fills, so they can only be asked for a day at a time
+const MAX_FILLS_RANGE_SECS: u64 = 24 * 60 * 60;
+
 #[derive(Debug, Deserialize)]
 pub struct CandleParams {
     pub market_name: String,
     pub from: u64,
     pub to: u64,
+    /// In the format `Resolution` and `ChartResolution` are written in, where `1M` is a minute
     pub resolution: String,
 }
 
@@ -24,21 +35,244 @@ pub async fn get_candles(
     info: web::Query<CandleParams>,
     context: web::Data<WebContext>,
 ) -> Result<HttpResponse, ServerError> {
-    let resolution =
-        Resolution::from_str(info.resolution.as_str()).map_err(|_| ServerError::WrongResolution)?;
+    let resolution = ChartResolution::from_str(info.resolution.as_str())
+        .map_err(|_| ServerError::WrongResolution)?;
 
-    if !valid_market(&info.market_name, &context.markets) {
-        return Err(ServerError::WrongParameters);
-    }
+    let market = context
+        .markets
+        .iter()
+        .find(|m| m.name == info.market_name)
+        .ok_or(ServerError::WrongParameters)?;
+
+    let response = fetch_tv_candles(&context, market, resolution, info.from, info.to).await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Candles for a chart, shared by `/candles` and the UDF `/history` endpoint
+pub async fn fetch_tv_candles(
+    context: &WebContext,
+    market: &MarketInfo,
+    resolution: ChartResolution,
+    from_secs: u64,
+    to_secs: u64,
+) -> Result<TvResponse, ServerError> {
+    let to = to_timestampz(to_secs);
+    // Start from the whole bucket that `from` falls in, so the first candle isn't partial
+    let from = resolution.bucket_start(to_timestampz(from_secs));
 
-    let from = to_timestampz(info.from);
-    let to = to_timestampz(info.to);
+    let stored_resolution = match resolution.source() {
+        CandleSource::Stored(r) | CandleSource::Aggregated(r) => r,
+        CandleSource::Fills => {
+            if to_secs.saturating_sub(from_secs) > MAX_FILLS_RANGE_SECS {
+                return Err(ServerError::WrongParameters);
+            }
+            let fills = match fetch_fills_from(&context.pool, &market.address, from, to).await {
+                Ok(f) => f,
+                Err(_) => return Err(ServerError::DbQueryError),
+            };
+            if fills.is_empty() {
+                return Ok(TvResponse::no_data(None));
+            }
+            let candles = candles_from_fills(&fills, market, resolution);
+            return Ok(TvResponse::candles_to_tv(candles));
+        }
+    };
 
     let candles =
-        match fetch_candles_from(&context.pool, &info.market_name, resolution, from, to).await {
+        match fetch_candles_from(&context.pool, &market.name, stored_resolution, from, to).await {
             Ok(c) => c,
             Err(_) => return Err(ServerError::DbQueryError),
         };
 
-    Ok(HttpResponse::Ok().json(TvResponse::candles_to_tv(candles)))
+    if candles.is_empty() {
+        let next_candle =
+            match fetch_candle_before(&context.pool, &market.name, stored_resolution, from).await {
+                Ok(c) => c,
+                Err(_) => return Err(ServerError::DbQueryError),
+            };
+        let next_time =
+            next_candle.map(|c| resolution.bucket_start(c.start_time).timestamp() as u64);
+        return Ok(TvResponse::no_data(next_time));
+    }
+
+    let candles = match resolution.source() {
+        CandleSource::Aggregated(_) => aggregate_candles(&candles, resolution),
+        _ => candles,
+    };
+    Ok(TvResponse::candles_to_tv(candles))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web::Data, App};
+    use chrono::{Duration, TimeZone, Utc};
+    use deadpool_postgres::{Pool, Runtime};
+    use openbook_candles::{
+        database::{
+            initialize::connect_to_database, insert::upsert_candles, migrations::run_migrations,
+        },
+        structs::{
+            candle::Candle,
+            markets::test_market,
+            orderbook::OrderBookCache,
+            resolution::{CandleSource, Resolution},
+        },
+    };
+    use serde_json::Value;
+    use solana_sdk::pubkey::Pubkey;
+    use std::sync::Arc;
+    use strum::IntoEnumIterator;
+    use tokio_postgres::NoTls;
+
+    fn market(name: &str) -> MarketInfo {
+        MarketInfo {
+            bids_key: Pubkey::new_unique().to_string(),
+            asks_key: Pubkey::new_unique().to_string(),
+            ..test_market(name)
+        }
+    }
+
+    fn context(markets: Vec<MarketInfo>, pool: Pool) -> Data<WebContext> {
+        Data::new(WebContext {
+            rpc_url: String::new(),
+            orderbooks: Arc::new(OrderBookCache::new(&markets)),
+            markets,
+            pool,
+            usd_reference_markets: vec![],
+        })
+    }
+
+    #[test]
+    fn legacy_resolutions_map_to_stored_candles() {
+        let source = |s: &str| ChartResolution::from_str(s).unwrap().source();
+        for r in Resolution::iter() {
+            assert_eq!(source(&r.to_string()), CandleSource::Stored(r));
+        }
+        assert_eq!(source("1M"), CandleSource::Stored(Resolution::R1m));
+        assert_eq!(source("D"), CandleSource::Stored(Resolution::R1d));
+        assert_eq!(source("1"), CandleSource::Stored(Resolution::R1m));
+    }
+
+    /// Resolutions are parsed before anything touches the database, so a pool that can't
+    /// connect is enough to check which ones are accepted
+    #[actix_web::test]
+    async fn candles_accept_legacy_resolutions() {
+        let pool = deadpool_postgres::Config::new()
+            .create_pool(Some(Runtime::Tokio1), NoTls)
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(context(vec![], pool))
+                .service(get_candles),
+        )
+        .await;
+        for (resolution, errmsg) in [
+            // Accepted, so the request only fails on the unknown market
+            ("1M", "Bad request parameters"),
+            ("1H", "Bad request parameters"),
+            ("2H", "Bad request parameters"),
+            ("4H", "Bad request parameters"),
+            ("1D", "Bad request parameters"),
+            ("D", "Bad request parameters"),
+            ("1W", "Bad request parameters"),
+            ("1MO", "Bad request parameters"),
+            ("10S", "Bad request parameters"),
+            ("60", "Bad request parameters"),
+            ("0", "Wrong resolution"),
+            ("1Y", "Wrong resolution"),
+            // Longer than a century, so buckets could overflow chrono's dates
+            ("4000000000MO", "Wrong resolution"),
+            ("4000000000M", "Wrong resolution"),
+            ("99999999D", "Wrong resolution"),
+        ] {
+            let request = test::TestRequest::get()
+                .uri(&format!(
+                    "/candles?market_name=UNKNOWN&from=0&to=1&resolution={}",
+                    resolution
+                ))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status().as_u16(), 400, "{}", resolution);
+            let body: Value = test::read_body_json(response).await;
+            assert_eq!(body["errmsg"], errmsg, "{}", resolution);
+        }
+    }
+
+    /// Serves stored candles for each resolution. Needs a database from the PG_* variables,
+    /// run with `cargo test -- --ignored`.
+    #[actix_web::test]
+    #[ignore]
+    async fn candles_are_served_by_resolution() {
+        let pool = connect_to_database().await.unwrap();
+        run_migrations(&pool).await.unwrap();
+        let market_name = format!("E2E-{}/USDC", Utc::now().timestamp_nanos());
+
+        // One candle per stored resolution, each starting at a different time
+        let month = Utc.with_ymd_and_hms(2023, 5, 1, 0, 0, 0).unwrap();
+        let week = Utc.with_ymd_and_hms(2023, 5, 8, 0, 0, 0).unwrap();
+        let day = Utc.with_ymd_and_hms(2023, 5, 9, 0, 0, 0).unwrap();
+        let hour = Utc.with_ymd_and_hms(2023, 5, 9, 13, 0, 0).unwrap();
+        let stored = [
+            (
+                Resolution::R1mo,
+                month,
+                Utc.with_ymd_and_hms(2023, 6, 1, 0, 0, 0).unwrap(),
+            ),
+            (Resolution::R1w, week, week + Duration::weeks(1)),
+            (Resolution::R1d, day, day + Duration::days(1)),
+            (Resolution::R1h, hour, hour + Duration::hours(1)),
+        ];
+        let candles = stored
+            .iter()
+            .map(|(resolution, start_time, end_time)| Candle {
+                market_name: market_name.clone(),
+                start_time: *start_time,
+                end_time: *end_time,
+                resolution: resolution.to_string(),
+                open: 20.0,
+                close: 21.0,
+                high: 22.0,
+                low: 19.0,
+                volume: 100.0,
+                complete: true,
+            })
+            .collect::<Vec<Candle>>();
+        upsert_candles(&pool.get().await.unwrap(), &candles)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(context(vec![market(&market_name)], pool))
+                .service(get_candles),
+        )
+        .await;
+        let from = month.timestamp();
+        let to = Utc
+            .with_ymd_and_hms(2023, 6, 1, 0, 0, 0)
+            .unwrap()
+            .timestamp();
+        for (resolution, start_time) in [("1MO", month), ("1W", week), ("1D", day), ("1H", hour)] {
+            let request = test::TestRequest::get()
+                .uri(&format!(
+                    "/candles?market_name={}&from={}&to={}&resolution={}",
+                    market_name.replace('/', "%2F"),
+                    from,
+                    to,
+                    resolution
+                ))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status().as_u16(), 200, "{}", resolution);
+            let body: Value = test::read_body_json(response).await;
+            assert_eq!(body["s"], "ok", "{}", resolution);
+            assert_eq!(
+                body["time"],
+                serde_json::json!([start_time.timestamp()]),
+                "{}",
+                resolution
+            );
+        }
+    }
 }
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::server_error::ServerError;
+use actix_web::{get, web, HttpResponse, Scope};
+use chrono::{Duration, Utc};
+use futures::join;
+use openbook_candles::{
+    database::fetch::{
+        fetch_closes_24h_ago, fetch_coingecko_24h_high_low, fetch_coingecko_24h_volume,
+        fetch_trades_between,
+    },
+    structs::{
+        cmc::{
+            cmc_market_pair, market_symbols, price_change_percent, CmcAsset, CmcOrderBook,
+            CmcSummary, CmcTicker, CmcTrade,
+        },
+        coingecko::{CoinGecko24HourVolume, PgCoinGecko24HighLow},
+        markets::MarketInfo,
+        slab::construct_levels,
+    },
+    utils::WebContext,
+};
+use serde::Deserialize;
+
+/// Trades older than a day aren't part of the spec, so this only bounds busy markets
+const MAX_CMC_TRADES: i64 = 1000;
+
+pub fn service() -> Scope {
+    web::scope("/cmc")
+        .service(summary)
+        .service(assets)
+        .service(ticker)
+        .service(orderbook)
+        .service(trades)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CmcOrderBookParams {
+    /// 0 returns the full book
+    pub depth: Option<usize>,
+    /// 1 returns only the best bid and ask
+    pub level: Option<u8>,
+}
+
+/// 24h volume, high/low and the close from a day ago for every market, in the order of
+/// `context.markets`
+async fn fetch_24h_stats(
+    context: &WebContext,
+) -> Result<Vec<(CoinGecko24HourVolume, PgCoinGecko24HighLow, Option<f64>)>, ServerError> {
+    let markets = &context.markets;
+    let market_names = markets.iter().map(|x| x.name.as_str()).collect();
+    let market_addresses = markets.iter().map(|x| x.address.as_str()).collect();
+
+    let volume_fut = fetch_coingecko_24h_volume(&context.pool, &market_addresses);
+    let high_low_fut = fetch_coingecko_24h_high_low(&context.pool, &market_names);
+    let closes_fut = fetch_closes_24h_ago(&context.pool, &market_names);
+
+    let (volume_query, high_low_query, closes_query) = join!(volume_fut, high_low_fut, closes_fut);
+    let raw_volumes = volume_query.map_err(|_| ServerError::DbQueryError)?;
+    let mut high_low = high_low_query.map_err(|_| ServerError::DbQueryError)?;
+    let closes_24h_ago = closes_query.map_err(|_| ServerError::DbQueryError)?;
+
+    let mut volumes: Vec<CoinGecko24HourVolume> = raw_volumes
+        .into_iter()
+        .map(|v| v.convert_to_readable(markets))
+        .collect();
+    Ok(markets
+        .iter()
+        .map(|m| {
+            let volume = volumes
+                .iter()
+                .position(|x| x.market_name == m.name)
+                .map(|i| volumes.swap_remove(i))
+                .unwrap_or_default();
+            let hl = high_low
+                .iter()
+                .position(|x| x.market_name == m.name)
+                .map(|i| high_low.swap_remove(i))
+                .unwrap_or_default();
+            (volume, hl, closes_24h_ago.get(&m.name).copied())
+        })
+        .collect())
+}
+
+fn find_market_pair<'a>(
+    context: &'a WebContext,
+    market_pair: &str,
+) -> Result<&'a MarketInfo, ServerError> {
+    context
+        .markets
+        .iter()
+        .find(|m| cmc_market_pair(m) == market_pair)
+        .ok_or(ServerError::MarketNotFound)
+}
+
+#[get("/summary")]
+pub async fn summary(context: web::Data<WebContext>) -> Result<HttpResponse, ServerError> {
+    let stats = fetch_24h_stats(&context).await?;
+    let summaries = context
+        .markets
+        .iter()
+        .zip(stats)
+        .map(|(m, (volume, high_low, close_24h_ago))| {
+            let snapshot = context.orderbooks.snapshot(m);
+            CmcSummary {
+                trading_pairs: cmc_market_pair(m),
+                base_currency: m.base_mint_key.clone(),
+                quote_currency: m.quote_mint_key.clone(),
+                last_price: high_low.close,
+                lowest_ask: snapshot.as_ref().and_then(|s| s.best_ask(m)),
+                highest_bid: snapshot.as_ref().and_then(|s| s.best_bid(m)),
+                base_volume: volume.base_volume,
+                quote_volume: volume.target_volume,
+                highest_price_24h: high_low.high,
+                lowest_price_24h: high_low.low,
+                price_change_percent_24h: price_change_percent(high_low.close, close_24h_ago),
+            }
+        })
+        .collect::<Vec<CmcSummary>>();
+    Ok(HttpResponse::Ok().json(summaries))
+}
+
+#[get("/assets")]
+pub async fn assets(context: web::Data<WebContext>) -> Result<HttpResponse, ServerError> {
+    let mut assets = HashMap::new();
+    for m in context.markets.iter() {
+        let (base_symbol, quote_symbol) = market_symbols(m);
+        for (mint, symbol, decimals) in [
+            (&m.base_mint_key, base_symbol, m.base_decimals),
+            (&m.quote_mint_key, quote_symbol, m.quote_decimals),
+        ] {
+            assets.entry(mint.clone()).or_insert(CmcAsset {
+                name: symbol.clone(),
+                symbol,
+                contract_address: mint.clone(),
+                decimals,
+            });
+        }
+    }
+    Ok(HttpResponse::Ok().json(assets))
+}
+
+#[get("/ticker")]
+pub async fn ticker(context: web::Data<WebContext>) -> Result<HttpResponse, ServerError> {
+    let stats = fetch_24h_stats(&context).await?;
+    let tickers = context
+        .markets
+        .iter()
+        .zip(stats)
+        .map(|(m, (volume, high_low, _))| {
+            (
+                cmc_market_pair(m),
+                CmcTicker {
+                    base_id: m.base_mint_key.clone(),
+                    quote_id: m.quote_mint_key.clone(),
+                    last_price: high_low.close,
+                    base_volume: volume.base_volume,
+                    quote_volume: volume.target_volume,
+                    is_frozen: 0,
+                },
+            )
+        })
+        .collect::<HashMap<String, CmcTicker>>();
+    Ok(HttpResponse::Ok().json(tickers))
+}
+
+#[get("/orderbook/{market_pair}")]
+pub async fn orderbook(
+    path: web::Path<String>,
+    info: web::Query<CmcOrderBookParams>,
+    context: web::Data<WebContext>,
+) -> Result<HttpResponse, ServerError> {
+    let market = find_market_pair(&context, &path)?;
+    let depth = match (info.level, info.depth) {
+        (Some(1), _) => 1,
+        (_, None | Some(0)) => usize::MAX,
+        (_, Some(d)) => d,
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let snapshot = context
+        .orderbooks
+        .snapshot(market)
+        .ok_or(ServerError::OrderBookUnavailable)?;
+    let result = CmcOrderBook {
+        timestamp: timestamp as i64,
+        bids: construct_levels(&snapshot.bids, market, depth),
+        asks: construct_levels(&snapshot.asks, market, depth),
+    };
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[get("/trades/{market_pair}")]
+pub async fn trades(
+    path: web::Path<String>,
+    context: web::Data<WebContext>,
+) -> Result<HttpResponse, ServerError> {
+    let market = find_market_pair(&context, &path)?;
+    let to = Utc::now();
+    let from = to - Duration::days(1);
+
+    let pg_trades = match fetch_trades_between(
+        &context.pool,
+        &market.address,
+        from,
+        to,
+        None,
+        MAX_CMC_TRADES,
+    )
+    .await
+    {
+        Ok(t) => t,
+        Err(_) => return Err(ServerError::DbQueryError),
+    };
+    let trades = pg_trades
+        .iter()
+        .map(|t| CmcTrade::from_pg_trade(t, market))
+        .collect::<Vec<CmcTrade>>();
+    Ok(HttpResponse::Ok().json(trades))
+}
@@ -2,34 +2,50 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::server_error::ServerError;
 use actix_web::{get, web, HttpResponse, Scope};
+use chrono::{DateTime, Utc};
 use futures::join;
 use openbook_candles::{
-    database::fetch::{fetch_coingecko_24h_high_low, fetch_coingecko_24h_volume},
+    database::fetch::{
+        fetch_coingecko_24h_high_low, fetch_coingecko_24h_volume, fetch_trades_between,
+    },
     structs::{
         coingecko::{
-            CoinGecko24HourVolume, CoinGeckoOrderBook, CoinGeckoPair, CoinGeckoTicker,
-            PgCoinGecko24HighLow,
+            CoinGecko24HourVolume, CoinGeckoHistoricalTrades, CoinGeckoOrderBook, CoinGeckoPair,
+            CoinGeckoTicker, CoinGeckoTrade, PgCoinGecko24HighLow,
         },
-        slab::get_orderbooks_with_depth,
+        slab::construct_levels,
     },
-    utils::WebContext,
+    utils::{to_timestampz, WebContext},
 };
 use serde::Deserialize;
-use solana_client::nonblocking::rpc_client::RpcClient;
 
 pub fn service() -> Scope {
     web::scope("/coingecko")
         .service(pairs)
         .service(tickers)
         .service(orderbook)
+        .service(historical_trades)
 }
 
+/// Applies when `limit` is omitted, and caps larger limits
+const MAX_HISTORICAL_TRADES: i64 = 1000;
+
 #[derive(Debug, Deserialize)]
 pub struct OrderBookParams {
     pub ticker_id: String, // market_name
     pub depth: usize,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct HistoricalTradesParams {
+    pub ticker_id: String, // market_name
+    #[serde(rename = "type")]
+    pub trade_type: Option<String>,
+    pub limit: Option<i64>,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+}
+
 #[get("/pairs")]
 pub async fn pairs(context: web::Data<WebContext>) -> Result<HttpResponse, ServerError> {
     let markets = context.markets.clone();
@@ -49,12 +65,10 @@ pub async fn pairs(context: web::Data<WebContext>) -> Result<HttpResponse, Serve
 
 #[get("/tickers")]
 pub async fn tickers(context: web::Data<WebContext>) -> Result<HttpResponse, ServerError> {
-    // let client = RpcClient::new(context.rpc_url.clone());
     let markets = &context.markets;
     let market_names = markets.iter().map(|x| x.name.as_str()).collect();
     let market_addresses = markets.iter().map(|x| x.address.as_str()).collect();
 
-    // let bba_fut = get_best_bids_and_asks(client, markets);
     let volume_fut = fetch_coingecko_24h_volume(&context.pool, &market_addresses);
     let high_low_fut = fetch_coingecko_24h_high_low(&context.pool, &market_names);
 
@@ -88,6 +102,13 @@ pub async fn tickers(context: web::Data<WebContext>) -> Result<HttpResponse, Ser
                 .iter()
                 .find(|x| x.market_name == name)
                 .unwrap_or(&default_volume);
+            let snapshot = context.orderbooks.snapshot(m);
+            let bid = snapshot.as_ref().and_then(|s| s.best_bid(m));
+            let ask = snapshot.as_ref().and_then(|s| s.best_ask(m));
+            let spread = match (bid, ask) {
+                (Some(b), Some(a)) => Some(a - b),
+                _ => None,
+            };
             CoinGeckoTicker {
                 ticker_id: m.name.clone(),
                 base_currency: m.base_mint_key.clone(),
@@ -95,6 +116,9 @@ pub async fn tickers(context: web::Data<WebContext>) -> Result<HttpResponse, Ser
                 last_price: high_low.close.to_string(),
                 base_volume: volume.base_volume.to_string(),
                 target_volume: volume.target_volume.to_string(),
+                bid: bid.map(|b| b.to_string()),
+                ask: ask.map(|a| a.to_string()),
+                spread: spread.map(|s| s.to_string()),
                 high: high_low.high.to_string(),
                 low: high_low.low.to_string(),
             }
@@ -104,12 +128,11 @@ pub async fn tickers(context: web::Data<WebContext>) -> Result<HttpResponse, Ser
     Ok(HttpResponse::Ok().json(tickers))
 }
 
-#[get("/orderbook")] // TODO: implement an optional geyser version
+#[get("/orderbook")]
 pub async fn orderbook(
     info: web::Query<OrderBookParams>,
     context: web::Data<WebContext>,
 ) -> Result<HttpResponse, ServerError> {
-    let client = RpcClient::new(context.rpc_url.clone());
     let market_name = &info.ticker_id;
     let market = context
         .markets
@@ -120,12 +143,112 @@ pub async fn orderbook(
 
     let now = SystemTime::now();
     let timestamp = now.duration_since(UNIX_EPOCH).unwrap().as_millis();
-    let (bid_levels, ask_levels) = get_orderbooks_with_depth(client, market, depth).await;
+    let snapshot = context
+        .orderbooks
+        .snapshot(market)
+        .ok_or(ServerError::OrderBookUnavailable)?;
     let result = CoinGeckoOrderBook {
         timestamp: timestamp.to_string(),
         ticker_id: market.name.clone(),
-        bids: bid_levels,
-        asks: ask_levels,
+        bids: construct_levels(&snapshot.bids, market, depth),
+        asks: construct_levels(&snapshot.asks, market, depth),
+        slot: snapshot.slot,
+        age_ms: snapshot.age_ms(),
+    };
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[get("/historical_trades")]
+pub async fn historical_trades(
+    info: web::Query<HistoricalTradesParams>,
+    context: web::Data<WebContext>,
+) -> Result<HttpResponse, ServerError> {
+    let market = context
+        .markets
+        .iter()
+        .find(|m| m.name == info.ticker_id)
+        .ok_or(ServerError::MarketNotFound)?;
+    let bid = match info.trade_type.as_deref() {
+        Some("buy") => Some(true),
+        Some("sell") => Some(false),
+        None => None,
+        Some(_) => return Err(ServerError::WrongParameters),
+    };
+    let limit = historical_trades_limit(info.limit)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let (from, to) = historical_trades_range(info.start_time, info.end_time, now)?;
+
+    // Only taker fills are fetched, so each match is counted once
+    let pg_trades =
+        match fetch_trades_between(&context.pool, &market.address, from, to, bid, limit).await {
+            Ok(t) => t,
+            Err(_) => return Err(ServerError::DbQueryError),
+        };
+    let (buys, sells): (Vec<CoinGeckoTrade>, Vec<CoinGeckoTrade>) = pg_trades
+        .iter()
+        .map(|t| CoinGeckoTrade::from_pg_trade(t, market))
+        .partition(|t| t.trade_type == "buy");
+
+    let result = CoinGeckoHistoricalTrades {
+        buy: (bid != Some(false)).then_some(buys),
+        sell: (bid != Some(true)).then_some(sells),
     };
     Ok(HttpResponse::Ok().json(result))
 }
+
+/// A limit of 0 would mean the full history in the CoinGecko spec, which isn't served
+fn historical_trades_limit(limit: Option<i64>) -> Result<i64, ServerError> {
+    match limit {
+        None => Ok(MAX_HISTORICAL_TRADES),
+        Some(l) if l > 0 => Ok(l.min(MAX_HISTORICAL_TRADES)),
+        Some(_) => Err(ServerError::WrongParameters),
+    }
+}
+
+/// Defaults to every trade up to `now`, with `end_time` exclusive
+fn historical_trades_range(
+    start_time: Option<u64>,
+    end_time: Option<u64>,
+    now: u64,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), ServerError> {
+    let start_time = start_time.unwrap_or(0);
+    let end_time = end_time.unwrap_or(now + 1);
+    if start_time >= end_time {
+        return Err(ServerError::WrongParameters);
+    }
+    Ok((to_timestampz(start_time), to_timestampz(end_time)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn historical_trades_limits() {
+        assert_eq!(historical_trades_limit(None).unwrap(), 1000);
+        assert_eq!(historical_trades_limit(Some(1)).unwrap(), 1);
+        assert_eq!(historical_trades_limit(Some(50)).unwrap(), 50);
+        assert_eq!(historical_trades_limit(Some(5000)).unwrap(), 1000);
+        assert!(historical_trades_limit(Some(0)).is_err());
+        assert!(historical_trades_limit(Some(-1)).is_err());
+    }
+
+    #[test]
+    fn historical_trades_ranges() {
+        let now = 1_680_000_000;
+        let range = |start, end| {
+            historical_trades_range(start, end, now)
+                .map(|(from, to)| (from.timestamp(), to.timestamp()))
+                .ok()
+        };
+        assert_eq!(range(None, None), Some((0, now as i64 + 1)));
+        assert_eq!(range(Some(100), None), Some((100, now as i64 + 1)));
+        assert_eq!(range(Some(100), Some(200)), Some((100, 200)));
+        assert_eq!(range(None, Some(200)), Some((0, 200)));
+        assert_eq!(range(Some(200), Some(200)), None);
+        assert_eq!(range(Some(300), Some(200)), None);
+    }
+}
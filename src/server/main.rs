@@ -7,23 +7,38 @@ use actix_web::{
 };
 use actix_web_prom::PrometheusMetricsBuilder;
 use candles::get_candles;
+use log::warn;
 use prometheus::Registry;
 
 use markets::get_markets;
 use openbook_candles::{
-    database::initialize::connect_to_database,
-    structs::markets::{fetch_market_infos, load_markets},
+    database::{initialize::connect_to_database, migrations::check_migrations},
+    structs::{
+        markets::{fetch_market_infos, load_markets, valid_market},
+        orderbook::OrderBookCache,
+    },
     utils::{Config, WebContext},
 };
+use orderbook::get_orderbook;
+use orderbooks::{run_orderbook_feed, OrderBookFeedConfig};
 use std::env;
-use std::thread;
-use traders::{get_top_traders_by_base_volume, get_top_traders_by_quote_volume};
+use std::{sync::Arc, thread};
+use traders::{
+    get_top_traders_by_base_volume, get_top_traders_by_quote_volume, get_top_traders_by_usd_volume,
+    get_trader_fills, get_trader_summary,
+};
+use trades::get_trades;
 
 mod candles;
+mod cmc;
 mod coingecko;
 mod markets;
+mod orderbook;
+mod orderbooks;
 mod server_error;
 mod traders;
+mod trades;
+mod udf;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -40,9 +55,22 @@ async fn main() -> std::io::Result<()> {
         rpc_url: rpc_url.clone(),
     };
 
+    let usd_reference_markets: Vec<String> = dotenv::var("USD_REFERENCE_MARKETS")
+        .unwrap_or_else(|_| "SOL/USDC".to_string())
+        .split(',')
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty())
+        .collect();
+
     let markets = load_markets(path_to_markets_json);
     let market_infos = fetch_market_infos(&config, markets).await.unwrap();
+    for name in usd_reference_markets.iter() {
+        if !valid_market(name, &market_infos) {
+            warn!("USD reference market {} is not in the markets file", name);
+        }
+    }
     let pool = connect_to_database().await.unwrap();
+    check_migrations(&pool).await.unwrap();
 
     let registry = Registry::new();
     // For serving metrics on a private port
@@ -60,10 +88,27 @@ async fn main() -> std::io::Result<()> {
         .build()
         .unwrap();
 
+    let orderbooks = Arc::new(OrderBookCache::new(&market_infos));
+    let feed_config = OrderBookFeedConfig::from_env().unwrap();
+
+    // Thread to keep the in-memory order books up to date
+    let feed_rpc_url = rpc_url.clone();
+    let feed_orderbooks = orderbooks.clone();
+    let orderbook_thread = thread::spawn(move || {
+        let sys = System::new();
+        sys.block_on(run_orderbook_feed(
+            feed_rpc_url,
+            feed_orderbooks,
+            feed_config,
+        ));
+    });
+
     let context = Data::new(WebContext {
         rpc_url,
         pool,
         markets: market_infos,
+        orderbooks,
+        usd_reference_markets,
     });
 
     println!("Starting server");
@@ -80,8 +125,15 @@ async fn main() -> std::io::Result<()> {
                         .service(get_candles)
                         .service(get_top_traders_by_base_volume)
                         .service(get_top_traders_by_quote_volume)
+                        .service(get_top_traders_by_usd_volume)
+                        .service(get_trader_fills)
+                        .service(get_trader_summary)
                         .service(get_markets)
-                        .service(coingecko::service()),
+                        .service(get_orderbook)
+                        .service(get_trades)
+                        .service(coingecko::service())
+                        .service(cmc::service())
+                        .service(udf::service()),
                 )
         })
         .bind(&bind_addr)
@@ -102,5 +154,6 @@ async fn main() -> std::io::Result<()> {
 
     private_server.join().unwrap();
     public_server.join().unwrap();
+    orderbook_thread.join().unwrap();
     Ok(())
 }
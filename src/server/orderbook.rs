@@ -0,0 +1,77 @@
+use openbook_candles::{
+    database::fetch::fetch_open_orders_owners,
+    structs::orderbook::{
+        construct_l2_levels, construct_l3_orders, grouping_lots, open_orders_accounts,
+        OrderBookResponse,
+    },
+    utils::WebContext,
+};
+
+use crate::server_error::ServerError;
+
+use {
+    actix_web::{get, web, HttpResponse},
+    serde::Deserialize,
+};
+
+const DEFAULT_DEPTH: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct OrderBookParams {
+    pub market_name: String,
+    /// 2 for aggregated price levels, 3 for individual orders
+    pub level: Option<u8>,
+    pub depth: Option<usize>,
+    /// Price increment to group L2 levels by, a whole number of ticks
+    pub grouping: Option<f64>,
+}
+
+#[get("/orderbook")]
+pub async fn get_orderbook(
+    info: web::Query<OrderBookParams>,
+    context: web::Data<WebContext>,
+) -> Result<HttpResponse, ServerError> {
+    let market = context
+        .markets
+        .iter()
+        .find(|m| m.name == info.market_name)
+        .ok_or(ServerError::MarketNotFound)?;
+    let depth = info.depth.unwrap_or(DEFAULT_DEPTH);
+    let grouping = match info.grouping {
+        Some(g) => Some(grouping_lots(g, market).ok_or(ServerError::WrongParameters)?),
+        None => None,
+    };
+
+    let snapshot = context
+        .orderbooks
+        .snapshot(market)
+        .ok_or(ServerError::OrderBookUnavailable)?;
+
+    match info.level.unwrap_or(2) {
+        2 => Ok(HttpResponse::Ok().json(OrderBookResponse {
+            market_name: market.name.clone(),
+            slot: snapshot.slot,
+            age_ms: snapshot.age_ms(),
+            bids: construct_l2_levels(&snapshot.bids, market, depth, grouping, true),
+            asks: construct_l2_levels(&snapshot.asks, market, depth, grouping, false),
+        })),
+        3 => {
+            let bids = &snapshot.bids[..depth.min(snapshot.bids.len())];
+            let asks = &snapshot.asks[..depth.min(snapshot.asks.len())];
+            let mut accounts = open_orders_accounts(bids);
+            accounts.append(&mut open_orders_accounts(asks));
+            let owners = match fetch_open_orders_owners(&context.pool, &accounts).await {
+                Ok(o) => o,
+                Err(_) => return Err(ServerError::DbQueryError),
+            };
+            Ok(HttpResponse::Ok().json(OrderBookResponse {
+                market_name: market.name.clone(),
+                slot: snapshot.slot,
+                age_ms: snapshot.age_ms(),
+                bids: construct_l3_orders(bids, market, depth, &owners),
+                asks: construct_l3_orders(asks, market, depth, &owners),
+            }))
+        }
+        _ => Err(ServerError::WrongParameters),
+    }
+}
@@ -0,0 +1,125 @@
+use log::{debug, warn};
+use openbook_candles::{
+    structs::orderbook::OrderBookCache,
+    worker::trade_fetching::source::{
+        build_source, run_source, IngestionConfig, IngestionSource, IngestionUpdate, SourceFilter,
+    },
+};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::commitment_config::CommitmentConfig;
+use std::{collections::HashMap, sync::Arc, time::Duration as WaitDuration};
+use tokio::sync::mpsc;
+
+/// How often to poll the order books when there is no streaming source
+const POLLING_REFRESH_MS: u64 = 1000;
+/// Streaming sources only send changes, so still poll occasionally in case one was missed
+const STREAMING_REFRESH_MS: u64 = 30000;
+
+pub struct OrderBookFeedConfig {
+    /// None when books are only polled over RPC
+    pub source: Option<Arc<dyn IngestionSource>>,
+    pub refresh_ms: u64,
+}
+
+impl OrderBookFeedConfig {
+    /// Reads the ingestion source and `ORDERBOOK_REFRESH_MS`, so a bad setting stops the server
+    /// at startup rather than leaving it up without order books
+    pub fn from_env() -> anyhow::Result<Self> {
+        let source = build_source(&IngestionConfig::from_env()?)?;
+        let refresh_ms = parse_refresh_ms(
+            dotenv::var("ORDERBOOK_REFRESH_MS").ok().as_deref(),
+            source.is_some(),
+        )?;
+        Ok(OrderBookFeedConfig { source, refresh_ms })
+    }
+}
+
+fn parse_refresh_ms(value: Option<&str>, streaming: bool) -> anyhow::Result<u64> {
+    match value {
+        Some(ms) => match ms.parse::<u64>() {
+            Ok(ms) if ms > 0 => Ok(ms),
+            _ => Err(anyhow::anyhow!(
+                "ORDERBOOK_REFRESH_MS must be a positive number of milliseconds, got {}",
+                ms
+            )),
+        },
+        None if streaming => Ok(STREAMING_REFRESH_MS),
+        None => Ok(POLLING_REFRESH_MS),
+    }
+}
+
+/// Keeps the order book cache up to date. Account updates are streamed from the configured
+/// ingestion source (`accountSubscribe` or geyser) and the books are also polled over RPC.
+/// Never returns, errors are logged and retried.
+pub async fn run_orderbook_feed(
+    rpc_url: String,
+    cache: Arc<OrderBookCache>,
+    config: OrderBookFeedConfig,
+) {
+    let OrderBookFeedConfig { source, refresh_ms } = config;
+    if let Some(source) = source {
+        let (sender, mut receiver) = mpsc::channel(1000);
+        let filter = SourceFilter {
+            target_markets: HashMap::new(),
+            programs: vec![],
+            accounts: cache.accounts(),
+        };
+        tokio::spawn(async move {
+            run_source(source, filter, sender, |_| {}).await;
+        });
+        let stream_cache = cache.clone();
+        tokio::spawn(async move {
+            while let Some(update) = receiver.recv().await {
+                if let IngestionUpdate::Account {
+                    pubkey,
+                    slot,
+                    mut data,
+                } = update
+                {
+                    stream_cache.update(&pubkey, slot, &mut data);
+                }
+            }
+        });
+    }
+
+    let client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
+    loop {
+        if let Err(e) = refresh_orderbooks(&client, &cache).await {
+            warn!("failed to refresh order books: {}", e);
+        }
+        tokio::time::sleep(WaitDuration::from_millis(refresh_ms)).await;
+    }
+}
+
+async fn refresh_orderbooks(client: &RpcClient, cache: &OrderBookCache) -> anyhow::Result<()> {
+    let accounts = cache.accounts();
+    // getMultipleAccounts takes at most 100 accounts
+    for keys in accounts.chunks(100) {
+        let response = client
+            .get_multiple_accounts_with_commitment(keys, CommitmentConfig::confirmed())
+            .await?;
+        let slot = response.context.slot;
+        for (key, account) in keys.iter().zip(response.value) {
+            if let Some(mut account) = account {
+                cache.update(key, slot, &mut account.data);
+            }
+        }
+    }
+    debug!("Refreshed {} order book accounts", accounts.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_interval_defaults_to_the_feed_and_rejects_bad_values() {
+        assert_eq!(parse_refresh_ms(None, false).unwrap(), POLLING_REFRESH_MS);
+        assert_eq!(parse_refresh_ms(None, true).unwrap(), STREAMING_REFRESH_MS);
+        assert_eq!(parse_refresh_ms(Some("250"), true).unwrap(), 250);
+        for bad in ["", "1s", "-5", "0"] {
+            assert!(parse_refresh_ms(Some(bad), false).is_err());
+        }
+    }
+}
@@ -1,9 +1,6 @@
-use actix_web::{
-    error,
-    http::{header::ContentType, StatusCode},
-    HttpResponse,
-};
+use actix_web::{error, http::StatusCode, HttpResponse};
 use derive_more::{Display, Error};
+use serde_json::json;
 
 #[derive(Debug, Display, Error)]
 pub enum ServerError {
@@ -21,13 +18,17 @@ pub enum ServerError {
     MarketNotFound,
     #[display(fmt = "Request symbol not found")]
     SymbolNotFound,
+    #[display(fmt = "Order book not loaded yet")]
+    OrderBookUnavailable,
 }
 
 impl error::ResponseError for ServerError {
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::html())
-            .body(self.to_string())
+        // Same shape as a TradingView error response
+        HttpResponse::build(self.status_code()).json(json!({
+            "s": "error",
+            "errmsg": self.to_string(),
+        }))
     }
 
     fn status_code(&self) -> StatusCode {
@@ -39,6 +40,7 @@ impl error::ResponseError for ServerError {
             ServerError::DbPoolError => StatusCode::INTERNAL_SERVER_ERROR,
             ServerError::MarketNotFound => StatusCode::BAD_REQUEST,
             ServerError::SymbolNotFound => StatusCode::BAD_REQUEST,
+            ServerError::OrderBookUnavailable => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 }
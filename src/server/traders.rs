@@ -2,20 +2,51 @@ use crate::server_error::ServerError;
 use openbook_candles::{
     database::fetch::{
         fetch_top_traders_by_base_volume_from, fetch_top_traders_by_quote_volume_from,
+        fetch_top_traders_by_usd_volume_from, fetch_trader_fills, fetch_trader_fills_between,
+    },
+    structs::{
+        markets::MarketInfo,
+        trader::{
+            calculate_trader_volume, usd_valuations, PgTraderFill, Trader, TraderFill,
+            TraderFillsResponse, TraderMarketSummarizer, TraderResponse, TraderSummaryResponse,
+            UsdTraderResponse, VolumeType,
+        },
+        trades::{FillFilter, TradeCursor},
     },
-    structs::trader::{calculate_trader_volume, Trader, TraderResponse, VolumeType},
     utils::{to_timestampz, WebContext},
 };
+use std::collections::HashMap;
 use {
     actix_web::{get, web, HttpResponse},
     serde::Deserialize,
 };
 
+const DEFAULT_FILLS_LIMIT: i64 = 100;
+const MAX_FILLS_LIMIT: i64 = 1000;
+const DEFAULT_TRADERS_LIMIT: i64 = 100;
+const MAX_TRADERS_LIMIT: i64 = 10000;
+const TRADER_SUMMARY_PAGE_SIZE: i64 = 5000;
+/// Bounds the work a single summary can do, longer ranges are truncated
+const MAX_TRADER_SUMMARY_FILLS: usize = 100_000;
+
 #[derive(Debug, Deserialize)]
 pub struct TraderParams {
     pub market_name: String,
     pub from: u64,
     pub to: u64,
+    pub client_order_id: Option<u64>,
+    pub owner_slot: Option<u8>,
+    pub referred: Option<bool>,
+}
+
+impl TraderParams {
+    fn filter(&self) -> FillFilter {
+        FillFilter {
+            client_order_id: self.client_order_id,
+            owner_slot: self.owner_slot,
+            referred: self.referred,
+        }
+    }
 }
 
 #[get("/traders/base-volume")]
@@ -36,6 +67,7 @@ pub async fn get_top_traders_by_base_volume(
         &selected_market.address,
         from,
         to,
+        &info.filter(),
     )
     .await
     {
@@ -45,7 +77,13 @@ pub async fn get_top_traders_by_base_volume(
 
     let traders = raw_traders
         .into_iter()
-        .map(|t| calculate_trader_volume(t, selected_market.base_decimals))
+        .map(|t| {
+            calculate_trader_volume(
+                t,
+                selected_market.base_decimals,
+                selected_market.quote_decimals,
+            )
+        })
         .collect::<Vec<Trader>>();
 
     let response = TraderResponse {
@@ -75,6 +113,7 @@ pub async fn get_top_traders_by_quote_volume(
         &selected_market.address,
         from,
         to,
+        &info.filter(),
     )
     .await
     {
@@ -84,7 +123,13 @@ pub async fn get_top_traders_by_quote_volume(
 
     let traders = raw_traders
         .into_iter()
-        .map(|t| calculate_trader_volume(t, selected_market.quote_decimals))
+        .map(|t| {
+            calculate_trader_volume(
+                t,
+                selected_market.quote_decimals,
+                selected_market.quote_decimals,
+            )
+        })
         .collect::<Vec<Trader>>();
 
     let response = TraderResponse {
@@ -95,3 +140,231 @@ pub async fn get_top_traders_by_quote_volume(
     };
     Ok(HttpResponse::Ok().json(response))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct UsdTraderParams {
+    pub from: u64,
+    pub to: u64,
+    /// Only maker (true) or taker (false) fills
+    pub maker: Option<bool>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    pub client_order_id: Option<u64>,
+    pub owner_slot: Option<u8>,
+    pub referred: Option<bool>,
+}
+
+#[get("/traders/usd-volume")]
+pub async fn get_top_traders_by_usd_volume(
+    info: web::Query<UsdTraderParams>,
+    context: web::Data<WebContext>,
+) -> Result<HttpResponse, ServerError> {
+    let limit = info.limit.unwrap_or(DEFAULT_TRADERS_LIMIT);
+    let offset = info.offset.unwrap_or(0);
+    if !(1..=MAX_TRADERS_LIMIT).contains(&limit) || offset < 0 || info.from >= info.to {
+        return Err(ServerError::WrongParameters);
+    }
+    let from = to_timestampz(info.from);
+    let to = to_timestampz(info.to);
+    let filter = FillFilter {
+        client_order_id: info.client_order_id,
+        owner_slot: info.owner_slot,
+        referred: info.referred,
+    };
+
+    let valuations = usd_valuations(&context.markets, &context.usd_reference_markets);
+    let raw_traders = match fetch_top_traders_by_usd_volume_from(
+        &context.pool,
+        &valuations,
+        from,
+        to,
+        info.maker,
+        &filter,
+        limit,
+        offset,
+    )
+    .await
+    {
+        Ok(c) => c,
+        Err(_) => return Err(ServerError::DbQueryError),
+    };
+
+    let next_offset = if raw_traders.len() as i64 == limit {
+        Some(offset + limit)
+    } else {
+        None
+    };
+    let markets: Vec<String> = valuations.iter().map(|v| v.market.name.clone()).collect();
+    let excluded_markets = context
+        .markets
+        .iter()
+        .filter(|m| !markets.contains(&m.name))
+        .map(|m| m.name.clone())
+        .collect();
+
+    let response = UsdTraderResponse {
+        start_time: info.from,
+        end_time: info.to,
+        volume_type: VolumeType::Usd.to_string(),
+        markets,
+        excluded_markets,
+        traders: raw_traders.into_iter().map(Trader::from).collect(),
+        next_offset,
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TraderFillsParams {
+    pub market_name: Option<String>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub limit: Option<i64>,
+    /// The `next_cursor` of a previous response
+    pub before: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TraderSummaryParams {
+    pub market_name: Option<String>,
+    pub from: u64,
+    pub to: u64,
+}
+
+/// Looks up the optional market filter, erroring if it names an unknown market
+fn find_market<'a>(
+    markets: &'a [MarketInfo],
+    market_name: Option<&str>,
+) -> Result<Option<&'a MarketInfo>, ServerError> {
+    match market_name {
+        Some(name) => match markets.iter().find(|m| m.name == name) {
+            Some(m) => Ok(Some(m)),
+            None => Err(ServerError::MarketNotFound),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Fills in markets this server doesn't serve are dropped, since they can't be priced
+fn to_trader_fills(markets: &[MarketInfo], pg_fills: &[PgTraderFill]) -> Vec<TraderFill> {
+    pg_fills
+        .iter()
+        .filter_map(|f| {
+            markets
+                .iter()
+                .find(|m| m.address == f.market)
+                .map(|m| TraderFill::from_pg_fill(f, m))
+        })
+        .collect()
+}
+
+/// `owner` can be either an open orders owner or a single open orders account
+#[get("/traders/{owner}/fills")]
+pub async fn get_trader_fills(
+    path: web::Path<String>,
+    info: web::Query<TraderFillsParams>,
+    context: web::Data<WebContext>,
+) -> Result<HttpResponse, ServerError> {
+    let owner = path.into_inner();
+    let market = find_market(&context.markets, info.market_name.as_deref())?;
+    let limit = info.limit.unwrap_or(DEFAULT_FILLS_LIMIT);
+    if !(1..=MAX_FILLS_LIMIT).contains(&limit) {
+        return Err(ServerError::WrongParameters);
+    }
+    let before = match &info.before {
+        Some(b) => Some(TradeCursor::parse(b).ok_or(ServerError::WrongParameters)?),
+        None => None,
+    };
+
+    let pg_fills = match fetch_trader_fills(
+        &context.pool,
+        &owner,
+        market.map(|m| m.address.as_str()),
+        info.from.map(to_timestampz),
+        info.to.map(to_timestampz),
+        before.as_ref(),
+        limit,
+    )
+    .await
+    {
+        Ok(f) => f,
+        Err(_) => return Err(ServerError::DbQueryError),
+    };
+
+    let next_cursor = match pg_fills.last() {
+        Some(f) if pg_fills.len() as i64 == limit => Some(f.trade.cursor().to_string()),
+        _ => None,
+    };
+    let response = TraderFillsResponse {
+        owner,
+        fills: to_trader_fills(&context.markets, &pg_fills),
+        next_cursor,
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Per market totals and FIFO realized PnL. Positions opened before `from` are ignored.
+#[get("/traders/{owner}/summary")]
+pub async fn get_trader_summary(
+    path: web::Path<String>,
+    info: web::Query<TraderSummaryParams>,
+    context: web::Data<WebContext>,
+) -> Result<HttpResponse, ServerError> {
+    let owner = path.into_inner();
+    let market = find_market(&context.markets, info.market_name.as_deref())?;
+    if info.from >= info.to {
+        return Err(ServerError::WrongParameters);
+    }
+
+    // Fills are summarized a page at a time, so only the open lots are kept in memory
+    let mut summarizers: HashMap<String, TraderMarketSummarizer> = HashMap::new();
+    let mut after: Option<TradeCursor> = None;
+    let mut num_fills = 0;
+    let truncated = loop {
+        let pg_fills = match fetch_trader_fills_between(
+            &context.pool,
+            &owner,
+            market.map(|m| m.address.as_str()),
+            to_timestampz(info.from),
+            to_timestampz(info.to),
+            after.as_ref(),
+            TRADER_SUMMARY_PAGE_SIZE,
+        )
+        .await
+        {
+            Ok(f) => f,
+            Err(_) => return Err(ServerError::DbQueryError),
+        };
+        num_fills += pg_fills.len();
+        for fill in to_trader_fills(&context.markets, &pg_fills) {
+            summarizers
+                .entry(fill.market_name.clone())
+                .or_default()
+                .add_fill(&fill);
+        }
+        match pg_fills.last() {
+            Some(f) if pg_fills.len() as i64 == TRADER_SUMMARY_PAGE_SIZE => {
+                if num_fills >= MAX_TRADER_SUMMARY_FILLS {
+                    break true;
+                }
+                after = Some(f.trade.cursor());
+            }
+            _ => break false,
+        }
+    };
+
+    let markets = context
+        .markets
+        .iter()
+        .filter_map(|m| summarizers.remove(&m.name).map(|s| s.finish()))
+        .collect();
+
+    let response = TraderSummaryResponse {
+        owner,
+        start_time: info.from,
+        end_time: info.to,
+        markets,
+        truncated,
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
@@ -0,0 +1,79 @@
+use openbook_candles::{
+    database::fetch::fetch_trades,
+    structs::trades::{FillFilter, Trade, TradeCursor, TradesResponse},
+    utils::WebContext,
+};
+
+use crate::server_error::ServerError;
+
+use {
+    actix_web::{get, web, HttpResponse},
+    serde::Deserialize,
+};
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct TradesParams {
+    pub market_name: String,
+    pub limit: Option<i64>,
+    /// The `next_cursor` of a previous response
+    pub before: Option<String>,
+    pub client_order_id: Option<u64>,
+    pub owner_slot: Option<u8>,
+    pub referred: Option<bool>,
+}
+
+#[get("/trades")]
+pub async fn get_trades(
+    info: web::Query<TradesParams>,
+    context: web::Data<WebContext>,
+) -> Result<HttpResponse, ServerError> {
+    let market = context
+        .markets
+        .iter()
+        .find(|m| m.name == info.market_name)
+        .ok_or(ServerError::MarketNotFound)?;
+    let limit = info.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ServerError::WrongParameters);
+    }
+    let before = match &info.before {
+        Some(b) => Some(TradeCursor::parse(b).ok_or(ServerError::WrongParameters)?),
+        None => None,
+    };
+
+    let filter = FillFilter {
+        client_order_id: info.client_order_id,
+        owner_slot: info.owner_slot,
+        referred: info.referred,
+    };
+
+    let pg_trades = match fetch_trades(
+        &context.pool,
+        &market.address,
+        limit,
+        before.as_ref(),
+        &filter,
+    )
+    .await
+    {
+        Ok(t) => t,
+        Err(_) => return Err(ServerError::DbQueryError),
+    };
+
+    let next_cursor = match pg_trades.last() {
+        Some(t) if pg_trades.len() as i64 == limit => Some(t.cursor().to_string()),
+        _ => None,
+    };
+    let response = TradesResponse {
+        market_name: market.name.clone(),
+        trades: pg_trades
+            .iter()
+            .map(|t| Trade::from_pg_trade(t, market))
+            .collect(),
+        next_cursor,
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
@@ -0,0 +1,195 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{candles::fetch_tv_candles, server_error::ServerError};
+use actix_web::{get, web, HttpResponse, Scope};
+use openbook_candles::{
+    structs::{
+        markets::MarketInfo,
+        resolution::ChartResolution,
+        tradingview::{TvConfig, TvSearchResult, TvSymbolInfo, TV_EXCHANGE, TV_SYMBOL_TYPE},
+    },
+    utils::WebContext,
+};
+use serde::Deserialize;
+
+const DEFAULT_SEARCH_LIMIT: usize = 30;
+
+pub fn service() -> Scope {
+    web::scope("/udf")
+        .service(config)
+        .service(symbols)
+        .service(search)
+        .service(time)
+        .service(history)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SymbolParams {
+    pub symbol: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryParams {
+    pub symbol: String,
+    /// In TradingView's format, where `1M` is a month
+    pub resolution: String,
+    pub from: u64,
+    pub to: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    pub query: String,
+    #[serde(rename = "type")]
+    pub symbol_type: Option<String>,
+    pub exchange: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[get("/config")]
+pub async fn config() -> Result<HttpResponse, ServerError> {
+    Ok(HttpResponse::Ok().json(TvConfig::default()))
+}
+
+#[get("/symbols")]
+pub async fn symbols(
+    info: web::Query<SymbolParams>,
+    context: web::Data<WebContext>,
+) -> Result<HttpResponse, ServerError> {
+    let market = find_symbol(&info.symbol, &context.markets).ok_or(ServerError::SymbolNotFound)?;
+    Ok(HttpResponse::Ok().json(TvSymbolInfo::from_market(market)))
+}
+
+#[get("/search")]
+pub async fn search(
+    info: web::Query<SearchParams>,
+    context: web::Data<WebContext>,
+) -> Result<HttpResponse, ServerError> {
+    let type_matches = info
+        .symbol_type
+        .as_ref()
+        .map_or(true, |t| t.is_empty() || t == TV_SYMBOL_TYPE);
+    let exchange_matches = info
+        .exchange
+        .as_ref()
+        .map_or(true, |e| e.is_empty() || e == TV_EXCHANGE);
+    if !type_matches || !exchange_matches {
+        return Ok(HttpResponse::Ok().json(Vec::<TvSearchResult>::new()));
+    }
+
+    let query = info.query.to_lowercase();
+    let limit = info.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    let results = context
+        .markets
+        .iter()
+        .filter(|m| m.name.to_lowercase().contains(&query))
+        .take(limit)
+        .map(TvSearchResult::from_market)
+        .collect::<Vec<TvSearchResult>>();
+
+    Ok(HttpResponse::Ok().json(results))
+}
+
+#[get("/history")]
+pub async fn history(
+    info: web::Query<HistoryParams>,
+    context: web::Data<WebContext>,
+) -> Result<HttpResponse, ServerError> {
+    let resolution =
+        ChartResolution::from_tradingview(&info.resolution).ok_or(ServerError::WrongResolution)?;
+    let market = find_symbol(&info.symbol, &context.markets).ok_or(ServerError::SymbolNotFound)?;
+    let response = fetch_tv_candles(&context, market, resolution, info.from, info.to).await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[get("/time")]
+pub async fn time() -> Result<HttpResponse, ServerError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| ServerError::InternalError)?;
+    Ok(HttpResponse::Ok().body(now.as_secs().to_string()))
+}
+
+/// TradingView may send either the bare ticker or one prefixed with the exchange, e.g. `OpenBook:SOL/USDC`
+fn find_symbol<'a>(symbol: &str, markets: &'a [MarketInfo]) -> Option<&'a MarketInfo> {
+    let exchange_prefix = format!("{}:", TV_EXCHANGE);
+    let symbol = symbol.strip_prefix(&exchange_prefix).unwrap_or(symbol);
+    markets
+        .iter()
+        .find(|m| m.name == symbol || m.address == symbol)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web::Data, App};
+    use deadpool_postgres::Runtime;
+    use openbook_candles::structs::{markets::test_market, orderbook::OrderBookCache};
+    use serde_json::Value;
+    use std::sync::Arc;
+    use tokio_postgres::NoTls;
+
+    fn market(name: &str, address: &str) -> MarketInfo {
+        MarketInfo {
+            address: address.to_string(),
+            ..test_market(name)
+        }
+    }
+
+    #[test]
+    fn finds_symbols_by_ticker_prefix_or_address() {
+        let markets = vec![
+            market("SOL/USDC", "8BnEgHoWFysVcuFFX7QztDmzuH8r5ZFvyP3sYwn1XTh6"),
+            market("BONK/SOL", "Hs97TCZeuYiJxooo3U73qEHXg3dKpRL4uYKYRryEK9CF"),
+        ];
+        let name = |s: &str| find_symbol(s, &markets).map(|m| m.name.as_str());
+
+        assert_eq!(name("SOL/USDC"), Some("SOL/USDC"));
+        assert_eq!(name("OpenBook:BONK/SOL"), Some("BONK/SOL"));
+        assert_eq!(
+            name("Hs97TCZeuYiJxooo3U73qEHXg3dKpRL4uYKYRryEK9CF"),
+            Some("BONK/SOL")
+        );
+        // Names are matched exactly, and only our own exchange prefix is stripped
+        assert_eq!(name("sol/usdc"), None);
+        assert_eq!(name("Binance:SOL/USDC"), None);
+        assert_eq!(name("OpenBook:"), None);
+    }
+
+    /// Unlike `/candles`, history takes TradingView's format, where `1M` is a month. Resolutions
+    /// are parsed before the symbol is looked up, so no market or database is needed.
+    #[actix_web::test]
+    async fn history_takes_tradingview_resolutions() {
+        let pool = deadpool_postgres::Config::new()
+            .create_pool(Some(Runtime::Tokio1), NoTls)
+            .unwrap();
+        let context = Data::new(WebContext {
+            rpc_url: String::new(),
+            orderbooks: Arc::new(OrderBookCache::new(&[])),
+            markets: vec![],
+            pool,
+            usd_reference_markets: vec![],
+        });
+        let app = test::init_service(App::new().app_data(context).service(service())).await;
+        for (resolution, errmsg) in [
+            ("1M", "Request symbol not found"),
+            ("60", "Request symbol not found"),
+            ("1D", "Request symbol not found"),
+            ("1H", "Wrong resolution"),
+            ("1MO", "Wrong resolution"),
+            ("4000000000M", "Wrong resolution"),
+            ("99999999D", "Wrong resolution"),
+        ] {
+            let request = test::TestRequest::get()
+                .uri(&format!(
+                    "/udf/history?symbol=UNKNOWN&from=0&to=1&resolution={}",
+                    resolution
+                ))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status().as_u16(), 400, "{}", resolution);
+            let body: Value = test::read_body_json(response).await;
+            assert_eq!(body["errmsg"], errmsg, "{}", resolution);
+        }
+    }
+}
@@ -1,7 +1,13 @@
 use chrono::{DateTime, NaiveDateTime, Utc};
 use tokio_postgres::Row;
 
-use super::resolution::Resolution;
+use crate::utils::{f64_max, f64_min};
+
+use super::{
+    markets::MarketInfo,
+    openbook::{calculate_fill_price_and_size, PgOpenBookFill},
+    resolution::{ChartResolution, Resolution},
+};
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Candle {
@@ -48,3 +54,90 @@ impl Candle {
         }
     }
 }
+
+/// Minutes of a market whose candles need rebuilding, from `start_time` up to `end_time`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CandleDirtyRange {
+    pub id: i64,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+}
+
+impl CandleDirtyRange {
+    pub fn from_row(row: Row) -> Self {
+        CandleDirtyRange {
+            id: row.get(0),
+            start_time: row.get(1),
+            end_time: row.get(2),
+        }
+    }
+}
+
+/// Combines candles of a stored resolution, in time order, into the larger buckets of
+/// `resolution`. A bucket is complete once its last constituent is complete and ends it.
+pub fn aggregate_candles(candles: &[Candle], resolution: ChartResolution) -> Vec<Candle> {
+    let mut combined: Vec<Candle> = vec![];
+    for candle in candles {
+        let start_time = resolution.bucket_start(candle.start_time);
+        match combined.last_mut() {
+            Some(c) if c.start_time == start_time => {
+                c.high = f64_max(c.high, candle.high);
+                c.low = f64_min(c.low, candle.low);
+                c.close = candle.close;
+                c.volume += candle.volume;
+                c.complete = candle.complete && candle.end_time == c.end_time;
+            }
+            _ => {
+                let end_time = resolution.next_bucket_start(start_time);
+                combined.push(Candle {
+                    market_name: candle.market_name.clone(),
+                    start_time,
+                    end_time,
+                    resolution: resolution.to_string(),
+                    complete: candle.complete && candle.end_time == end_time,
+                    ..candle.clone()
+                });
+            }
+        }
+    }
+    combined
+}
+
+/// Builds candles from fills in time order. Unlike stored candles, buckets without fills
+/// are left out, since there can be far too many of them at resolutions under a minute.
+pub fn candles_from_fills(
+    fills: &[PgOpenBookFill],
+    market: &MarketInfo,
+    resolution: ChartResolution,
+) -> Vec<Candle> {
+    let mut candles: Vec<Candle> = vec![];
+    for fill in fills {
+        let (price, volume) =
+            calculate_fill_price_and_size(*fill, market.base_decimals, market.quote_decimals);
+        let start_time = resolution.bucket_start(fill.time);
+        match candles.last_mut() {
+            Some(c) if c.start_time == start_time => {
+                c.high = f64_max(c.high, price);
+                c.low = f64_min(c.low, price);
+                c.close = price;
+                c.volume += volume;
+            }
+            _ => {
+                let end_time = resolution.next_bucket_start(start_time);
+                candles.push(Candle {
+                    market_name: market.name.clone(),
+                    start_time,
+                    end_time,
+                    resolution: resolution.to_string(),
+                    open: price,
+                    close: price,
+                    high: price,
+                    low: price,
+                    volume,
+                    complete: end_time <= Utc::now(),
+                });
+            }
+        }
+    }
+    candles
+}
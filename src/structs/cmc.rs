@@ -0,0 +1,117 @@
+use serde::Serialize;
+
+use rust_decimal::Decimal;
+
+use super::{
+    markets::MarketInfo,
+    openbook::{calculate_fill_price_and_size, fill_native_amounts, readable_amount},
+    trades::PgTrade,
+};
+
+/// CoinMarketCap identifies markets as `BASE_QUOTE`
+pub fn cmc_market_pair(market: &MarketInfo) -> String {
+    market.name.replace('/', "_")
+}
+
+/// The base and quote symbols of a market, taken from its name
+pub fn market_symbols(market: &MarketInfo) -> (String, String) {
+    match market.name.split_once('/') {
+        Some((base, quote)) => (base.to_string(), quote.to_string()),
+        None => (market.base_mint_key.clone(), market.quote_mint_key.clone()),
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CmcSummary {
+    pub trading_pairs: String,
+    pub base_currency: String,
+    pub quote_currency: String,
+    pub last_price: f64,
+    /// None if that side of the book is empty, or the book hasn't loaded yet
+    pub lowest_ask: Option<f64>,
+    pub highest_bid: Option<f64>,
+    pub base_volume: f64,
+    pub quote_volume: f64,
+    pub highest_price_24h: f64,
+    pub lowest_price_24h: f64,
+    /// None if the market has no trades from a day ago
+    pub price_change_percent_24h: Option<f64>,
+}
+
+/// The change from the last price a day ago to `last_price`, in percent
+pub fn price_change_percent(last_price: f64, price_24h_ago: Option<f64>) -> Option<f64> {
+    match price_24h_ago {
+        Some(p) if p > 0.0 => Some((last_price - p) / p * 100.0),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CmcAsset {
+    pub name: String,
+    pub symbol: String,
+    pub contract_address: String,
+    pub decimals: u8,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CmcTicker {
+    pub base_id: String,
+    pub quote_id: String,
+    pub last_price: f64,
+    pub base_volume: f64,
+    pub quote_volume: f64,
+    #[serde(rename = "isFrozen")]
+    pub is_frozen: u8,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CmcOrderBook {
+    pub timestamp: i64, //as milliseconds
+    pub bids: Vec<(String, String)>,
+    pub asks: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CmcTrade {
+    /// `{signature}-{log_index}` of the taker fill
+    pub trade_id: String,
+    pub price: f64,
+    pub base_volume: f64,
+    pub quote_volume: f64,
+    pub timestamp: i64, //as milliseconds
+    #[serde(rename = "type")]
+    pub trade_type: String,
+}
+impl CmcTrade {
+    pub fn from_pg_trade(trade: &PgTrade, market: &MarketInfo) -> Self {
+        let (price, size) =
+            calculate_fill_price_and_size(trade.fill, market.base_decimals, market.quote_decimals);
+        let (quote_before_fees, _) = fill_native_amounts(&trade.fill);
+        CmcTrade {
+            trade_id: format!("{}-{}", trade.signature, trade.log_index),
+            price,
+            base_volume: size,
+            quote_volume: readable_amount(
+                Decimal::from_i128_with_scale(quote_before_fees as i128, 0),
+                market.quote_decimals,
+            ),
+            timestamp: trade.fill.time.timestamp_millis(),
+            trade_type: if trade.fill.bid { "buy" } else { "sell" }.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn price_change_from_a_day_ago() {
+        assert_eq!(price_change_percent(22.0, Some(20.0)), Some(10.0));
+        assert_eq!(price_change_percent(15.0, Some(20.0)), Some(-25.0));
+        assert_eq!(price_change_percent(20.0, Some(20.0)), Some(0.0));
+        assert_eq!(price_change_percent(20.0, None), None);
+        assert_eq!(price_change_percent(20.0, Some(0.0)), None);
+    }
+}
@@ -1,7 +1,12 @@
+use rust_decimal::Decimal;
 use serde::Serialize;
 use tokio_postgres::Row;
 
-use super::{markets::MarketInfo, openbook::token_factor};
+use super::{
+    markets::MarketInfo,
+    openbook::{calculate_fill_price_and_size, fill_native_amounts, readable_amount},
+    trades::PgTrade,
+};
 
 #[derive(Debug, Clone, Serialize)]
 pub struct CoinGeckoOrderBook {
@@ -9,6 +14,9 @@ pub struct CoinGeckoOrderBook {
     pub timestamp: String, //as milliseconds
     pub bids: Vec<(String, String)>,
     pub asks: Vec<(String, String)>,
+    /// Slot and age of the cached order book the levels were built from
+    pub slot: u64,
+    pub age_ms: i64,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -27,22 +35,65 @@ pub struct CoinGeckoTicker {
     pub last_price: String,
     pub base_volume: String,
     pub target_volume: String,
-    // pub bid: String,
-    // pub ask: String,
+    /// None if that side of the book is empty, or the book hasn't loaded yet
+    pub bid: Option<String>,
+    pub ask: Option<String>,
+    pub spread: Option<String>,
     pub high: String,
     pub low: String,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct CoinGeckoTrade {
+    /// `{signature}-{log_index}` of the taker fill
+    pub trade_id: String,
+    pub price: String,
+    pub base_volume: String,
+    pub target_volume: String,
+    pub trade_timestamp: String, //as milliseconds
+    #[serde(rename = "type")]
+    pub trade_type: String,
+}
+impl CoinGeckoTrade {
+    pub fn from_pg_trade(trade: &PgTrade, market: &MarketInfo) -> Self {
+        let (price, size) =
+            calculate_fill_price_and_size(trade.fill, market.base_decimals, market.quote_decimals);
+        // From the native quote amount rather than price * size, which rounds twice
+        let (quote_before_fees, _) = fill_native_amounts(&trade.fill);
+        let target_volume = readable_amount(
+            Decimal::from_i128_with_scale(quote_before_fees as i128, 0),
+            market.quote_decimals,
+        );
+        CoinGeckoTrade {
+            trade_id: format!("{}-{}", trade.signature, trade.log_index),
+            price: price.to_string(),
+            base_volume: size.to_string(),
+            target_volume: target_volume.to_string(),
+            trade_timestamp: trade.fill.time.timestamp_millis().to_string(),
+            trade_type: if trade.fill.bid { "buy" } else { "sell" }.to_string(),
+        }
+    }
+}
+
+/// Only the requested type is included if the request filters by type
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CoinGeckoHistoricalTrades {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buy: Option<Vec<CoinGeckoTrade>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sell: Option<Vec<CoinGeckoTrade>>,
+}
+
 pub struct PgCoinGecko24HourVolume {
     pub address: String,
-    pub raw_base_size: f64,
-    pub raw_quote_size: f64,
+    pub raw_base_size: Decimal,
+    pub raw_quote_size: Decimal,
 }
 impl PgCoinGecko24HourVolume {
     pub fn convert_to_readable(&self, markets: &Vec<MarketInfo>) -> CoinGecko24HourVolume {
         let market = markets.iter().find(|m| m.address == self.address).unwrap();
-        let base_volume = self.raw_base_size / token_factor(market.base_decimals);
-        let target_volume = self.raw_quote_size / token_factor(market.quote_decimals);
+        let base_volume = readable_amount(self.raw_base_size, market.base_decimals);
+        let target_volume = readable_amount(self.raw_quote_size, market.quote_decimals);
         CoinGecko24HourVolume {
             market_name: market.name.clone(),
             base_volume,
@@ -84,3 +135,38 @@ impl PgCoinGecko24HighLow {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{markets::test_market, openbook::PgOpenBookFill};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn target_volume_is_the_native_quote_amount() {
+        // A taker sold 0.1 SOL for 0.3 USDC, where 3.0 * 0.1 is 0.30000000000000004
+        let trade = PgTrade {
+            fill: PgOpenBookFill {
+                time: Utc.timestamp_opt(1_680_000_000, 0).unwrap(),
+                bid: false,
+                maker: false,
+                native_qty_paid: 100_000_000,
+                native_qty_received: 299_000,
+                native_fee_or_rebate: 1_000,
+                client_order_id: None,
+                referrer_rebate: None,
+                owner_slot: None,
+                slot: None,
+            },
+            signature: "sig".to_string(),
+            log_index: 3,
+        };
+        let cg_trade = CoinGeckoTrade::from_pg_trade(&trade, &test_market("SOL/USDC"));
+        assert_eq!(cg_trade.trade_id, "sig-3");
+        assert_eq!(cg_trade.price, "3");
+        assert_eq!(cg_trade.base_volume, "0.1");
+        assert_eq!(cg_trade.target_volume, "0.3");
+        assert_eq!(cg_trade.trade_timestamp, "1680000000000");
+        assert_eq!(cg_trade.trade_type, "sell");
+    }
+}
@@ -1,14 +1,45 @@
-use anchor_lang::AnchorDeserialize;
 use serde::{Deserialize, Serialize};
 use solana_account_decoder::UiAccountEncoding;
 use solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcAccountInfoConfig};
 use solana_sdk::{commitment_config::CommitmentConfig, program_pack::Pack, pubkey::Pubkey};
 use spl_token::state::Mint;
-use std::{collections::HashMap, fs::File, str::FromStr};
+use std::{collections::HashMap, convert::TryFrom, fs::File, str::FromStr};
 
-use crate::utils::Config;
+use crate::utils::{Config, OPENBOOK_KEY, OPENBOOK_V2_KEY};
 
-use super::openbook::MarketState;
+use super::openbook::{MarketState, MarketStateV2};
+
+/// The OpenBook version a market was created with, `1` or `2` in the markets file and API
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum MarketVersion {
+    /// OpenBook and its Serum-based forks
+    #[default]
+    V1,
+    /// The Anchor rewrite, with its own market, event and order book layouts
+    V2,
+}
+
+impl From<MarketVersion> for u8 {
+    fn from(version: MarketVersion) -> Self {
+        match version {
+            MarketVersion::V1 => 1,
+            MarketVersion::V2 => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for MarketVersion {
+    type Error = String;
+
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(MarketVersion::V1),
+            2 => Ok(MarketVersion::V2),
+            v => Err(format!("unknown market version {}", v)),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize)]
 pub struct MarketInfo {
@@ -22,12 +53,33 @@ pub struct MarketInfo {
     pub asks_key: String,
     pub base_lot_size: u64,
     pub quote_lot_size: u64,
+    pub program_id: String,
+    pub version: MarketVersion,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct MarketConfig {
     pub name: String,
     pub address: String,
+    /// The program that owns the market, the OpenBook program of its version unless set.
+    /// Forks must share its layout
+    #[serde(default)]
+    pub program_id: Option<String>,
+    #[serde(default)]
+    pub version: MarketVersion,
+}
+
+impl MarketConfig {
+    pub fn program_id(&self) -> anyhow::Result<Pubkey> {
+        match &self.program_id {
+            Some(p) => Pubkey::from_str(p)
+                .map_err(|e| anyhow::anyhow!("invalid program_id for {}: {}", self.name, e)),
+            None => match self.version {
+                MarketVersion::V1 => Ok(OPENBOOK_KEY),
+                MarketVersion::V2 => Ok(OPENBOOK_V2_KEY),
+            },
+        }
+    }
 }
 
 pub fn load_markets(path: &str) -> Vec<MarketConfig> {
@@ -66,42 +118,21 @@ pub async fn fetch_market_infos(
 
     let mut market_infos = market_results
         .iter_mut()
-        .map(|r| {
-            let get_account_result = r.as_mut().unwrap();
-
-            let mut market_bytes: &[u8] = &mut get_account_result.data[5..];
-            let raw_market: MarketState =
-                AnchorDeserialize::deserialize(&mut market_bytes).unwrap();
-
-            let market_address_string = serum_bytes_to_pubkey(raw_market.own_address).to_string();
-            let bids_key = serum_bytes_to_pubkey(raw_market.bids);
-            let asks_key = serum_bytes_to_pubkey(raw_market.asks);
-            let base_mint_key = serum_bytes_to_pubkey(raw_market.coin_mint);
-            let quote_mint_key = serum_bytes_to_pubkey(raw_market.pc_mint);
-            mint_key_map.insert(base_mint_key, 0);
-            mint_key_map.insert(quote_mint_key, 0);
-
-            let market_name = markets
-                .iter()
-                .find(|x| x.address == market_address_string)
-                .unwrap()
-                .name
-                .clone();
+        .zip(markets.iter())
+        .map(|(r, market)| {
+            let get_account_result = r
+                .as_mut()
+                .ok_or_else(|| anyhow::anyhow!("market account {} not found", market.address))?;
+            let program_id = check_market_owner(market, &get_account_result.owner)?;
 
-            MarketInfo {
-                name: market_name,
-                address: market_address_string,
-                base_decimals: 0,
-                quote_decimals: 0,
-                base_mint_key: base_mint_key.to_string(),
-                quote_mint_key: quote_mint_key.to_string(),
-                bids_key: bids_key.to_string(),
-                asks_key: asks_key.to_string(),
-                base_lot_size: raw_market.coin_lot_size,
-                quote_lot_size: raw_market.pc_lot_size,
-            }
+            let market_info =
+                market_info_from_account(market, program_id, &get_account_result.data)?;
+            mint_key_map.insert(Pubkey::from_str(&market_info.base_mint_key)?, 0);
+            mint_key_map.insert(Pubkey::from_str(&market_info.quote_mint_key)?, 0);
+
+            Ok(market_info)
         })
-        .collect::<Vec<MarketInfo>>();
+        .collect::<anyhow::Result<Vec<MarketInfo>>>()?;
 
     let mint_keys = mint_key_map.keys().cloned().collect::<Vec<Pubkey>>();
 
@@ -127,10 +158,232 @@ pub async fn fetch_market_infos(
     Ok(market_infos)
 }
 
-fn serum_bytes_to_pubkey(data: [u64; 4]) -> Pubkey {
+/// Reads a market account. Decimals are left at 0 for v1 markets, they are read from the mints.
+fn market_info_from_account(
+    market: &MarketConfig,
+    program_id: Pubkey,
+    data: &[u8],
+) -> anyhow::Result<MarketInfo> {
+    let market_info = match market.version {
+        MarketVersion::V1 => {
+            let raw_market = MarketState::load(data)
+                .map_err(|e| anyhow::anyhow!("market {}: {}", market.name, e))?;
+            MarketInfo {
+                name: market.name.clone(),
+                address: serum_bytes_to_pubkey(raw_market.own_address).to_string(),
+                base_decimals: 0,
+                quote_decimals: 0,
+                base_mint_key: serum_bytes_to_pubkey(raw_market.coin_mint).to_string(),
+                quote_mint_key: serum_bytes_to_pubkey(raw_market.pc_mint).to_string(),
+                bids_key: serum_bytes_to_pubkey(raw_market.bids).to_string(),
+                asks_key: serum_bytes_to_pubkey(raw_market.asks).to_string(),
+                base_lot_size: raw_market.coin_lot_size,
+                quote_lot_size: raw_market.pc_lot_size,
+                program_id: program_id.to_string(),
+                version: MarketVersion::V1,
+            }
+        }
+        MarketVersion::V2 => {
+            let raw_market = MarketStateV2::load(data)
+                .map_err(|e| anyhow::anyhow!("market {}: {}", market.name, e))?;
+            MarketInfo {
+                name: market.name.clone(),
+                address: market.address.clone(),
+                base_decimals: raw_market.base_decimals,
+                quote_decimals: raw_market.quote_decimals,
+                base_mint_key: raw_market.base_mint.to_string(),
+                quote_mint_key: raw_market.quote_mint.to_string(),
+                bids_key: raw_market.bids.to_string(),
+                asks_key: raw_market.asks.to_string(),
+                base_lot_size: raw_market.base_lot_size as u64,
+                quote_lot_size: raw_market.quote_lot_size as u64,
+                program_id: program_id.to_string(),
+                version: MarketVersion::V2,
+            }
+        }
+    };
+    Ok(market_info)
+}
+
+/// The market's program, as long as it owns the market account
+fn check_market_owner(market: &MarketConfig, owner: &Pubkey) -> anyhow::Result<Pubkey> {
+    let program_id = market.program_id()?;
+    if *owner != program_id {
+        anyhow::bail!(
+            "market {} is owned by {}, not {}. Set its program_id in the markets file",
+            market.name,
+            owner,
+            program_id
+        );
+    }
+    Ok(program_id)
+}
+
+/// The distinct programs that own the markets
+pub fn market_programs(markets: &[MarketInfo]) -> Vec<Pubkey> {
+    let mut programs: Vec<Pubkey> = vec![];
+    for market in markets {
+        let program_id = Pubkey::from_str(&market.program_id).unwrap();
+        if !programs.contains(&program_id) {
+            programs.push(program_id);
+        }
+    }
+    programs
+}
+
+/// A v1 market with 9 and 6 decimals and lot sizes of 1, for tests that only need its name.
+/// Public so the binaries' tests can use it too.
+pub fn test_market(name: &str) -> MarketInfo {
+    MarketInfo {
+        name: name.to_string(),
+        address: String::new(),
+        base_decimals: 9,
+        quote_decimals: 6,
+        base_mint_key: String::new(),
+        quote_mint_key: String::new(),
+        bids_key: String::new(),
+        asks_key: String::new(),
+        base_lot_size: 1,
+        quote_lot_size: 1,
+        program_id: String::new(),
+        version: MarketVersion::V1,
+    }
+}
+
+pub fn serum_bytes_to_pubkey(data: [u64; 4]) -> Pubkey {
     let mut res = [0; 32];
     for i in 0..4 {
         res[8 * i..][..8].copy_from_slice(&data[i].to_le_bytes());
     }
     Pubkey::new_from_array(res)
 }
+
+pub fn pubkey_to_serum_bytes(pubkey: &Pubkey) -> [u64; 4] {
+    let bytes = pubkey.to_bytes();
+    let mut res = [0; 4];
+    for (word, chunk) in res.iter_mut().zip(bytes.chunks_exact(8)) {
+        *word = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(program_id: Option<&str>, version: MarketVersion) -> MarketConfig {
+        MarketConfig {
+            name: "SOL/USDC".to_string(),
+            address: Pubkey::new_unique().to_string(),
+            program_id: program_id.map(|p| p.to_string()),
+            version,
+        }
+    }
+
+    #[test]
+    fn program_ids_default_to_the_openbook_version() {
+        assert_eq!(
+            config(None, MarketVersion::V1).program_id().unwrap(),
+            OPENBOOK_KEY
+        );
+        assert_eq!(
+            config(None, MarketVersion::V2).program_id().unwrap(),
+            OPENBOOK_V2_KEY
+        );
+
+        let fork = Pubkey::new_unique();
+        let forked = config(Some(&fork.to_string()), MarketVersion::V1);
+        assert_eq!(forked.program_id().unwrap(), fork);
+
+        let err = config(Some("not a key"), MarketVersion::V1)
+            .program_id()
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid program_id for SOL/USDC"));
+    }
+
+    #[test]
+    fn program_ids_are_read_from_the_markets_file() {
+        let fork = Pubkey::new_unique();
+        let markets: Vec<MarketConfig> = serde_json::from_str(&format!(
+            r#"[
+                {{"name": "SOL/USDC", "address": "{}"}},
+                {{"name": "SOL/USDT", "address": "{}", "version": 2}},
+                {{"name": "FORK/USDC", "address": "{}", "program_id": "{}"}}
+            ]"#,
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            fork
+        ))
+        .unwrap();
+        let program_ids = markets
+            .iter()
+            .map(|m| m.program_id().unwrap())
+            .collect::<Vec<Pubkey>>();
+        assert_eq!(program_ids, vec![OPENBOOK_KEY, OPENBOOK_V2_KEY, fork]);
+    }
+
+    #[test]
+    fn markets_must_be_owned_by_their_program() {
+        let market = config(None, MarketVersion::V1);
+        assert_eq!(
+            check_market_owner(&market, &OPENBOOK_KEY).unwrap(),
+            OPENBOOK_KEY
+        );
+
+        let err = check_market_owner(&market, &OPENBOOK_V2_KEY).unwrap_err();
+        assert!(err.to_string().contains(&format!(
+            "market SOL/USDC is owned by {}, not {}",
+            OPENBOOK_V2_KEY, OPENBOOK_KEY
+        )));
+
+        let fork = Pubkey::new_unique();
+        let forked = config(Some(&fork.to_string()), MarketVersion::V1);
+        assert_eq!(check_market_owner(&forked, &fork).unwrap(), fork);
+        assert!(check_market_owner(&forked, &OPENBOOK_KEY).is_err());
+    }
+
+    #[test]
+    fn market_programs_are_distinct_in_order() {
+        let fork = Pubkey::new_unique();
+        let markets = [OPENBOOK_KEY, fork, OPENBOOK_KEY, OPENBOOK_V2_KEY, fork]
+            .iter()
+            .map(|program_id| {
+                let mut market = test_market("SOL/USDC");
+                market.program_id = program_id.to_string();
+                market
+            })
+            .collect::<Vec<MarketInfo>>();
+        assert_eq!(
+            market_programs(&markets),
+            vec![OPENBOOK_KEY, fork, OPENBOOK_V2_KEY]
+        );
+        assert!(market_programs(&[]).is_empty());
+    }
+
+    #[test]
+    fn malformed_market_accounts_are_errors() {
+        let v1 = config(None, MarketVersion::V1);
+        let mut data = b"serum".to_vec();
+        data.extend(vec![0; std::mem::size_of::<MarketState>() + 7]);
+        let own_address = Pubkey::new_unique();
+        data[13..45].copy_from_slice(own_address.as_ref());
+        let market = market_info_from_account(&v1, OPENBOOK_KEY, &data).unwrap();
+        assert_eq!(market.address, own_address.to_string());
+
+        let short = market_info_from_account(&v1, OPENBOOK_KEY, &data[..100]).unwrap_err();
+        assert_eq!(
+            short.to_string(),
+            "market SOL/USDC: not an OpenBook v1 market account"
+        );
+        data[..5].copy_from_slice(b"other");
+        assert!(market_info_from_account(&v1, OPENBOOK_KEY, &data).is_err());
+
+        let v2 = config(None, MarketVersion::V2);
+        let err = market_info_from_account(&v2, OPENBOOK_V2_KEY, &data[..100]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "market SOL/USDC: not an OpenBook v2 market account"
+        );
+    }
+}
@@ -1,9 +1,13 @@
 pub mod candle;
+pub mod cmc;
 pub mod coingecko;
 pub mod markets;
 pub mod openbook;
+pub mod order_tree;
+pub mod orderbook;
 pub mod resolution;
 pub mod slab;
 pub mod trader;
+pub mod trades;
 pub mod tradingview;
 pub mod transaction;
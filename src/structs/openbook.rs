@@ -1,9 +1,14 @@
-use anchor_lang::{event, AnchorDeserialize, AnchorSerialize};
+use anchor_lang::{event, solana_program::hash::hash, AnchorDeserialize, AnchorSerialize};
+use bytemuck::{Pod, Zeroable};
 use chrono::{DateTime, Utc};
-use num_traits::Pow;
+use num_traits::{Pow, ToPrimitive};
+use rust_decimal::Decimal;
 use solana_sdk::pubkey::Pubkey;
+use std::mem::size_of;
 use tokio_postgres::Row;
 
+use super::markets::MarketInfo;
+
 #[event]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct OpenBookFillEventRaw {
@@ -25,19 +30,21 @@ impl OpenBookFillEventRaw {
     pub fn into_event(
         self,
         signature: String,
+        slot: u64,
         block_time: i64,
         log_index: usize,
     ) -> OpenBookFillEvent {
         OpenBookFillEvent {
             signature,
+            slot,
             market: self.market,
             open_orders: self.open_orders,
-            open_orders_owner: self.open_orders_owner,
+            open_orders_owner: Some(self.open_orders_owner),
             bid: self.bid,
             maker: self.maker,
             native_qty_paid: self.native_qty_paid,
             native_qty_received: self.native_qty_received,
-            native_fee_or_rebate: self.native_fee_or_rebate,
+            native_fee_or_rebate: self.native_fee_or_rebate as i128,
             order_id: self.order_id,
             owner_slot: self.owner_slot,
             fee_tier: self.fee_tier,
@@ -49,18 +56,111 @@ impl OpenBookFillEventRaw {
     }
 }
 
+/// OpenBook v2's fill event, logged once per match with both sides of it
+#[event]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FillLog {
+    pub market: Pubkey,
+    /// 0 if the taker bought, 1 if it sold
+    pub taker_side: u8,
+    pub maker_slot: u8,
+    pub maker_out: bool,
+    pub timestamp: u64,
+    pub seq_num: u64,
+    /// The maker's open orders account
+    pub maker: Pubkey,
+    pub maker_client_order_id: u64,
+    pub maker_fee: u64,
+    pub maker_timestamp: u64,
+    /// The taker's open orders account
+    pub taker: Pubkey,
+    pub taker_client_order_id: u64,
+    pub taker_fee_ceil: u64,
+    /// Quote lots per base lot
+    pub price: i64,
+    /// Base lots
+    pub quantity: i64,
+}
+impl FillLog {
+    /// Splits the match into a maker and a taker fill, like the two events v1 logs for it.
+    /// Neither the owners of the open orders accounts nor the maker's order id are logged, so
+    /// the owners are left out and the order id is 0. Unlike v1, makers can pay a fee, which
+    /// is recorded as a negative rebate. None if the native amounts don't fit a u64 or a seller's
+    /// fee is more than the quote it received, which a valid match can't produce.
+    pub fn into_events(
+        self,
+        market: &MarketInfo,
+        signature: String,
+        slot: u64,
+        block_time: i64,
+        maker_log_index: usize,
+        taker_log_index: usize,
+    ) -> Option<[OpenBookFillEvent; 2]> {
+        let price = u128::try_from(self.price).ok()?;
+        let quantity = u128::try_from(self.quantity).ok()?;
+        let base = u64::try_from(quantity.checked_mul(market.base_lot_size as u128)?).ok()?;
+        let quote = u64::try_from(
+            price
+                .checked_mul(quantity)?
+                .checked_mul(market.quote_lot_size as u128)?,
+        )
+        .ok()?;
+        let taker_bid = self.taker_side == 0;
+        let fill = |bid: bool, maker: bool, fee: u64| {
+            let (native_qty_paid, native_qty_received) = match bid {
+                true => (quote.checked_add(fee)?, base),
+                false => (base, quote.checked_sub(fee)?),
+            };
+            Some(OpenBookFillEvent {
+                signature: signature.clone(),
+                slot,
+                market: self.market,
+                open_orders: if maker { self.maker } else { self.taker },
+                open_orders_owner: None,
+                bid,
+                maker,
+                native_qty_paid,
+                native_qty_received,
+                native_fee_or_rebate: if maker { -(fee as i128) } else { fee as i128 },
+                order_id: 0,
+                owner_slot: if maker { self.maker_slot } else { 0 },
+                fee_tier: 0,
+                client_order_id: Some(if maker {
+                    self.maker_client_order_id
+                } else {
+                    self.taker_client_order_id
+                }),
+                referrer_rebate: None,
+                block_time,
+                log_index: if maker {
+                    maker_log_index
+                } else {
+                    taker_log_index
+                },
+            })
+        };
+        Some([
+            fill(!taker_bid, true, self.maker_fee)?,
+            fill(taker_bid, false, self.taker_fee_ceil)?,
+        ])
+    }
+}
+
 #[event]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct OpenBookFillEvent {
     pub signature: String,
+    pub slot: u64,
     pub market: Pubkey,
     pub open_orders: Pubkey,
-    pub open_orders_owner: Pubkey,
+    /// None for v2 fills, whose logs don't say who owns the open orders account
+    pub open_orders_owner: Option<Pubkey>,
     pub bid: bool,
     pub maker: bool,
     pub native_qty_paid: u64,
     pub native_qty_received: u64,
-    pub native_fee_or_rebate: u64,
+    /// A fee paid as taker, or a rebate earned as maker. Negative for a fee paid as a v2 maker.
+    pub native_fee_or_rebate: i128,
     pub order_id: u128,
     pub owner_slot: u8,
     pub fee_tier: u8,
@@ -75,23 +175,58 @@ pub struct PgOpenBookFill {
     pub time: DateTime<Utc>,
     pub bid: bool,
     pub maker: bool,
-    pub native_qty_paid: f64,
-    pub native_qty_received: f64,
-    pub native_fee_or_rebate: f64,
+    pub native_qty_paid: u64,
+    pub native_qty_received: u64,
+    pub native_fee_or_rebate: i128,
+    /// The columns below are None for fills stored before they were recorded
+    pub client_order_id: Option<u64>,
+    pub referrer_rebate: Option<u64>,
+    pub owner_slot: Option<u8>,
+    pub slot: Option<u64>,
 }
 impl PgOpenBookFill {
-    pub fn from_row(row: Row) -> Self {
-        PgOpenBookFill {
+    /// Errors if a stored amount is out of range for its type, rather than panicking the
+    /// request that read it
+    pub fn from_row(row: Row) -> anyhow::Result<Self> {
+        let native_fee_or_rebate: Decimal = row.get(5);
+        Ok(PgOpenBookFill {
             time: row.get(0),
             bid: row.get(1),
             maker: row.get(2),
-            native_qty_paid: row.get(3),
-            native_qty_received: row.get(4),
-            native_fee_or_rebate: row.get(5),
-        }
+            native_qty_paid: numeric_to_u64(row.get(3))?,
+            native_qty_received: numeric_to_u64(row.get(4))?,
+            native_fee_or_rebate: native_fee_or_rebate.to_i128().ok_or_else(|| {
+                anyhow::anyhow!("{} is not a valid fee or rebate", native_fee_or_rebate)
+            })?,
+            client_order_id: row
+                .get::<_, Option<Decimal>>("client_order_id")
+                .map(numeric_to_u64)
+                .transpose()?,
+            referrer_rebate: row
+                .get::<_, Option<Decimal>>("referrer_rebate")
+                .map(numeric_to_u64)
+                .transpose()?,
+            owner_slot: row.get::<_, Option<i16>>("owner_slot").map(|s| s as u8),
+            slot: row.get::<_, Option<i64>>("slot").map(|s| s as u64),
+        })
     }
 }
 
+/// Native amounts are stored as numeric(20, 0), which also holds negative values and values
+/// up to 10^20 - 1, so not every stored amount fits a u64
+pub fn numeric_to_u64(value: Decimal) -> anyhow::Result<u64> {
+    value
+        .to_u64()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a valid native amount", value))
+}
+
+/// Divides a sum of native amounts by the token's decimals
+pub fn readable_amount(native: Decimal, decimals: u8) -> f64 {
+    (native / Decimal::from(10u64.pow(decimals as u32)))
+        .to_f64()
+        .unwrap()
+}
+
 #[derive(Copy, Clone, AnchorDeserialize)]
 #[cfg_attr(target_endian = "little", derive(Debug))]
 #[repr(packed)]
@@ -147,34 +282,299 @@ pub struct MarketState {
     pub referrer_rebates_accrued: u64,
 }
 
+impl MarketState {
+    /// v1 market accounts start with a 5 byte `serum` header before the state
+    pub fn load(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < 5 + size_of::<Self>() || &data[..5] != b"serum" {
+            anyhow::bail!("not an OpenBook v1 market account");
+        }
+        let mut market_bytes = &data[5..];
+        Ok(AnchorDeserialize::deserialize(&mut market_bytes)?)
+    }
+}
+
+/// The first 8 bytes of an Anchor account, identifying its type
+pub fn anchor_account_discriminator(name: &str) -> [u8; 8] {
+    let mut discriminator = [0; 8];
+    discriminator.copy_from_slice(&hash(format!("account:{}", name).as_bytes()).to_bytes()[..8]);
+    discriminator
+}
+
+/// The start of an OpenBook v2 `Market` account, after its discriminator, up to the mints
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct MarketStateV2 {
+    pub bump: u8,
+    pub base_decimals: u8,
+    pub quote_decimals: u8,
+    _padding: [u8; 5],
+    pub market_authority: Pubkey,
+    pub time_expiry: i64,
+    pub collect_fee_admin: Pubkey,
+    pub open_orders_admin: Pubkey,
+    pub consume_events_admin: Pubkey,
+    pub close_market_admin: Pubkey,
+    pub name: [u8; 16],
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub event_heap: Pubkey,
+    pub oracle_a: Pubkey,
+    pub oracle_b: Pubkey,
+    _oracle_config: [u8; 88],
+    _stable_price_model: [u8; 288],
+    pub quote_lot_size: i64,
+    pub base_lot_size: i64,
+    pub seq_num: u64,
+    pub registration_time: i64,
+    pub maker_fee: i64,
+    pub taker_fee: i64,
+    pub fees_accrued: u128,
+    pub fees_to_referrers: u128,
+    pub referrer_rebates_accrued: u64,
+    pub fees_available: u64,
+    pub maker_volume: u128,
+    pub taker_volume_wo_oo: u128,
+    pub base_mint: Pubkey,
+    pub quote_mint: Pubkey,
+}
+unsafe impl Zeroable for MarketStateV2 {}
+unsafe impl Pod for MarketStateV2 {}
+
+impl MarketStateV2 {
+    pub fn load(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < 8 + size_of::<Self>() || data[..8] != anchor_account_discriminator("Market")
+        {
+            anyhow::bail!("not an OpenBook v2 market account");
+        }
+        Ok(*bytemuck::from_bytes(&data[8..8 + size_of::<Self>()]))
+    }
+}
+
+/// The native amounts are combined with integer math, so the only rounding is the final division
 pub fn calculate_fill_price_and_size(
     fill: PgOpenBookFill,
     base_decimals: u8,
     quote_decimals: u8,
 ) -> (f64, f64) {
+    let (quote_before_fees, base) = fill_native_amounts(&fill);
+    let price = (quote_before_fees * 10u128.pow(base_decimals as u32)) as f64
+        / (base * 10u128.pow(quote_decimals as u32)) as f64;
+    let size = base as f64 / token_factor(base_decimals);
+    (price, size)
+}
+
+/// The native quote amount a fill traded at before fees, and its native base amount. Makers'
+/// rebates are negative when they paid a fee.
+pub fn fill_native_amounts(fill: &PgOpenBookFill) -> (u128, u128) {
+    let paid = fill.native_qty_paid as i128;
+    let received = fill.native_qty_received as i128;
+    let fee_or_rebate = fill.native_fee_or_rebate;
     if fill.bid {
-        let price_before_fees = if fill.maker {
-            fill.native_qty_paid + fill.native_fee_or_rebate
+        let quote_before_fees = if fill.maker {
+            paid + fee_or_rebate
         } else {
-            fill.native_qty_paid - fill.native_fee_or_rebate
+            paid - fee_or_rebate
         };
-        let price = (price_before_fees * token_factor(base_decimals))
-            / (token_factor(quote_decimals) * fill.native_qty_received);
-        let size = fill.native_qty_received / token_factor(base_decimals);
-        (price, size)
+        (quote_before_fees.max(0) as u128, received as u128)
     } else {
-        let price_before_fees = if fill.maker {
-            fill.native_qty_received - fill.native_fee_or_rebate
+        let quote_before_fees = if fill.maker {
+            received - fee_or_rebate
         } else {
-            fill.native_qty_received + fill.native_fee_or_rebate
+            received + fee_or_rebate
         };
-        let price = (price_before_fees * token_factor(base_decimals))
-            / (token_factor(quote_decimals) * fill.native_qty_paid);
-        let size = fill.native_qty_paid / token_factor(base_decimals);
-        (price, size)
+        (quote_before_fees.max(0) as u128, paid as u128)
     }
 }
 
 pub fn token_factor(decimals: u8) -> f64 {
     10f64.pow(decimals as f64)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::markets::test_market;
+    use std::str::FromStr;
+
+    fn fill_log(market: Pubkey, taker_side: u8) -> FillLog {
+        FillLog {
+            market,
+            taker_side,
+            maker_slot: 3,
+            maker_out: false,
+            timestamp: 1_680_000_000,
+            seq_num: 1,
+            maker: Pubkey::new_unique(),
+            maker_client_order_id: 11,
+            maker_fee: 30,
+            maker_timestamp: 1_679_999_000,
+            taker: Pubkey::new_unique(),
+            taker_client_order_id: 12,
+            taker_fee_ceil: 40,
+            price: 2_000,
+            quantity: 5,
+        }
+    }
+
+    fn pg_fill(event: &OpenBookFillEvent) -> PgOpenBookFill {
+        PgOpenBookFill {
+            time: Utc::now(),
+            bid: event.bid,
+            maker: event.maker,
+            native_qty_paid: event.native_qty_paid,
+            native_qty_received: event.native_qty_received,
+            native_fee_or_rebate: event.native_fee_or_rebate,
+            client_order_id: event.client_order_id,
+            referrer_rebate: event.referrer_rebate,
+            owner_slot: Some(event.owner_slot),
+            slot: Some(event.slot),
+        }
+    }
+
+    #[test]
+    fn v2_fills_split_into_maker_and_taker() {
+        let mut market = test_market("SOL/USDC");
+        market.base_lot_size = 100;
+        market.quote_lot_size = 10;
+        for taker_side in [0, 1] {
+            let log = fill_log(Pubkey::new_unique(), taker_side);
+            let [maker, taker] = log
+                .clone()
+                .into_events(&market, "sig".to_string(), 100, 1_680_000_000, 2, 9)
+                .unwrap();
+            assert_eq!(
+                (maker.maker, maker.open_orders, maker.log_index),
+                (true, log.maker, 2)
+            );
+            assert_eq!(
+                (taker.maker, taker.open_orders, taker.log_index),
+                (false, log.taker, 9)
+            );
+            assert_eq!(taker.bid, taker_side == 0);
+            assert_eq!(maker.bid, taker_side == 1);
+            assert_eq!(taker.native_fee_or_rebate, 40);
+            assert_eq!(maker.native_fee_or_rebate, -30);
+            // Both sides paid their fee on top of the 100,000 quote traded
+            let quote_paid = |event: &OpenBookFillEvent| match event.bid {
+                true => event.native_qty_paid as i128,
+                false => -(event.native_qty_received as i128),
+            };
+            assert_eq!(
+                quote_paid(&taker),
+                if taker.bid { 100_040 } else { -99_960 }
+            );
+            assert_eq!(
+                quote_paid(&maker),
+                if maker.bid { 100_030 } else { -99_970 }
+            );
+            assert_eq!(maker.client_order_id, Some(11));
+            // Owners aren't logged, so neither side is attributed to anyone
+            assert_eq!(
+                (maker.open_orders_owner, taker.open_orders_owner),
+                (None, None)
+            );
+
+            // 2000 quote lots of 10 per base lot of 100, with 9 and 6 decimals
+            for event in [&maker, &taker] {
+                let (price, size) = calculate_fill_price_and_size(pg_fill(event), 9, 6);
+                assert_eq!(price, 200_000.0);
+                assert_eq!(size, 0.0000005);
+            }
+        }
+    }
+
+    #[test]
+    fn v2_fills_that_overflow_are_dropped() {
+        let mut market = test_market("SOL/USDC");
+        market.base_lot_size = 100;
+        market.quote_lot_size = 10;
+        let into_events =
+            |log: FillLog| log.into_events(&market, "sig".to_string(), 100, 1_680_000_000, 2, 9);
+        let log = fill_log(Pubkey::new_unique(), 0);
+
+        // price * quantity * quote lot size is more than a u64 holds, though each fits
+        let overflowing = FillLog {
+            price: i64::MAX,
+            quantity: 4,
+            ..log.clone()
+        };
+        assert!(into_events(overflowing).is_none());
+        let negative = FillLog {
+            quantity: -5,
+            ..log.clone()
+        };
+        assert!(into_events(negative).is_none());
+        // The taker's fee is added to what it paid
+        let fee_overflows = FillLog {
+            price: (u64::MAX / 50) as i64,
+            quantity: 5,
+            taker_fee_ceil: u64::MAX,
+            ..log.clone()
+        };
+        assert!(into_events(fee_overflows).is_none());
+        // and subtracted from what a seller received, which is 100,000 here
+        let taker_fee_underflows = FillLog {
+            taker_side: 1,
+            taker_fee_ceil: 100_001,
+            ..log.clone()
+        };
+        assert!(into_events(taker_fee_underflows).is_none());
+        let maker_fee_underflows = FillLog {
+            maker_fee: 100_001,
+            ..log.clone()
+        };
+        assert!(into_events(maker_fee_underflows).is_none());
+        assert!(into_events(FillLog {
+            taker_side: 1,
+            taker_fee_ceil: 100_000,
+            ..log.clone()
+        })
+        .is_some());
+        assert!(into_events(log).is_some());
+    }
+
+    #[test]
+    fn fill_price_uses_exact_native_amounts() {
+        // A BONK/USDC taker buy of 10^19 + 1 native BONK, which rounds to 10^19 as a double.
+        // The USDC paid is 2^53 + 1 including a fee of 1, which a double also can't hold.
+        let fill = PgOpenBookFill {
+            time: Utc::now(),
+            bid: true,
+            maker: false,
+            native_qty_paid: 9_007_199_254_740_993,
+            native_qty_received: 10_000_000_000_000_000_001,
+            native_fee_or_rebate: 1,
+            client_order_id: None,
+            referrer_rebate: None,
+            owner_slot: None,
+            slot: None,
+        };
+        assert_eq!(fill.native_qty_received as f64, 1e19);
+        assert_eq!(
+            fill_native_amounts(&fill),
+            (9_007_199_254_740_992, 10_000_000_000_000_000_001)
+        );
+        let (price, size) = calculate_fill_price_and_size(fill, 5, 6);
+        assert!((price - 0.00009007199254740992).abs() < 1e-18);
+        assert_eq!(size, 100_000_000_000_000.0);
+
+        // Amounts are stored and summed as numeric, so volumes stay exact
+        let stored = Decimal::from(fill.native_qty_received);
+        assert_eq!(numeric_to_u64(stored).unwrap(), 10_000_000_000_000_000_001);
+        // The column holds values a u64 can't
+        assert!(numeric_to_u64(Decimal::from_str("99999999999999999999").unwrap()).is_err());
+        assert!(numeric_to_u64(Decimal::from(-1)).is_err());
+        assert_eq!(
+            stored + Decimal::from(1u64),
+            Decimal::from_str("10000000000000000002").unwrap()
+        );
+        assert_eq!(
+            (fill.native_qty_received as f64 + 1.0) as u64,
+            10_000_000_000_000_000_000
+        );
+        assert_eq!(
+            readable_amount(stored + Decimal::from(999_999u64), 6),
+            10_000_000_000_001.0
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use bytemuck::{cast_ref, cast_slice, from_bytes, Pod, Zeroable};
+use solana_sdk::pubkey::Pubkey;
+use std::mem::size_of;
+
+use super::{
+    markets::pubkey_to_serum_bytes,
+    openbook::anchor_account_discriminator,
+    slab::{FeeTier, LeafNode},
+};
+
+// OpenBook v2 keeps each side of a book in a `BookSide` account, holding a critbit tree of
+// fixed price orders and one of oracle pegged orders in a shared array of nodes
+
+/// After the discriminator, the roots of both trees, reserved roots and 256 reserved bytes,
+/// and the node array's header and 512 reserved bytes
+const BOOK_SIDE_NODES_OFFSET: usize = 840;
+
+const INNER_NODE_TAG: u8 = 1;
+const LEAF_NODE_TAG: u8 = 2;
+
+#[derive(Copy, Clone)]
+#[repr(packed)]
+struct OrderTreeRoot {
+    maybe_node: u32,
+    leaf_count: u32,
+}
+unsafe impl Zeroable for OrderTreeRoot {}
+unsafe impl Pod for OrderTreeRoot {}
+
+#[derive(Copy, Clone)]
+#[repr(packed)]
+#[allow(dead_code)]
+struct InnerNode {
+    tag: u8,
+    _padding: [u8; 3],
+    prefix_len: u32,
+    key: u128,
+    children: [u32; 2],
+    child_earliest_expiry: [u64; 2],
+    _reserved: [u8; 40],
+}
+unsafe impl Zeroable for InnerNode {}
+unsafe impl Pod for InnerNode {}
+
+#[derive(Copy, Clone)]
+#[repr(packed)]
+#[allow(dead_code)]
+struct LeafNodeV2 {
+    tag: u8,
+    owner_slot: u8,
+    /// Seconds after `timestamp` the order expires at, or 0 if it doesn't
+    time_in_force: u16,
+    _padding: [u8; 4],
+    key: u128,
+    /// The open orders account that placed the order
+    owner: Pubkey,
+    quantity: i64,
+    timestamp: u64,
+    peg_limit: i64,
+    client_order_id: u64,
+}
+unsafe impl Zeroable for LeafNodeV2 {}
+unsafe impl Pod for LeafNodeV2 {}
+
+impl LeafNodeV2 {
+    fn is_expired(&self, now_ts: u64) -> bool {
+        let time_in_force = self.time_in_force;
+        let timestamp = self.timestamp;
+        time_in_force > 0 && now_ts >= timestamp + time_in_force as u64
+    }
+
+    /// The upper half of a fixed price order's key is its price in lots, as in v1
+    fn into_leaf(self) -> LeafNode {
+        LeafNode::new(
+            self.owner_slot,
+            self.key,
+            pubkey_to_serum_bytes(&self.owner),
+            self.quantity as u64,
+            FeeTier::Base,
+            self.client_order_id,
+        )
+    }
+}
+
+#[derive(Copy, Clone)]
+#[repr(packed)]
+struct AnyNode {
+    tag: u8,
+    _data: [u8; 87],
+}
+unsafe impl Zeroable for AnyNode {}
+unsafe impl Pod for AnyNode {}
+
+/// The fixed price orders of a v2 `BookSide` account, best price first and without expired
+/// orders, as v1 leaves so both versions share the order book code. Oracle pegged orders
+/// need the oracle price and are left out.
+pub fn decode_book_side(data: &[u8], bid: bool, now_ts: u64) -> anyhow::Result<Vec<LeafNode>> {
+    if data.len() < BOOK_SIDE_NODES_OFFSET || data[..8] != anchor_account_discriminator("BookSide")
+    {
+        anyhow::bail!("not an OpenBook v2 book side account");
+    }
+    let root: &OrderTreeRoot = from_bytes(&data[8..8 + size_of::<OrderTreeRoot>()]);
+    let node_bytes = &data[BOOK_SIDE_NODES_OFFSET..];
+    let nodes: &[AnyNode] =
+        cast_slice(&node_bytes[..node_bytes.len() / size_of::<AnyNode>() * size_of::<AnyNode>()]);
+
+    let mut leaves = Vec::with_capacity(root.leaf_count as usize);
+    if root.leaf_count == 0 {
+        return Ok(leaves);
+    }
+    // Bids are walked from the highest key, asks from the lowest
+    let mut stack = vec![root.maybe_node];
+    while let Some(handle) = stack.pop() {
+        let node = nodes
+            .get(handle as usize)
+            .ok_or_else(|| anyhow::anyhow!("order tree node {} out of bounds", handle))?;
+        match node.tag {
+            INNER_NODE_TAG => {
+                let inner: &InnerNode = cast_ref(node);
+                let children = inner.children;
+                if bid {
+                    stack.push(children[0]);
+                    stack.push(children[1]);
+                } else {
+                    stack.push(children[1]);
+                    stack.push(children[0]);
+                }
+            }
+            LEAF_NODE_TAG => {
+                let leaf: &LeafNodeV2 = cast_ref(node);
+                if !leaf.is_expired(now_ts) {
+                    leaves.push(leaf.into_leaf());
+                }
+            }
+            tag => anyhow::bail!("unexpected order tree node tag {}", tag),
+        }
+    }
+    Ok(leaves)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::bytes_of;
+
+    fn leaf(price_lots: u64, seq_num: u64, time_in_force: u16) -> AnyNode {
+        *cast_ref(&LeafNodeV2 {
+            tag: LEAF_NODE_TAG,
+            owner_slot: 0,
+            time_in_force,
+            _padding: [0; 4],
+            key: ((price_lots as u128) << 64) | seq_num as u128,
+            owner: Pubkey::new_unique(),
+            quantity: 10,
+            timestamp: 1_000,
+            peg_limit: -1,
+            client_order_id: seq_num,
+        })
+    }
+
+    fn inner(children: [u32; 2]) -> AnyNode {
+        *cast_ref(&InnerNode {
+            tag: INNER_NODE_TAG,
+            _padding: [0; 3],
+            prefix_len: 0,
+            key: 0,
+            children,
+            child_earliest_expiry: [u64::MAX; 2],
+            _reserved: [0; 40],
+        })
+    }
+
+    /// A book side whose fixed order tree is rooted at node 0
+    fn book_side(nodes: &[AnyNode], leaf_count: u32) -> Vec<u8> {
+        let mut data = anchor_account_discriminator("BookSide").to_vec();
+        data.extend_from_slice(bytes_of(&OrderTreeRoot {
+            maybe_node: 0,
+            leaf_count,
+        }));
+        data.resize(BOOK_SIDE_NODES_OFFSET, 0);
+        for node in nodes {
+            data.extend_from_slice(bytes_of(node));
+        }
+        data
+    }
+
+    fn prices(leaves: &[LeafNode]) -> Vec<u64> {
+        leaves.iter().map(|l| l.price().get()).collect()
+    }
+
+    #[test]
+    fn walks_best_price_first_and_skips_expired_orders() {
+        // Prices 100 and 101 under one inner node, 102 under the root
+        let data = book_side(
+            &[
+                inner([1, 2]),
+                inner([3, 4]),
+                leaf(102, 3, 0),
+                leaf(100, 1, 0),
+                leaf(101, 2, 0),
+            ],
+            3,
+        );
+        assert_eq!(
+            prices(&decode_book_side(&data, true, 2_000).unwrap()),
+            vec![102, 101, 100]
+        );
+        assert_eq!(
+            prices(&decode_book_side(&data, false, 2_000).unwrap()),
+            vec![100, 101, 102]
+        );
+
+        // The order at 101 expires 60 seconds after it was placed
+        let data = book_side(
+            &[
+                inner([1, 2]),
+                inner([3, 4]),
+                leaf(102, 3, 0),
+                leaf(100, 1, 0),
+                leaf(101, 2, 60),
+            ],
+            3,
+        );
+        assert_eq!(
+            prices(&decode_book_side(&data, true, 1_059).unwrap()),
+            vec![102, 101, 100]
+        );
+        assert_eq!(
+            prices(&decode_book_side(&data, true, 1_060).unwrap()),
+            vec![102, 100]
+        );
+    }
+
+    #[test]
+    fn empty_and_foreign_accounts() {
+        let data = book_side(&[], 0);
+        assert!(decode_book_side(&data, true, 0).unwrap().is_empty());
+
+        let mut data = book_side(&[leaf(100, 1, 0)], 1);
+        data[0] ^= 1;
+        assert!(decode_book_side(&data, true, 0).is_err());
+    }
+}
@@ -0,0 +1,424 @@
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Serialize;
+use solana_sdk::pubkey::Pubkey;
+use std::{collections::HashMap, str::FromStr, sync::RwLock};
+
+use super::{
+    markets::{serum_bytes_to_pubkey, MarketInfo, MarketVersion},
+    order_tree::decode_book_side,
+    slab::{readable_price_lots, LeafNode, Slab},
+};
+
+#[derive(Clone, Debug)]
+pub struct BookSide {
+    pub slot: u64,
+    pub updated_at: DateTime<Utc>,
+    /// Best price first
+    pub orders: Vec<LeafNode>,
+}
+
+#[derive(Clone, Debug)]
+pub struct OrderBookSnapshot {
+    pub bids: Vec<LeafNode>,
+    pub asks: Vec<LeafNode>,
+    /// The slot of the least recently updated side
+    pub slot: u64,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl OrderBookSnapshot {
+    pub fn age_ms(&self) -> i64 {
+        (Utc::now() - self.updated_at).num_milliseconds()
+    }
+
+    pub fn best_bid(&self, market: &MarketInfo) -> Option<f64> {
+        self.bids.first().map(|leaf| leaf.readable_price(market))
+    }
+
+    pub fn best_ask(&self, market: &MarketInfo) -> Option<f64> {
+        self.asks.first().map(|leaf| leaf.readable_price(market))
+    }
+}
+
+/// Decoded bids and asks for each market, kept up to date by a background task
+pub struct OrderBookCache {
+    /// Maps each bids/asks account to whether it holds bids, and its market's version
+    side_accounts: HashMap<Pubkey, (bool, MarketVersion)>,
+    sides: RwLock<HashMap<Pubkey, BookSide>>,
+}
+
+impl OrderBookCache {
+    pub fn new(markets: &[MarketInfo]) -> Self {
+        let mut side_accounts = HashMap::new();
+        for m in markets {
+            side_accounts.insert(Pubkey::from_str(&m.bids_key).unwrap(), (true, m.version));
+            side_accounts.insert(Pubkey::from_str(&m.asks_key).unwrap(), (false, m.version));
+        }
+        OrderBookCache {
+            side_accounts,
+            sides: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn accounts(&self) -> Vec<Pubkey> {
+        self.side_accounts.keys().cloned().collect()
+    }
+
+    /// Decodes the slab or v2 order tree in `data`, ignoring accounts that aren't tracked and updates older than what is cached
+    pub fn update(&self, account: &Pubkey, slot: u64, data: &mut [u8]) {
+        let (bid, version) = match self.side_accounts.get(account) {
+            Some(side) => *side,
+            None => return,
+        };
+        if matches!(self.sides.read().unwrap().get(account), Some(s) if s.slot > slot) {
+            return;
+        }
+        let orders = match version {
+            MarketVersion::V1 => Slab::try_new(data)
+                .and_then(|slab| slab.try_traverse(bid))
+                .map(|leaves| leaves.into_iter().copied().collect::<Vec<LeafNode>>()),
+            MarketVersion::V2 => decode_book_side(data, bid, Utc::now().timestamp() as u64),
+        };
+        let orders = match orders {
+            Ok(orders) => orders,
+            Err(e) => {
+                warn!("failed to decode order book {}: {}", account, e);
+                return;
+            }
+        };
+
+        let mut sides = self.sides.write().unwrap();
+        if matches!(sides.get(account), Some(s) if s.slot > slot) {
+            return;
+        }
+        sides.insert(
+            *account,
+            BookSide {
+                slot,
+                updated_at: Utc::now(),
+                orders,
+            },
+        );
+    }
+
+    /// Returns None until both sides of the market have been loaded
+    pub fn snapshot(&self, market: &MarketInfo) -> Option<OrderBookSnapshot> {
+        let bids_key = Pubkey::from_str(&market.bids_key).ok()?;
+        let asks_key = Pubkey::from_str(&market.asks_key).ok()?;
+        let sides = self.sides.read().unwrap();
+        let bids = sides.get(&bids_key)?;
+        let asks = sides.get(&asks_key)?;
+        Some(OrderBookSnapshot {
+            bids: bids.orders.clone(),
+            asks: asks.orders.clone(),
+            slot: bids.slot.min(asks.slot),
+            updated_at: bids.updated_at.min(asks.updated_at),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OrderBookResponse<T> {
+    pub market_name: String,
+    pub slot: u64,
+    pub age_ms: i64,
+    pub bids: Vec<T>,
+    pub asks: Vec<T>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct L2Level {
+    pub price: f64,
+    pub size: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct L3Order {
+    pub order_id: String,
+    pub price: f64,
+    pub size: f64,
+    /// The open orders account that placed the order
+    pub open_orders: String,
+    /// Looked up from past fills, so None if the open orders account has never traded
+    pub open_orders_owner: Option<String>,
+    pub owner_slot: u8,
+    pub client_order_id: String,
+    pub fee_tier: u8,
+}
+
+/// The number of price lots in a grouping increment, or None unless it is a positive whole
+/// number of ticks
+pub fn grouping_lots(grouping: f64, market: &MarketInfo) -> Option<u64> {
+    let tick = readable_price_lots(1, market);
+    let lots = (grouping / tick).round();
+    if !lots.is_finite() || lots < 1.0 || lots > u64::MAX as f64 {
+        return None;
+    }
+    // Allow for the rounding in the tick and in the caller's decimal
+    if ((lots * tick - grouping) / grouping).abs() > 1e-9 {
+        return None;
+    }
+    Some(lots as u64)
+}
+
+/// Aggregates orders (best price first) into at most `depth` levels. With `grouping_lots`,
+/// bid prices are rounded down and ask prices up to a multiple of that many price lots.
+pub fn construct_l2_levels(
+    orders: &[LeafNode],
+    market: &MarketInfo,
+    depth: usize,
+    grouping_lots: Option<u64>,
+    bid: bool,
+) -> Vec<L2Level> {
+    let mut levels: Vec<(u64, L2Level)> = vec![];
+    for order in orders {
+        let price_lots = order.price().get();
+        let price_lots = match grouping_lots {
+            Some(g) if bid => price_lots / g * g,
+            Some(g) => price_lots
+                .checked_add(g - 1)
+                .map_or(price_lots / g * g, |p| p / g * g),
+            None => price_lots,
+        };
+        let size = order.readable_quantity(market);
+        match levels.last_mut() {
+            Some((lots, level)) if *lots == price_lots => level.size += size,
+            _ if levels.len() == depth => break,
+            _ => levels.push((
+                price_lots,
+                L2Level {
+                    price: readable_price_lots(price_lots, market),
+                    size,
+                },
+            )),
+        }
+    }
+    levels.into_iter().map(|(_, level)| level).collect()
+}
+
+pub fn construct_l3_orders(
+    orders: &[LeafNode],
+    market: &MarketInfo,
+    depth: usize,
+    owners: &HashMap<String, String>,
+) -> Vec<L3Order> {
+    orders
+        .iter()
+        .take(depth)
+        .map(|order| {
+            let open_orders = serum_bytes_to_pubkey(order.owner()).to_string();
+            L3Order {
+                order_id: order.order_id().to_string(),
+                price: order.readable_price(market),
+                size: order.readable_quantity(market),
+                open_orders_owner: owners.get(&open_orders).cloned(),
+                open_orders,
+                owner_slot: order.owner_slot(),
+                client_order_id: order.client_order_id().to_string(),
+                fee_tier: order.fee_tier,
+            }
+        })
+        .collect()
+}
+
+/// The open orders accounts behind a set of orders
+pub fn open_orders_accounts(orders: &[LeafNode]) -> Vec<String> {
+    let mut accounts = orders
+        .iter()
+        .map(|order| serum_bytes_to_pubkey(order.owner()).to_string())
+        .collect::<Vec<String>>();
+    accounts.sort();
+    accounts.dedup();
+    accounts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{markets::test_market, slab::test_slab_account};
+
+    #[test]
+    fn malformed_accounts_are_skipped() {
+        let bids = Pubkey::new_unique();
+        let asks = Pubkey::new_unique();
+        let market = MarketInfo {
+            bids_key: bids.to_string(),
+            asks_key: asks.to_string(),
+            ..test_market("SOL/USDC")
+        };
+        let cache = OrderBookCache::new(&[market.clone()]);
+
+        // Truncated, and long enough but not a slab
+        cache.update(&bids, 1, &mut [0; 10]);
+        cache.update(&asks, 1, &mut [0xff; 400]);
+        assert!(cache.snapshot(&market).is_none());
+    }
+
+    #[test]
+    fn empty_books_have_no_best_prices() {
+        let bids = Pubkey::new_unique();
+        let asks = Pubkey::new_unique();
+        let market = MarketInfo {
+            bids_key: bids.to_string(),
+            asks_key: asks.to_string(),
+            ..test_market("SOL/USDC")
+        };
+        let cache = OrderBookCache::new(&[market.clone()]);
+
+        cache.update(&bids, 1, &mut test_slab_account(&[]));
+        cache.update(&asks, 1, &mut test_slab_account(&[]));
+        let snapshot = cache.snapshot(&market).unwrap();
+        assert!(snapshot.bids.is_empty() && snapshot.asks.is_empty());
+        assert_eq!(snapshot.best_bid(&market), None);
+        assert_eq!(snapshot.best_ask(&market), None);
+
+        // Only asks
+        cache.update(&asks, 2, &mut test_slab_account(&[(102, 1), (101, 2)]));
+        let snapshot = cache.snapshot(&market).unwrap();
+        assert_eq!(snapshot.best_bid(&market), None);
+        assert_eq!(
+            snapshot.best_ask(&market),
+            Some(snapshot.asks[0].readable_price(&market))
+        );
+        assert_eq!(snapshot.asks[0].price().get(), 101);
+        assert_eq!(snapshot.slot, 1);
+    }
+
+    /// Ticks at 0.01 with whole base token lots
+    fn lot_market() -> MarketInfo {
+        MarketInfo {
+            base_decimals: 6,
+            quote_decimals: 6,
+            base_lot_size: 1_000_000,
+            quote_lot_size: 10_000,
+            ..test_market("SOL/USDC")
+        }
+    }
+
+    fn orders(book: &[(u64, u64)], bid: bool) -> Vec<LeafNode> {
+        let mut data = test_slab_account(book);
+        let slab = Slab::try_new(&mut data).unwrap();
+        slab.try_traverse(bid)
+            .unwrap()
+            .into_iter()
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn groupings_are_whole_ticks() {
+        let market = lot_market();
+        assert_eq!(grouping_lots(0.01, &market), Some(1));
+        assert_eq!(grouping_lots(0.1, &market), Some(10));
+        assert_eq!(grouping_lots(2.5, &market), Some(250));
+        // Finer than a tick, or between ticks
+        assert_eq!(grouping_lots(0.005, &market), None);
+        assert_eq!(grouping_lots(0.025, &market), None);
+        assert_eq!(grouping_lots(0.0, &market), None);
+        assert_eq!(grouping_lots(-0.1, &market), None);
+        assert_eq!(grouping_lots(f64::NAN, &market), None);
+        assert_eq!(grouping_lots(f64::INFINITY, &market), None);
+    }
+
+    #[test]
+    fn l2_levels_group_in_price_lots() {
+        let market = lot_market();
+        let book = [(1005, 10), (1003, 20), (999, 30), (1010, 5)];
+
+        let bids = orders(&book, true);
+        assert_eq!(
+            construct_l2_levels(&bids, &market, 10, None, true),
+            vec![
+                L2Level {
+                    price: 10.1,
+                    size: 5.0
+                },
+                L2Level {
+                    price: 10.05,
+                    size: 10.0
+                },
+                L2Level {
+                    price: 10.03,
+                    size: 20.0
+                },
+                L2Level {
+                    price: 9.99,
+                    size: 30.0
+                },
+            ]
+        );
+        // Bids round down to 0.1
+        assert_eq!(
+            construct_l2_levels(&bids, &market, 10, Some(10), true),
+            vec![
+                L2Level {
+                    price: 10.1,
+                    size: 5.0
+                },
+                L2Level {
+                    price: 10.0,
+                    size: 30.0
+                },
+                L2Level {
+                    price: 9.9,
+                    size: 30.0
+                },
+            ]
+        );
+        assert_eq!(
+            construct_l2_levels(&bids, &market, 2, Some(10), true),
+            vec![
+                L2Level {
+                    price: 10.1,
+                    size: 5.0
+                },
+                L2Level {
+                    price: 10.0,
+                    size: 30.0
+                },
+            ]
+        );
+
+        // Asks round up
+        let asks = orders(&book, false);
+        assert_eq!(
+            construct_l2_levels(&asks, &market, 10, Some(10), false),
+            vec![
+                L2Level {
+                    price: 10.0,
+                    size: 30.0
+                },
+                L2Level {
+                    price: 10.1,
+                    size: 35.0
+                },
+            ]
+        );
+        assert!(construct_l2_levels(&[], &market, 10, Some(10), false).is_empty());
+    }
+
+    #[test]
+    fn l3_orders_keep_each_order() {
+        let market = lot_market();
+        let bids = orders(&[(1000, 10), (1002, 20), (1001, 30)], true);
+        let accounts = open_orders_accounts(&bids);
+        assert_eq!(accounts.len(), 3);
+        let best = serum_bytes_to_pubkey(bids[0].owner()).to_string();
+        let owners = HashMap::from([(best.clone(), "owner".to_string())]);
+
+        let l3 = construct_l3_orders(&bids, &market, 2, &owners);
+        assert_eq!(l3.len(), 2);
+        assert_eq!(l3[0].price, 10.02);
+        assert_eq!(l3[0].size, 20.0);
+        assert_eq!(l3[0].open_orders, best);
+        assert_eq!(l3[0].open_orders_owner.as_deref(), Some("owner"));
+        assert_eq!(l3[0].client_order_id, "1");
+        assert_eq!(l3[0].order_id, bids[0].order_id().to_string());
+        assert_eq!(l3[1].price, 10.01);
+        assert_eq!(l3[1].size, 30.0);
+        assert_eq!(l3[1].open_orders_owner, None);
+        assert_eq!(l3[1].client_order_id, "2");
+        assert_ne!(l3[0].open_orders, l3[1].open_orders);
+    }
+}
@@ -1,8 +1,9 @@
-use chrono::Duration;
-use std::fmt;
-use strum::EnumIter;
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+use std::{fmt, str::FromStr};
+use strum::{EnumIter, IntoEnumIterator};
 
-#[derive(EnumIter, Copy, Clone, Eq, PartialEq)]
+/// The resolutions that candles are built and stored for
+#[derive(EnumIter, Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Resolution {
     R1m,
     R3m,
@@ -13,6 +14,8 @@ pub enum Resolution {
     R2h,
     R4h,
     R1d,
+    R1w,
+    R1mo,
 }
 
 pub fn day() -> Duration {
@@ -31,51 +34,419 @@ impl fmt::Display for Resolution {
             Resolution::R2h => write!(f, "2H"),
             Resolution::R4h => write!(f, "4H"),
             Resolution::R1d => write!(f, "1D"),
+            Resolution::R1w => write!(f, "1W"),
+            Resolution::R1mo => write!(f, "1MO"),
         }
     }
 }
 
+impl FromStr for Resolution {
+    type Err = ();
+
+    /// Parses the names written by `Display`, plus the legacy `D` for `1D`
+    fn from_str(v: &str) -> Result<Self, ()> {
+        if v == "D" {
+            return Ok(Resolution::R1d);
+        }
+        Resolution::iter().find(|r| r.to_string() == v).ok_or(())
+    }
+}
+
 impl Resolution {
-    pub fn get_constituent_resolution(self) -> Resolution {
+    /// The resolution this one is built from, None for 1M candles which are built from fills
+    pub fn get_constituent_resolution(self) -> Option<Resolution> {
         match self {
-            Resolution::R1m => panic!("have to use fills to make 1M candles"),
-            Resolution::R3m => Resolution::R1m,
-            Resolution::R5m => Resolution::R1m,
-            Resolution::R15m => Resolution::R5m,
-            Resolution::R30m => Resolution::R15m,
-            Resolution::R1h => Resolution::R30m,
-            Resolution::R2h => Resolution::R1h,
-            Resolution::R4h => Resolution::R2h,
-            Resolution::R1d => Resolution::R4h,
+            Resolution::R1m => None,
+            Resolution::R3m => Some(Resolution::R1m),
+            Resolution::R5m => Some(Resolution::R1m),
+            Resolution::R15m => Some(Resolution::R5m),
+            Resolution::R30m => Some(Resolution::R15m),
+            Resolution::R1h => Some(Resolution::R30m),
+            Resolution::R2h => Some(Resolution::R1h),
+            Resolution::R4h => Some(Resolution::R2h),
+            Resolution::R1d => Some(Resolution::R4h),
+            Resolution::R1w => Some(Resolution::R1d),
+            Resolution::R1mo => Some(Resolution::R1d),
         }
     }
 
-    pub fn get_duration(self) -> Duration {
+    /// The equivalent resolution string in TradingView's format
+    pub fn get_tradingview_resolution(self) -> String {
+        ChartResolution::from(self).to_tradingview()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResolutionUnit {
+    Second,
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+impl ResolutionUnit {
+    /// The longest a single unit can be, counting months as 31 days
+    fn max_seconds(self) -> i64 {
         match self {
-            Resolution::R1m => Duration::minutes(1),
-            Resolution::R3m => Duration::minutes(3),
-            Resolution::R5m => Duration::minutes(5),
-            Resolution::R15m => Duration::minutes(15),
-            Resolution::R30m => Duration::minutes(30),
-            Resolution::R1h => Duration::hours(1),
-            Resolution::R2h => Duration::hours(2),
-            Resolution::R4h => Duration::hours(4),
-            Resolution::R1d => day(),
-        }
-    }
-
-    pub fn from_str(v: &str) -> Result<Self, ()> {
-        match v {
-            "1M" => Ok(Resolution::R1m),
-            "3M" => Ok(Resolution::R3m),
-            "5M" => Ok(Resolution::R5m),
-            "15M" => Ok(Resolution::R15m),
-            "30M" => Ok(Resolution::R30m),
-            "1H" => Ok(Resolution::R1h),
-            "2H" => Ok(Resolution::R2h),
-            "4H" => Ok(Resolution::R4h),
-            "D" => Ok(Resolution::R1d),
-            _ => Err(()),
+            ResolutionUnit::Second => 1,
+            ResolutionUnit::Minute => 60,
+            ResolutionUnit::Hour => 60 * 60,
+            ResolutionUnit::Day => day().num_seconds(),
+            ResolutionUnit::Week => 7 * day().num_seconds(),
+            ResolutionUnit::Month => 31 * day().num_seconds(),
+        }
+    }
+}
+
+/// Parsed resolutions are limited to 100 years of 31 day months, so bucket boundaries stay
+/// well within the dates chrono can represent
+const MAX_RESOLUTION_SECONDS: i64 = 100 * 12 * 31 * 24 * 60 * 60;
+
+/// Any resolution a chart can ask for, e.g. `10S`, `6H` or `1MO`. Fixed length resolutions
+/// are aligned to the unix epoch, weeks start on Monday 00:00 UTC and months on the first.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ChartResolution {
+    pub count: u32,
+    pub unit: ResolutionUnit,
+}
+
+/// Where the candles for a chart resolution come from
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CandleSource {
+    /// The resolution is stored and can be served as is
+    Stored(Resolution),
+    /// Aggregated from the largest stored resolution whose buckets nest in this one's
+    Aggregated(Resolution),
+    /// Finer than any stored resolution, so built from fills
+    Fills,
+}
+
+/// The first Monday after the unix epoch, which was a Thursday
+fn first_monday() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(1970, 1, 5, 0, 0, 0).unwrap()
+}
+
+fn months_since_epoch(time: DateTime<Utc>) -> i64 {
+    (time.year() as i64 - 1970) * 12 + time.month0() as i64
+}
+
+fn month_start(months_since_epoch: i64) -> DateTime<Utc> {
+    let year = 1970 + months_since_epoch.div_euclid(12) as i32;
+    let month = months_since_epoch.rem_euclid(12) as u32 + 1;
+    Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).unwrap()
+}
+
+impl ChartResolution {
+    pub fn new(count: u32, unit: ResolutionUnit) -> Self {
+        ChartResolution { count, unit }.normalized()
+    }
+
+    /// Like `new`, but None for resolutions longer than `MAX_RESOLUTION_SECONDS`
+    fn parsed(count: u32, unit: ResolutionUnit) -> Option<Self> {
+        if count as i64 * unit.max_seconds() > MAX_RESOLUTION_SECONDS {
+            return None;
+        }
+        Some(ChartResolution::new(count, unit))
+    }
+
+    /// Writes seconds as minutes, minutes as hours and hours as days when they divide evenly,
+    /// so equal resolutions compare equal. Days never become weeks since they align differently.
+    fn normalized(self) -> Self {
+        match self.unit {
+            ResolutionUnit::Second if self.count % 60 == 0 => {
+                ChartResolution::new(self.count / 60, ResolutionUnit::Minute)
+            }
+            ResolutionUnit::Minute if self.count % 60 == 0 => {
+                ChartResolution::new(self.count / 60, ResolutionUnit::Hour)
+            }
+            ResolutionUnit::Hour if self.count % 24 == 0 => ChartResolution {
+                count: self.count / 24,
+                unit: ResolutionUnit::Day,
+            },
+            _ => self,
+        }
+    }
+
+    /// The length of each bucket, None for months which vary in length
+    pub fn duration(self) -> Option<Duration> {
+        let count = self.count as i64;
+        match self.unit {
+            ResolutionUnit::Second => Some(Duration::seconds(count)),
+            ResolutionUnit::Minute => Some(Duration::minutes(count)),
+            ResolutionUnit::Hour => Some(Duration::hours(count)),
+            ResolutionUnit::Day => Some(Duration::days(count)),
+            ResolutionUnit::Week => Some(Duration::weeks(count)),
+            ResolutionUnit::Month => None,
+        }
+    }
+
+    /// Start of the bucket that `time` falls in
+    pub fn bucket_start(self, time: DateTime<Utc>) -> DateTime<Utc> {
+        match (self.unit, self.duration()) {
+            (ResolutionUnit::Month, _) | (_, None) => {
+                let months = months_since_epoch(time);
+                month_start(months - months.rem_euclid(self.count as i64))
+            }
+            (ResolutionUnit::Week, Some(duration)) => {
+                let since_monday = (time - first_monday()).num_seconds();
+                first_monday()
+                    + Duration::seconds(
+                        since_monday - since_monday.rem_euclid(duration.num_seconds()),
+                    )
+            }
+            (_, Some(duration)) => {
+                let seconds = time.timestamp();
+                Utc.timestamp_opt(seconds - seconds.rem_euclid(duration.num_seconds()), 0)
+                    .unwrap()
+            }
+        }
+    }
+
+    /// Start of the bucket after the one starting at `bucket_start`
+    pub fn next_bucket_start(self, bucket_start: DateTime<Utc>) -> DateTime<Utc> {
+        match self.duration() {
+            Some(duration) => bucket_start + duration,
+            None => month_start(months_since_epoch(bucket_start) + self.count as i64),
+        }
+    }
+
+    /// Whether every bucket of this resolution lies within a single bucket of `other`
+    pub fn nests_in(self, other: ChartResolution) -> bool {
+        let is_week = |r: ChartResolution| r.unit == ResolutionUnit::Week;
+        match (self.duration(), other.duration()) {
+            (None, None) => other.count % self.count == 0,
+            (None, Some(_)) => false,
+            // Anything that evenly divides a day starts at midnight, so nests in months
+            (Some(duration), None) => day().num_seconds() % duration.num_seconds() == 0,
+            (Some(duration), Some(other_duration)) => match (is_week(self), is_week(other)) {
+                (true, false) => false,
+                (false, true) => day().num_seconds() % duration.num_seconds() == 0,
+                _ => other_duration.num_seconds() % duration.num_seconds() == 0,
+            },
+        }
+    }
+
+    pub fn source(self) -> CandleSource {
+        if let Some(stored) = Resolution::iter().find(|r| ChartResolution::from(*r) == self) {
+            return CandleSource::Stored(stored);
+        }
+        let divisor = Resolution::iter()
+            .filter(|r| ChartResolution::from(*r).nests_in(self))
+            .last();
+        match divisor {
+            Some(r) => CandleSource::Aggregated(r),
+            None => CandleSource::Fills,
+        }
+    }
+
+    /// TradingView's format, where a bare number is minutes and `M` is months
+    pub fn to_tradingview(self) -> String {
+        match self.unit {
+            ResolutionUnit::Second => format!("{}S", self.count),
+            ResolutionUnit::Minute => self.count.to_string(),
+            ResolutionUnit::Hour => (self.count * 60).to_string(),
+            ResolutionUnit::Day => format!("{}D", self.count),
+            ResolutionUnit::Week => format!("{}W", self.count),
+            ResolutionUnit::Month => format!("{}M", self.count),
+        }
+    }
+
+    pub fn from_tradingview(v: &str) -> Option<Self> {
+        let (count, unit) = split_count(v)?;
+        let unit = match unit {
+            "" => ResolutionUnit::Minute,
+            "S" => ResolutionUnit::Second,
+            "D" => ResolutionUnit::Day,
+            "W" => ResolutionUnit::Week,
+            "M" => ResolutionUnit::Month,
+            _ => return None,
+        };
+        ChartResolution::parsed(count, unit)
+    }
+}
+
+/// Splits e.g. `15M` into 15 and `M`. The count defaults to 1 and can't be 0.
+fn split_count(v: &str) -> Option<(u32, &str)> {
+    let digits = v.find(|c: char| !c.is_ascii_digit()).unwrap_or(v.len());
+    let count = match &v[..digits] {
+        "" => 1,
+        n => n.parse().ok()?,
+    };
+    if count == 0 {
+        return None;
+    }
+    Some((count, &v[digits..]))
+}
+
+impl From<Resolution> for ChartResolution {
+    fn from(resolution: Resolution) -> Self {
+        match resolution {
+            Resolution::R1m => ChartResolution::new(1, ResolutionUnit::Minute),
+            Resolution::R3m => ChartResolution::new(3, ResolutionUnit::Minute),
+            Resolution::R5m => ChartResolution::new(5, ResolutionUnit::Minute),
+            Resolution::R15m => ChartResolution::new(15, ResolutionUnit::Minute),
+            Resolution::R30m => ChartResolution::new(30, ResolutionUnit::Minute),
+            Resolution::R1h => ChartResolution::new(1, ResolutionUnit::Hour),
+            Resolution::R2h => ChartResolution::new(2, ResolutionUnit::Hour),
+            Resolution::R4h => ChartResolution::new(4, ResolutionUnit::Hour),
+            Resolution::R1d => ChartResolution::new(1, ResolutionUnit::Day),
+            Resolution::R1w => ChartResolution::new(1, ResolutionUnit::Week),
+            Resolution::R1mo => ChartResolution::new(1, ResolutionUnit::Month),
         }
     }
 }
+
+/// Written the same way as `Resolution`, with `MO` for months since `M` is minutes
+impl fmt::Display for ChartResolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = match self.unit {
+            ResolutionUnit::Second => "S",
+            ResolutionUnit::Minute => "M",
+            ResolutionUnit::Hour => "H",
+            ResolutionUnit::Day => "D",
+            ResolutionUnit::Week => "W",
+            ResolutionUnit::Month => "MO",
+        };
+        write!(f, "{}{}", self.count, unit)
+    }
+}
+
+impl FromStr for ChartResolution {
+    type Err = ();
+
+    /// Accepts the format written by `Display` in any case, and bare minutes like TradingView
+    fn from_str(v: &str) -> Result<Self, ()> {
+        let upper = v.to_ascii_uppercase();
+        let (count, unit) = split_count(&upper).ok_or(())?;
+        let unit = match unit {
+            "" | "M" => ResolutionUnit::Minute,
+            "S" => ResolutionUnit::Second,
+            "H" => ResolutionUnit::Hour,
+            "D" => ResolutionUnit::Day,
+            "W" => ResolutionUnit::Week,
+            "MO" => ResolutionUnit::Month,
+            _ => return Err(()),
+        };
+        ChartResolution::parsed(count, unit).ok_or(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn resolutions_round_trip() {
+        for r in Resolution::iter() {
+            assert_eq!(Resolution::from_str(&r.to_string()), Ok(r));
+            let chart = ChartResolution::from(r);
+            assert_eq!(chart.to_string(), r.to_string());
+            assert_eq!(chart.source(), CandleSource::Stored(r));
+        }
+        for s in [
+            "1S", "10S", "7M", "6H", "12H", "3D", "1W", "2W", "1MO", "3MO",
+        ] {
+            let chart = ChartResolution::from_str(s).unwrap();
+            assert_eq!(chart.to_string(), s);
+            assert_eq!(
+                ChartResolution::from_tradingview(&chart.to_tradingview()),
+                Some(chart)
+            );
+        }
+        assert_eq!(Resolution::from_str("D"), Ok(Resolution::R1d));
+        assert_eq!(ChartResolution::from_str("60").unwrap().to_string(), "1H");
+        assert_eq!(ChartResolution::from_str("120S").unwrap().to_string(), "2M");
+        assert_eq!(ChartResolution::from_str("1mo").unwrap().to_string(), "1MO");
+        assert!(ChartResolution::from_str("0H").is_err());
+        assert!(ChartResolution::from_str("1Y").is_err());
+    }
+
+    #[test]
+    fn resolutions_are_limited_to_a_century() {
+        assert!(ChartResolution::from_str("1200MO").is_ok());
+        assert!(ChartResolution::from_str("1201MO").is_err());
+        assert!(ChartResolution::from_str("5200W").is_ok());
+        assert!(ChartResolution::from_str("99999999D").is_err());
+        assert!(ChartResolution::from_str("4000000000M").is_err());
+        assert!(ChartResolution::from_str("4000000000S").is_err());
+        assert_eq!(
+            ChartResolution::from_tradingview("1200M").map(|r| r.count),
+            Some(1200)
+        );
+        assert_eq!(ChartResolution::from_tradingview("4000000000M"), None);
+        assert_eq!(ChartResolution::from_tradingview("99999999D"), None);
+
+        // The longest accepted resolutions still have representable bucket boundaries
+        let now = Utc::now();
+        for s in ["1200MO", "5200W", "36600D", "52560000M"] {
+            let resolution = ChartResolution::from_str(s).unwrap();
+            let start = resolution.bucket_start(now);
+            assert!(resolution.next_bucket_start(start) > now, "{}", s);
+        }
+    }
+
+    #[test]
+    fn sources_divide_evenly() {
+        let source = |s: &str| ChartResolution::from_str(s).unwrap().source();
+        assert_eq!(source("10S"), CandleSource::Fills);
+        assert_eq!(source("6H"), CandleSource::Aggregated(Resolution::R2h));
+        assert_eq!(source("12H"), CandleSource::Aggregated(Resolution::R4h));
+        assert_eq!(source("1W"), CandleSource::Stored(Resolution::R1w));
+        assert_eq!(source("1MO"), CandleSource::Stored(Resolution::R1mo));
+        // Days are aligned to the epoch, which wasn't a Monday
+        assert_eq!(source("7D"), CandleSource::Aggregated(Resolution::R1d));
+        assert_eq!(source("2W"), CandleSource::Aggregated(Resolution::R1w));
+        assert_eq!(source("3MO"), CandleSource::Aggregated(Resolution::R1mo));
+    }
+
+    #[test]
+    fn weeks_align_to_monday() {
+        let week = ChartResolution::new(1, ResolutionUnit::Week);
+        // Sunday 2023-01-01 is in the week starting Monday 2022-12-26
+        assert_eq!(
+            week.bucket_start(time(2023, 1, 1, 23, 59)),
+            time(2022, 12, 26, 0, 0)
+        );
+        assert_eq!(
+            week.bucket_start(time(2023, 1, 2, 0, 0)),
+            time(2023, 1, 2, 0, 0)
+        );
+        assert_eq!(
+            week.next_bucket_start(time(2023, 1, 2, 0, 0)),
+            time(2023, 1, 9, 0, 0)
+        );
+    }
+
+    #[test]
+    fn months_align_to_the_first() {
+        let month = ChartResolution::new(1, ResolutionUnit::Month);
+        assert_eq!(
+            month.bucket_start(time(2024, 2, 29, 12, 0)),
+            time(2024, 2, 1, 0, 0)
+        );
+        assert_eq!(
+            month.next_bucket_start(time(2023, 12, 1, 0, 0)),
+            time(2024, 1, 1, 0, 0)
+        );
+        let quarter = ChartResolution::new(3, ResolutionUnit::Month);
+        assert_eq!(
+            quarter.bucket_start(time(2023, 6, 30, 0, 0)),
+            time(2023, 4, 1, 0, 0)
+        );
+    }
+
+    #[test]
+    fn fixed_resolutions_align_to_the_epoch() {
+        let six_hours = ChartResolution::from_str("6H").unwrap();
+        assert_eq!(
+            six_hours.bucket_start(time(2023, 5, 9, 17, 30)),
+            time(2023, 5, 9, 12, 0)
+        );
+    }
+}
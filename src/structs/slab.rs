@@ -1,7 +1,6 @@
 use anchor_lang::prelude::Pubkey;
 use arrayref::array_refs;
 use bytemuck::{cast_mut, cast_ref, cast_slice, Pod, Zeroable};
-use futures::join;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use num_traits::ToPrimitive;
 use solana_client::nonblocking::rpc_client::RpcClient;
@@ -102,12 +101,7 @@ impl LeafNode {
     }
 
     pub fn readable_price(&self, market: &MarketInfo) -> f64 {
-        let price_lots = (self.key >> 64) as f64;
-        let base_multiplier = token_factor(market.base_decimals);
-        let quote_multiplier = token_factor(market.quote_decimals);
-        let base_lot_size = market.base_lot_size as f64;
-        let quote_lot_size = market.quote_lot_size as f64;
-        (price_lots * quote_lot_size * base_multiplier) / (base_lot_size * quote_multiplier)
+        readable_price_lots((self.key >> 64) as u64, market)
     }
 
     pub fn readable_quantity(&self, market: &MarketInfo) -> f64 {
@@ -240,6 +234,16 @@ impl AsRef<AnyNode> for LeafNode {
     }
 }
 
+/// Converts a price in lots to quote tokens per base token
+pub fn readable_price_lots(price_lots: u64, market: &MarketInfo) -> f64 {
+    let price_lots = price_lots as f64;
+    let base_multiplier = token_factor(market.base_decimals);
+    let quote_multiplier = token_factor(market.quote_decimals);
+    let base_lot_size = market.base_lot_size as f64;
+    let quote_lot_size = market.quote_lot_size as f64;
+    (price_lots * quote_lot_size * base_multiplier) / (base_lot_size * quote_multiplier)
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(packed)]
 struct SlabHeader {
@@ -254,6 +258,8 @@ unsafe impl Zeroable for SlabHeader {}
 unsafe impl Pod for SlabHeader {}
 
 const SLAB_HEADER_LEN: usize = size_of::<SlabHeader>();
+/// The `serum` padding and account flags before a slab, and the padding after it
+const SLAB_ACCOUNT_PADDING: usize = 13 + 7;
 
 #[cfg(debug_assertions)]
 unsafe fn invariant(check: bool) {
@@ -296,6 +302,17 @@ impl Slab {
         slab
     }
 
+    /// Like `new`, but errors instead of panicking on an account too short to hold a slab
+    pub fn try_new(raw_bytes: &mut [u8]) -> anyhow::Result<&mut Self> {
+        if raw_bytes.len() < SLAB_ACCOUNT_PADDING + SLAB_HEADER_LEN {
+            anyhow::bail!(
+                "{} bytes is too short for an order book account",
+                raw_bytes.len()
+            );
+        }
+        Ok(Self::new(raw_bytes))
+    }
+
     pub fn get(&self, key: u32) -> Option<&AnyNode> {
         let node = self.nodes().get(key as usize)?;
         let tag = NodeTag::try_from(node.tag);
@@ -387,84 +404,59 @@ impl Slab {
         buf
     }
 
-    #[inline]
-    pub fn find_min(&self) -> Option<&LeafNode> {
-        let handle = self.find_min_max(false).unwrap();
-        match self.get(handle) {
-            Some(node) => Some(node.as_leaf().unwrap()),
-            None => None,
+    /// Like `traverse`, but errors instead of panicking or looping on a malformed tree
+    pub fn try_traverse(&self, descending: bool) -> anyhow::Result<Vec<&LeafNode>> {
+        let leaf_count = self.header().leaf_count as usize;
+        let mut buf = Vec::with_capacity(leaf_count.min(self.nodes().len()));
+        let mut stack: Vec<NodeHandle> = self.root().into_iter().collect();
+        let mut visited = 0;
+        while let Some(handle) = stack.pop() {
+            visited += 1;
+            if visited > self.nodes().len() {
+                anyhow::bail!("order book tree has a cycle");
+            }
+            let node = self
+                .get(handle)
+                .ok_or_else(|| anyhow::anyhow!("order book node {} is missing", handle))?;
+            match node.case() {
+                Some(NodeRef::Leaf(leaf)) => buf.push(leaf),
+                Some(NodeRef::Inner(inner)) => {
+                    let children = inner.children;
+                    // Popped in reverse, so the child to visit first is pushed last
+                    if descending {
+                        stack.push(children[0]);
+                        stack.push(children[1]);
+                    } else {
+                        stack.push(children[1]);
+                        stack.push(children[0]);
+                    }
+                }
+                None => anyhow::bail!("order book node {} has an unknown tag", handle),
+            }
+        }
+        if buf.len() != leaf_count {
+            anyhow::bail!(
+                "order book has {} orders, expected {}",
+                buf.len(),
+                leaf_count
+            );
         }
+        Ok(buf)
     }
 
     #[inline]
-    pub fn find_max(&self) -> Option<&LeafNode> {
-        let handle = self.find_min_max(true).unwrap();
-        match self.get(handle) {
-            Some(node) => Some(node.as_leaf().unwrap()),
-            None => None,
-        }
+    pub fn find_min(&self) -> Option<&LeafNode> {
+        let handle = self.find_min_max(false)?;
+        self.get(handle)?.as_leaf()
     }
 
-    pub fn get_best(&self, market: &MarketInfo, bid: bool) -> f64 {
-        let min = if bid {
-            self.find_max()
-        } else {
-            self.find_min()
-        };
-        min.unwrap().readable_price(market)
+    #[inline]
+    pub fn find_max(&self) -> Option<&LeafNode> {
+        let handle = self.find_min_max(true)?;
+        self.get(handle)?.as_leaf()
     }
 }
 
-pub async fn get_best_bids_and_asks(
-    client: RpcClient,
-    markets: &Vec<MarketInfo>,
-) -> (Vec<f64>, Vec<f64>) {
-    let bid_keys = markets
-        .iter()
-        .map(|m| Pubkey::from_str(&m.bids_key).unwrap())
-        .collect::<Vec<Pubkey>>();
-    let ask_keys = markets
-        .iter()
-        .map(|m| Pubkey::from_str(&m.asks_key).unwrap())
-        .collect::<Vec<Pubkey>>();
-
-    // will error if more than 100 markets are used (not a good idea in general)
-    let (bid_results, ask_results) = join!(
-        client.get_multiple_accounts(&bid_keys),
-        client.get_multiple_accounts(&ask_keys)
-    );
-
-    let bids = bid_results.unwrap();
-    let asks = ask_results.unwrap();
-
-    let best_bids = bids
-        .into_iter()
-        .enumerate()
-        .filter_map(|(index, x)| {
-            if let Some(mut account) = x {
-                let slab = Slab::new(&mut account.data);
-                Some(slab.get_best(&markets[index], true))
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<_>>();
-
-    let best_asks = asks
-        .into_iter()
-        .enumerate()
-        .filter_map(|(index, x)| {
-            if let Some(mut account) = x {
-                let slab = Slab::new(&mut account.data);
-                Some(slab.get_best(&markets[index], false))
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<_>>();
-    (best_bids, best_asks)
-}
-
 pub async fn get_orderbooks_with_depth(
     client: RpcClient,
     market: &MarketInfo,
@@ -485,16 +477,15 @@ pub async fn get_orderbooks_with_depth(
     let bids = Slab::new(&mut bid_acc.data);
     let asks = Slab::new(&mut ask_acc.data);
 
-    let bid_leaves = bids.traverse(true);
-    let ask_leaves = asks.traverse(false);
-    let bid_levels = construct_levels(bid_leaves, market, depth);
-    let ask_levels = construct_levels(ask_leaves, market, depth);
+    let bid_levels = construct_levels(bids.traverse(true), market, depth);
+    let ask_levels = construct_levels(asks.traverse(false), market, depth);
 
     (bid_levels, ask_levels)
 }
 
-fn construct_levels(
-    leaves: Vec<&LeafNode>,
+/// Aggregates orders (best price first) into price levels
+pub fn construct_levels<'a>(
+    leaves: impl IntoIterator<Item = &'a LeafNode>,
     market: &MarketInfo,
     depth: usize,
 ) -> Vec<(String, String)> {
@@ -518,3 +509,141 @@ fn construct_levels(
         .map(|x| (x.0.to_string(), x.1.to_string()))
         .collect()
 }
+
+/// A bids or asks account holding `nodes`, with the tree rooted at `root`
+#[cfg(test)]
+fn slab_account(root: NodeHandle, leaf_count: u64, nodes: &[AnyNode]) -> Vec<u8> {
+    let mut data = b"serum".to_vec();
+    data.extend_from_slice(&[0; 8]);
+    data.extend_from_slice(bytemuck::bytes_of(&SlabHeader {
+        _bump_index: nodes.len() as u64,
+        _free_list_len: 0,
+        _free_list_head: 0,
+        root_node: root,
+        leaf_count,
+    }));
+    for node in nodes {
+        data.extend_from_slice(bytemuck::bytes_of(node));
+    }
+    data.extend_from_slice(b"padding");
+    data
+}
+
+#[cfg(test)]
+fn test_leaf(price_lots: u64, seq_num: u64, quantity: u64) -> AnyNode {
+    *cast_ref(&LeafNode::new(
+        0,
+        ((price_lots as u128) << 64) | seq_num as u128,
+        super::markets::pubkey_to_serum_bytes(&Pubkey::new_unique()),
+        quantity,
+        FeeTier::Base,
+        seq_num,
+    ))
+}
+
+#[cfg(test)]
+fn test_inner(children: [NodeHandle; 2]) -> AnyNode {
+    *cast_ref(&InnerNode {
+        tag: NodeTag::InnerNode.into(),
+        prefix_len: 0,
+        key: 0,
+        children,
+        _padding: [0; 5],
+    })
+}
+
+/// A bids or asks account holding orders of the given price and quantity in lots, each from its
+/// own open orders account and with its position in `orders` as the client order id
+#[cfg(test)]
+pub(crate) fn test_slab_account(orders: &[(u64, u64)]) -> Vec<u8> {
+    let mut leaves: Vec<(u64, u64, u64)> = orders
+        .iter()
+        .enumerate()
+        .map(|(seq_num, (price, quantity))| (*price, seq_num as u64, *quantity))
+        .collect();
+    leaves.sort();
+    // A chain of inner nodes, each with the next lowest order on its left
+    let num_inner = leaves.len().saturating_sub(1) as NodeHandle;
+    let mut nodes = vec![];
+    for i in 0..num_inner {
+        let right = if i + 1 < num_inner {
+            i + 1
+        } else {
+            2 * num_inner
+        };
+        nodes.push(test_inner([num_inner + i, right]));
+    }
+    for (price, seq_num, quantity) in leaves.iter() {
+        nodes.push(test_leaf(*price, *seq_num, *quantity));
+    }
+    slab_account(0, leaves.len() as u64, &nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(price_lots: u64, seq_num: u64) -> AnyNode {
+        test_leaf(price_lots, seq_num, 1)
+    }
+
+    fn prices(leaves: Vec<&LeafNode>) -> Vec<u64> {
+        leaves.iter().map(|l| l.price().get()).collect()
+    }
+
+    #[test]
+    fn try_traverse_matches_traverse() {
+        let mut data = slab_account(
+            0,
+            3,
+            &[
+                test_inner([1, 2]),
+                leaf(100, 1),
+                test_inner([3, 4]),
+                leaf(101, 2),
+                leaf(102, 3),
+            ],
+        );
+        let slab = Slab::try_new(&mut data).unwrap();
+        assert_eq!(
+            prices(slab.try_traverse(true).unwrap()),
+            vec![102, 101, 100]
+        );
+        assert_eq!(
+            prices(slab.try_traverse(false).unwrap()),
+            vec![100, 101, 102]
+        );
+        assert_eq!(prices(slab.traverse(true)), vec![102, 101, 100]);
+
+        let mut empty = slab_account(0, 0, &[]);
+        assert!(Slab::try_new(&mut empty)
+            .unwrap()
+            .try_traverse(true)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn malformed_slabs_are_errors() {
+        assert!(Slab::try_new(&mut [0; 10]).is_err());
+
+        // Root out of bounds
+        let mut data = slab_account(5, 1, &[leaf(100, 1)]);
+        assert!(Slab::try_new(&mut data)
+            .unwrap()
+            .try_traverse(true)
+            .is_err());
+        // An inner node that is its own child
+        let mut data = slab_account(0, 1, &[test_inner([0, 1]), leaf(100, 1)]);
+        assert!(Slab::try_new(&mut data)
+            .unwrap()
+            .try_traverse(true)
+            .is_err());
+        // Fewer orders than the header counts
+        let mut data = slab_account(0, 2, &[leaf(100, 1)]);
+        assert!(Slab::try_new(&mut data)
+            .unwrap()
+            .try_traverse(true)
+            .is_err());
+    }
+}
@@ -1,16 +1,21 @@
-use std::fmt;
+use std::{collections::VecDeque, fmt};
 
-use num_traits::ToPrimitive;
+use rust_decimal::Decimal;
 use serde::Serialize;
 use tokio_postgres::Row;
 
-use super::openbook::token_factor;
+use super::{
+    markets::MarketInfo,
+    openbook::{calculate_fill_price_and_size, readable_amount, token_factor},
+    trades::PgTrade,
+};
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct PgTrader {
     pub open_orders_owner: String,
-    pub raw_ask_size: f64,
-    pub raw_bid_size: f64,
+    pub raw_ask_size: Decimal,
+    pub raw_bid_size: Decimal,
+    pub raw_referrer_rebate: Decimal,
 }
 impl PgTrader {
     pub fn from_row(row: Row) -> Self {
@@ -18,6 +23,7 @@ impl PgTrader {
             open_orders_owner: row.get(0),
             raw_ask_size: row.get(1),
             raw_bid_size: row.get(2),
+            raw_referrer_rebate: row.get(3),
         }
     }
 }
@@ -26,12 +32,14 @@ impl PgTrader {
 pub enum VolumeType {
     Base,
     Quote,
+    Usd,
 }
 impl fmt::Display for VolumeType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             VolumeType::Base => write!(f, "Base"),
             VolumeType::Quote => write!(f, "Quote"),
+            VolumeType::Usd => write!(f, "Usd"),
         }
     }
 }
@@ -40,6 +48,8 @@ impl fmt::Display for VolumeType {
 pub struct Trader {
     pub pubkey: String,
     pub volume: f64,
+    /// Referrer rebates paid on the trader's fills, in the quote token
+    pub referrer_rebate: f64,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize)]
@@ -51,12 +61,367 @@ pub struct TraderResponse {
 }
 
 // Note that the Postgres queries only return volumes in base or quote
-pub fn calculate_trader_volume(trader: PgTrader, decimals: u8) -> Trader {
-    let bid_size = trader.raw_bid_size / token_factor(decimals);
-    let ask_size = trader.raw_ask_size / token_factor(decimals);
-
+pub fn calculate_trader_volume(trader: PgTrader, decimals: u8, quote_decimals: u8) -> Trader {
     Trader {
         pubkey: trader.open_orders_owner,
-        volume: (bid_size + ask_size).to_f64().unwrap(),
+        volume: readable_amount(trader.raw_bid_size + trader.raw_ask_size, decimals),
+        referrer_rebate: readable_amount(trader.raw_referrer_rebate, quote_decimals),
+    }
+}
+
+/// How a market's quote volume is valued in USD
+#[derive(Clone, Debug, PartialEq)]
+pub struct UsdValuation<'a> {
+    pub market: &'a MarketInfo,
+    /// The market whose 1H closes price this market's quote token, None if it is already USD
+    pub reference_market: Option<String>,
+}
+
+/// Markets quoted in the quote token of a reference market (e.g. USDC for SOL/USDC) are taken
+/// as USD. Markets quoted in the base token of a reference market (e.g. SOL for BONK/SOL) are
+/// converted with its candles. Any other market can't be valued and is left out.
+pub fn usd_valuations<'a>(
+    markets: &'a [MarketInfo],
+    reference_market_names: &[String],
+) -> Vec<UsdValuation<'a>> {
+    let references: Vec<&MarketInfo> = markets
+        .iter()
+        .filter(|m| reference_market_names.contains(&m.name))
+        .collect();
+
+    markets
+        .iter()
+        .filter_map(|market| {
+            if references
+                .iter()
+                .any(|r| r.quote_mint_key == market.quote_mint_key)
+            {
+                return Some(UsdValuation {
+                    market,
+                    reference_market: None,
+                });
+            }
+            references
+                .iter()
+                .find(|r| r.base_mint_key == market.quote_mint_key)
+                .map(|r| UsdValuation {
+                    market,
+                    reference_market: Some(r.name.clone()),
+                })
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PgUsdTrader {
+    pub open_orders_owner: String,
+    pub usd_volume: f64,
+    pub usd_referrer_rebate: f64,
+}
+impl PgUsdTrader {
+    pub fn from_row(row: Row) -> Self {
+        PgUsdTrader {
+            open_orders_owner: row.get(0),
+            usd_volume: row.get(1),
+            usd_referrer_rebate: row.get(2),
+        }
+    }
+}
+impl From<PgUsdTrader> for Trader {
+    fn from(trader: PgUsdTrader) -> Self {
+        Trader {
+            pubkey: trader.open_orders_owner,
+            volume: trader.usd_volume,
+            referrer_rebate: trader.usd_referrer_rebate,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct UsdTraderResponse {
+    pub start_time: u64,
+    pub end_time: u64,
+    pub volume_type: String,
+    /// The markets counted, and those left out because their quote token can't be valued
+    pub markets: Vec<String>,
+    pub excluded_markets: Vec<String>,
+    pub traders: Vec<Trader>,
+    /// Pass as `offset` to get the next page, None once there are no more traders
+    pub next_offset: Option<i64>,
+}
+
+/// A fill of one trader, with the market and account it was made from
+#[derive(Clone, Debug, PartialEq)]
+pub struct PgTraderFill {
+    pub trade: PgTrade,
+    pub market: String,
+    pub open_orders: String,
+}
+impl PgTraderFill {
+    pub fn from_row(row: Row) -> anyhow::Result<Self> {
+        Ok(PgTraderFill {
+            market: row.get("market"),
+            open_orders: row.get("open_orders"),
+            trade: PgTrade::from_row(row)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TraderFill {
+    pub market_name: String,
+    pub signature: String,
+    pub log_index: i32,
+    pub time: i64,
+    /// The trader's side, "buy" or "sell"
+    pub side: String,
+    pub maker: bool,
+    pub price: f64,
+    pub size: f64,
+    /// A fee paid as taker, or a rebate earned as maker, in the quote token. Negative for a
+    /// fee paid as a v2 maker
+    pub fee_or_rebate: f64,
+    pub open_orders: String,
+    pub client_order_id: Option<String>,
+}
+impl TraderFill {
+    pub fn from_pg_fill(fill: &PgTraderFill, market: &MarketInfo) -> Self {
+        let trade = &fill.trade;
+        let (price, size) =
+            calculate_fill_price_and_size(trade.fill, market.base_decimals, market.quote_decimals);
+        TraderFill {
+            market_name: market.name.clone(),
+            signature: trade.signature.clone(),
+            log_index: trade.log_index,
+            time: trade.fill.time.timestamp(),
+            side: if trade.fill.bid { "buy" } else { "sell" }.to_string(),
+            maker: trade.fill.maker,
+            price,
+            size,
+            fee_or_rebate: trade.fill.native_fee_or_rebate as f64
+                / token_factor(market.quote_decimals),
+            open_orders: fill.open_orders.clone(),
+            client_order_id: trade.fill.client_order_id.map(|id| id.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TraderFillsResponse {
+    pub owner: String,
+    pub fills: Vec<TraderFill>,
+    /// Pass as `before` to get the next page, None once there are no more fills
+    pub next_cursor: Option<String>,
+}
+
+/// Totals for one market. Quote amounts are in the quote token, sizes in the base token.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TraderMarketSummary {
+    pub market_name: String,
+    pub base_volume: f64,
+    pub quote_volume: f64,
+    pub maker_quote_volume: f64,
+    pub taker_quote_volume: f64,
+    pub maker_fills: u64,
+    pub taker_fills: u64,
+    pub fees_paid: f64,
+    pub rebates_earned: f64,
+    /// PnL of positions closed within the range, matching buys and sells first in first out
+    pub realized_pnl: f64,
+    /// `realized_pnl` less fees paid plus rebates earned
+    pub net_realized_pnl: f64,
+    /// Base still held (positive) or owed (negative) at the end of the range
+    pub open_position: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TraderSummaryResponse {
+    pub owner: String,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub markets: Vec<TraderMarketSummary>,
+    /// True if the fill limit was reached, in which case later fills in the range are left out
+    pub truncated: bool,
+}
+
+/// Running totals and open lots for one market, fed the trader's fills in time order. The
+/// trader is assumed to have no position at the first fill, since anything opened before the
+/// range isn't known.
+#[derive(Clone, Debug, Default)]
+pub struct TraderMarketSummarizer {
+    summary: TraderMarketSummary,
+    /// Open lots as (signed size, price), longs are positive
+    lots: VecDeque<(f64, f64)>,
+}
+
+impl TraderMarketSummarizer {
+    pub fn add_fill(&mut self, fill: &TraderFill) {
+        let summary = &mut self.summary;
+        if summary.market_name.is_empty() {
+            summary.market_name = fill.market_name.clone();
+        }
+        let quote = fill.price * fill.size;
+        summary.base_volume += fill.size;
+        summary.quote_volume += quote;
+        if fill.maker {
+            summary.maker_quote_volume += quote;
+            summary.maker_fills += 1;
+            // v2 makers can pay a fee, recorded as a negative rebate
+            if fill.fee_or_rebate < 0.0 {
+                summary.fees_paid -= fill.fee_or_rebate;
+            } else {
+                summary.rebates_earned += fill.fee_or_rebate;
+            }
+        } else {
+            summary.taker_quote_volume += quote;
+            summary.taker_fills += 1;
+            summary.fees_paid += fill.fee_or_rebate;
+        }
+
+        let direction = if fill.side == "buy" { 1.0 } else { -1.0 };
+        let mut remaining = fill.size;
+        while remaining > 0.0 {
+            match self.lots.front_mut() {
+                // Closing against the oldest lot on the other side
+                Some((lot_size, lot_price)) if lot_size.signum() != direction => {
+                    let closed = remaining.min(lot_size.abs());
+                    summary.realized_pnl += closed * (fill.price - *lot_price) * -direction;
+                    *lot_size += closed * direction;
+                    remaining -= closed;
+                    if lot_size.abs() <= f64::EPSILON {
+                        self.lots.pop_front();
+                    }
+                }
+                _ => {
+                    self.lots.push_back((remaining * direction, fill.price));
+                    remaining = 0.0;
+                }
+            }
+        }
+    }
+
+    pub fn finish(self) -> TraderMarketSummary {
+        let mut summary = self.summary;
+        summary.net_realized_pnl =
+            summary.realized_pnl - summary.fees_paid + summary.rebates_earned;
+        summary.open_position = self.lots.iter().map(|(size, _)| size).sum();
+        summary
+    }
+}
+
+/// Summarizes one market's fills, which must be in time order
+pub fn summarize_trader_fills(fills: &[TraderFill]) -> TraderMarketSummary {
+    let mut summarizer = TraderMarketSummarizer::default();
+    for fill in fills {
+        summarizer.add_fill(fill);
+    }
+    summarizer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::markets::test_market;
+
+    fn fill(side: &str, maker: bool, price: f64, size: f64) -> TraderFill {
+        TraderFill {
+            market_name: "SOL/USDC".to_string(),
+            signature: String::new(),
+            log_index: 0,
+            time: 0,
+            side: side.to_string(),
+            maker,
+            price,
+            size,
+            fee_or_rebate: 0.0,
+            open_orders: String::new(),
+            client_order_id: None,
+        }
+    }
+
+    fn market(name: &str, base_mint: &str, quote_mint: &str) -> MarketInfo {
+        MarketInfo {
+            address: name.to_string(),
+            base_mint_key: base_mint.to_string(),
+            quote_mint_key: quote_mint.to_string(),
+            ..test_market(name)
+        }
+    }
+
+    #[test]
+    fn usd_valuations_convert_through_reference_markets() {
+        let markets = vec![
+            market("SOL/USDC", "SOL", "USDC"),
+            market("MNGO/USDC", "MNGO", "USDC"),
+            market("BONK/SOL", "BONK", "SOL"),
+            market("BONK/MNGO", "BONK", "MNGO"),
+        ];
+        let valuations = usd_valuations(&markets, &["SOL/USDC".to_string()]);
+        let valued: Vec<(&str, Option<&str>)> = valuations
+            .iter()
+            .map(|v| (v.market.name.as_str(), v.reference_market.as_deref()))
+            .collect();
+        assert_eq!(
+            valued,
+            vec![
+                ("SOL/USDC", None),
+                ("MNGO/USDC", None),
+                ("BONK/SOL", Some("SOL/USDC")),
+            ]
+        );
+    }
+
+    #[test]
+    fn realized_pnl_is_first_in_first_out() {
+        let fills = vec![
+            fill("buy", true, 10.0, 1.0),
+            fill("buy", false, 12.0, 1.0),
+            // Closes the lot bought at 10 and half of the one at 12
+            fill("sell", false, 13.0, 1.5),
+            // Closes the rest and opens a 1.0 short at 9
+            fill("sell", true, 9.0, 1.5),
+        ];
+        let summary = summarize_trader_fills(&fills);
+        assert_eq!(summary.realized_pnl, 3.0 + 0.5 - 1.5);
+        assert_eq!(summary.open_position, -1.0);
+        assert_eq!(summary.maker_fills, 2);
+        assert_eq!(summary.taker_fills, 2);
+        assert_eq!(summary.base_volume, 5.0);
+    }
+
+    #[test]
+    fn summaries_carry_open_lots_across_pages() {
+        let fills = vec![
+            fill("buy", true, 10.0, 1.0),
+            fill("buy", false, 12.0, 1.0),
+            fill("sell", false, 13.0, 1.5),
+            fill("sell", true, 9.0, 1.5),
+        ];
+        for page_size in 1..=fills.len() {
+            let mut summarizer = TraderMarketSummarizer::default();
+            for page in fills.chunks(page_size) {
+                for fill in page {
+                    summarizer.add_fill(fill);
+                }
+            }
+            assert_eq!(summarizer.finish(), summarize_trader_fills(&fills));
+        }
+        assert_eq!(summarize_trader_fills(&[]), TraderMarketSummary::default());
+    }
+
+    #[test]
+    fn negative_maker_rebates_are_fees_paid() {
+        let with_fee = |maker: bool, fee_or_rebate: f64| TraderFill {
+            fee_or_rebate,
+            ..fill("buy", maker, 10.0, 1.0)
+        };
+        let fills = vec![
+            with_fee(true, 0.25),
+            with_fee(true, -0.5),
+            with_fee(false, 1.0),
+        ];
+        let summary = summarize_trader_fills(&fills);
+        assert_eq!(summary.rebates_earned, 0.25);
+        assert_eq!(summary.fees_paid, 1.5);
     }
 }
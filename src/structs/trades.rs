@@ -0,0 +1,118 @@
+use serde::Serialize;
+use tokio_postgres::Row;
+
+use crate::utils::to_timestampz;
+
+use super::{
+    markets::MarketInfo,
+    openbook::{calculate_fill_price_and_size, PgOpenBookFill},
+};
+
+/// A taker fill along with the columns that identify it
+#[derive(Clone, Debug, PartialEq)]
+pub struct PgTrade {
+    pub fill: PgOpenBookFill,
+    pub signature: String,
+    pub log_index: i32,
+}
+impl PgTrade {
+    pub fn from_row(row: Row) -> anyhow::Result<Self> {
+        Ok(PgTrade {
+            signature: row.get("signature"),
+            log_index: row.get("log_index"),
+            fill: PgOpenBookFill::from_row(row)?,
+        })
+    }
+
+    pub fn cursor(&self) -> TradeCursor {
+        TradeCursor {
+            time: self.fill.time.timestamp() as u64,
+            signature: self.signature.clone(),
+            log_index: self.log_index,
+        }
+    }
+}
+
+/// Optional filters on the fills behind trades and trader volumes
+#[derive(Clone, Debug, Default)]
+pub struct FillFilter {
+    pub client_order_id: Option<u64>,
+    pub owner_slot: Option<u8>,
+    /// Only fills that did (true) or didn't (false) pay a referrer rebate
+    pub referred: Option<bool>,
+}
+
+/// Position in the trade tape, ordered by `(time, signature, log_index)`.
+/// Serialized as `{time}_{signature}_{log_index}`, which is safe since signatures are base58.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TradeCursor {
+    pub time: u64,
+    pub signature: String,
+    pub log_index: i32,
+}
+impl TradeCursor {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split('_');
+        let cursor = TradeCursor {
+            time: parts.next()?.parse().ok()?,
+            signature: parts.next()?.to_string(),
+            log_index: parts.next()?.parse().ok()?,
+        };
+        match parts.next() {
+            Some(_) => None,
+            None => Some(cursor),
+        }
+    }
+
+    pub fn time(&self) -> chrono::DateTime<chrono::Utc> {
+        to_timestampz(self.time)
+    }
+}
+impl std::fmt::Display for TradeCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}_{}_{}", self.time, self.signature, self.log_index)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Trade {
+    pub signature: String,
+    pub log_index: i32,
+    pub time: i64,
+    pub price: f64,
+    pub size: f64,
+    /// The side of the fill, "buy" or "sell". This is the taker's side unless `maker` is true.
+    pub side: String,
+    pub maker: bool,
+    pub client_order_id: Option<String>,
+    pub referrer_rebate: Option<u64>,
+    pub owner_slot: Option<u8>,
+    pub slot: Option<u64>,
+}
+impl Trade {
+    pub fn from_pg_trade(trade: &PgTrade, market: &MarketInfo) -> Self {
+        let (price, size) =
+            calculate_fill_price_and_size(trade.fill, market.base_decimals, market.quote_decimals);
+        Trade {
+            signature: trade.signature.clone(),
+            log_index: trade.log_index,
+            time: trade.fill.time.timestamp(),
+            price,
+            size,
+            side: if trade.fill.bid { "buy" } else { "sell" }.to_string(),
+            maker: trade.fill.maker,
+            client_order_id: trade.fill.client_order_id.map(|id| id.to_string()),
+            referrer_rebate: trade.fill.referrer_rebate,
+            owner_slot: trade.fill.owner_slot,
+            slot: trade.fill.slot,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TradesResponse {
+    pub market_name: String,
+    pub trades: Vec<Trade>,
+    /// Pass as `before` to get the next page, None once there are no more trades
+    pub next_cursor: Option<String>,
+}
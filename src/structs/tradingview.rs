@@ -1,8 +1,18 @@
 use chrono::Utc;
 use num_traits::ToPrimitive;
 use serde::Serialize;
+use std::str::FromStr;
 
-use super::candle::Candle;
+use super::{
+    candle::Candle,
+    markets::MarketInfo,
+    resolution::{ChartResolution, ResolutionUnit},
+};
+
+/// Resolutions offered to charts, stored ones and those aggregated on request
+const CHART_RESOLUTIONS: &[&str] = &[
+    "1S", "10S", "1M", "3M", "5M", "15M", "30M", "1H", "2H", "4H", "6H", "12H", "1D", "1W", "1MO",
+];
 
 #[derive(Serialize)]
 pub struct TvResponse {
@@ -27,6 +37,10 @@ pub struct TvResponse {
 
 impl TvResponse {
     pub fn candles_to_tv(candles: Vec<Candle>) -> Self {
+        if candles.is_empty() {
+            return TvResponse::no_data(None);
+        }
+
         let mut time: Vec<u64> = Vec::new();
         let mut close: Vec<f64> = Vec::new();
         let mut open: Vec<f64> = Vec::new();
@@ -62,4 +76,237 @@ impl TvResponse {
             next_time: None,
         }
     }
+
+    /// `next_time` is the start of the nearest earlier candle, so TradingView can jump to it
+    /// instead of paging back through an empty range
+    pub fn no_data(next_time: Option<u64>) -> Self {
+        TvResponse {
+            status: "no_data".to_owned(),
+            error_message: None,
+            time: Vec::new(),
+            close: Vec::new(),
+            open: Vec::new(),
+            low: Vec::new(),
+            high: Vec::new(),
+            volume: Vec::new(),
+            next_time,
+        }
+    }
+}
+
+pub const TV_EXCHANGE: &str = "OpenBook";
+pub const TV_SYMBOL_TYPE: &str = "crypto";
+
+#[derive(Serialize)]
+pub struct TvConfig {
+    pub supported_resolutions: Vec<String>,
+    pub supports_group_request: bool,
+    pub supports_marks: bool,
+    pub supports_search: bool,
+    pub supports_timescale_marks: bool,
+    pub supports_time: bool,
+    pub exchanges: Vec<TvExchange>,
+    pub symbols_types: Vec<TvSymbolType>,
+}
+
+impl Default for TvConfig {
+    fn default() -> Self {
+        TvConfig {
+            supported_resolutions: tv_supported_resolutions(),
+            supports_group_request: false,
+            supports_marks: false,
+            supports_search: true,
+            supports_timescale_marks: false,
+            supports_time: true,
+            exchanges: vec![TvExchange {
+                value: TV_EXCHANGE.to_owned(),
+                name: TV_EXCHANGE.to_owned(),
+                desc: TV_EXCHANGE.to_owned(),
+            }],
+            symbols_types: vec![TvSymbolType {
+                name: TV_SYMBOL_TYPE.to_owned(),
+                value: TV_SYMBOL_TYPE.to_owned(),
+            }],
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TvExchange {
+    pub value: String,
+    pub name: String,
+    pub desc: String,
+}
+
+#[derive(Serialize)]
+pub struct TvSymbolType {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Serialize)]
+pub struct TvSymbolInfo {
+    pub name: String,
+    pub ticker: String,
+    pub description: String,
+    #[serde(rename(serialize = "type"))]
+    pub symbol_type: String,
+    pub session: String,
+    pub exchange: String,
+    pub listed_exchange: String,
+    pub timezone: String,
+    pub format: String,
+    pub minmov: u64,
+    pub pricescale: u64,
+    pub has_intraday: bool,
+    pub has_seconds: bool,
+    pub seconds_multipliers: Vec<String>,
+    pub has_daily: bool,
+    pub has_weekly_and_monthly: bool,
+    pub supported_resolutions: Vec<String>,
+    pub intraday_multipliers: Vec<String>,
+    pub volume_precision: u8,
+    pub data_status: String,
+}
+
+impl TvSymbolInfo {
+    pub fn from_market(market: &MarketInfo) -> Self {
+        let seconds_multipliers = chart_resolutions()
+            .into_iter()
+            .filter(|r| r.unit == ResolutionUnit::Second)
+            .map(|r| r.count.to_string())
+            .collect();
+        let intraday_multipliers = chart_resolutions()
+            .into_iter()
+            .filter(|r| matches!(r.unit, ResolutionUnit::Minute | ResolutionUnit::Hour))
+            .map(|r| r.to_tradingview())
+            .collect();
+
+        TvSymbolInfo {
+            name: market.name.clone(),
+            ticker: market.name.clone(),
+            description: market.name.clone(),
+            symbol_type: TV_SYMBOL_TYPE.to_owned(),
+            session: "24x7".to_owned(),
+            exchange: TV_EXCHANGE.to_owned(),
+            listed_exchange: TV_EXCHANGE.to_owned(),
+            timezone: "Etc/UTC".to_owned(),
+            format: "price".to_owned(),
+            minmov: 1,
+            pricescale: price_scale(market),
+            has_intraday: true,
+            has_seconds: true,
+            seconds_multipliers,
+            has_daily: true,
+            has_weekly_and_monthly: true,
+            supported_resolutions: tv_supported_resolutions(),
+            intraday_multipliers,
+            volume_precision: market.base_decimals,
+            data_status: "streaming".to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TvSearchResult {
+    pub symbol: String,
+    pub full_name: String,
+    pub description: String,
+    pub exchange: String,
+    pub ticker: String,
+    #[serde(rename(serialize = "type"))]
+    pub symbol_type: String,
+}
+
+impl TvSearchResult {
+    pub fn from_market(market: &MarketInfo) -> Self {
+        TvSearchResult {
+            symbol: market.name.clone(),
+            full_name: format!("{}:{}", TV_EXCHANGE, market.name),
+            description: market.name.clone(),
+            exchange: TV_EXCHANGE.to_owned(),
+            ticker: market.name.clone(),
+            symbol_type: TV_SYMBOL_TYPE.to_owned(),
+        }
+    }
+}
+
+pub fn chart_resolutions() -> Vec<ChartResolution> {
+    CHART_RESOLUTIONS
+        .iter()
+        .map(|r| ChartResolution::from_str(r).unwrap())
+        .collect()
+}
+
+/// In TradingView's format, where `1M` is a month
+pub fn tv_supported_resolutions() -> Vec<String> {
+    chart_resolutions()
+        .into_iter()
+        .map(|r| r.to_tradingview())
+        .collect()
+}
+
+/// The most price decimals a u64 price scale can hold
+const MAX_PRICE_DECIMALS: u32 = 19;
+
+/// TradingView wants the number of price decimals as a power of ten. One price lot
+/// (the tick size) is the smallest price movement the market allows, so this is the fewest
+/// decimals that write it exactly.
+pub fn price_scale(market: &MarketInfo) -> u64 {
+    // The tick size is quote_lot_size * 10^base_decimals / (base_lot_size * 10^quote_decimals)
+    let numerator = 10u128
+        .checked_pow(market.base_decimals as u32)
+        .and_then(|f| f.checked_mul(market.quote_lot_size as u128));
+    let denominator = 10u128
+        .checked_pow(market.quote_decimals as u32)
+        .and_then(|f| f.checked_mul(market.base_lot_size as u128));
+    let (numerator, denominator) = match (numerator, denominator) {
+        (Some(n), Some(d)) if n > 0 && d > 0 => (n, d),
+        _ => return 1,
+    };
+    let denominator = denominator / gcd(numerator, denominator);
+    let decimals = (0..=MAX_PRICE_DECIMALS)
+        .find(|d| 10u128.pow(*d) % denominator == 0)
+        .unwrap_or(MAX_PRICE_DECIMALS);
+    10u64.pow(decimals)
+}
+
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::markets::test_market;
+
+    fn market(base_decimals: u8, quote_decimals: u8, base_lot: u64, quote_lot: u64) -> MarketInfo {
+        MarketInfo {
+            base_decimals,
+            quote_decimals,
+            base_lot_size: base_lot,
+            quote_lot_size: quote_lot,
+            ..test_market("TEST/USDC")
+        }
+    }
+
+    #[test]
+    fn price_scale_matches_the_tick_size() {
+        // SOL/USDC ticks at 0.001 USDC
+        assert_eq!(price_scale(&market(9, 6, 100_000_000, 100)), 1_000);
+        // A tick of exactly 0.01 needs two decimals, not three
+        assert_eq!(price_scale(&market(6, 6, 100, 1)), 100);
+        // 0.0025 needs four decimals, though its log is closer to three
+        assert_eq!(price_scale(&market(6, 6, 400, 1)), 10_000);
+        // BONK/USDC ticks at 1e-7 USDC, finer than USDC itself
+        assert_eq!(price_scale(&market(5, 6, 1_000_000, 1)), 10_000_000);
+        // A third of a quote token per tick never ends, so gets every decimal a u64 holds
+        assert_eq!(price_scale(&market(0, 0, 3, 1)), 10_000_000_000_000_000_000);
+        // Ticks of a whole quote token or more need no decimals
+        assert_eq!(price_scale(&market(9, 6, 1, 1)), 1);
+        assert_eq!(price_scale(&market(9, 6, 0, 1)), 1);
+    }
 }
@@ -1,8 +1,10 @@
 use chrono::{DateTime, Utc};
 use solana_client::rpc_response::RpcConfirmedTransactionStatusWithSignature;
+use solana_sdk::pubkey::Pubkey;
+use std::fmt;
 use tokio_postgres::Row;
 
-use crate::utils::{to_timestampz, OPENBOOK_KEY};
+use crate::utils::to_timestampz;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct PgTransaction {
@@ -16,14 +18,18 @@ pub struct PgTransaction {
 }
 
 pub const NUM_TRANSACTION_PARTITIONS: u64 = 10;
+/// Transactions that failed to be fetched this many times aren't fetched again
+pub const MAX_FETCH_ATTEMPTS: i32 = 5;
 
 impl PgTransaction {
+    /// `program_id` is the program whose signatures were scraped
     pub fn from_rpc_confirmed_transaction(
         rpc_confirmed_transaction: RpcConfirmedTransactionStatusWithSignature,
+        program_id: &Pubkey,
     ) -> Self {
         PgTransaction {
             signature: rpc_confirmed_transaction.signature,
-            program_pk: OPENBOOK_KEY.to_string(),
+            program_pk: program_id.to_string(),
             block_datetime: to_timestampz(rpc_confirmed_transaction.block_time.unwrap() as u64),
             slot: rpc_confirmed_transaction.slot,
             err: rpc_confirmed_transaction.err.is_some(),
@@ -32,6 +38,47 @@ impl PgTransaction {
         }
     }
 
+    /// Log notifications don't carry a block time, so the caller supplies an estimate. The
+    /// transaction is left unprocessed so the scraper fetches it, which replaces the estimate
+    /// in its fills with the block time.
+    pub fn from_logs_notification(
+        signature: String,
+        slot: u64,
+        estimated_block_time: i64,
+        err: bool,
+        program_id: &Pubkey,
+    ) -> Self {
+        PgTransaction {
+            signature,
+            program_pk: program_id.to_string(),
+            block_datetime: to_timestampz(estimated_block_time as u64),
+            slot,
+            err,
+            processed: false,
+            worker_partition: (slot % NUM_TRANSACTION_PARTITIONS) as i32,
+        }
+    }
+
+    /// A transaction read in full from an archive, which is marked as processed since its
+    /// fills have already been parsed with its block time
+    pub fn from_archived_transaction(
+        signature: String,
+        slot: u64,
+        block_time: i64,
+        err: bool,
+        program_id: &Pubkey,
+    ) -> Self {
+        PgTransaction {
+            signature,
+            program_pk: program_id.to_string(),
+            block_datetime: to_timestampz(block_time as u64),
+            slot,
+            err,
+            processed: true,
+            worker_partition: (slot % NUM_TRANSACTION_PARTITIONS) as i32,
+        }
+    }
+
     pub fn from_row(row: Row) -> Self {
         let slot_raw = row.get::<usize, i64>(3);
         PgTransaction {
@@ -46,6 +93,214 @@ impl PgTransaction {
     }
 }
 
+/// The newest signature of a program the scraper has caught up to. Every signature after it
+/// has been scraped, and any before it are either scraped or covered by an open `ScrapeGap`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScrapeCursor {
+    pub program_id: String,
+    pub signature: String,
+    pub slot: u64,
+    pub block_datetime: DateTime<Utc>,
+}
+
+impl ScrapeCursor {
+    pub fn from_transaction(transaction: &PgTransaction) -> Self {
+        ScrapeCursor {
+            program_id: transaction.program_pk.clone(),
+            signature: transaction.signature.clone(),
+            slot: transaction.slot,
+            block_datetime: transaction.block_datetime,
+        }
+    }
+
+    pub fn from_row(row: Row) -> Self {
+        ScrapeCursor {
+            program_id: row.get(0),
+            signature: row.get(1),
+            slot: row.get::<usize, i64>(2) as u64,
+            block_datetime: row.get(3),
+        }
+    }
+}
+
+/// Signatures older than `before_signature` and newer than `until_signature` that are yet to
+/// be scraped
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScrapeGap {
+    /// Assigned by the database on insert
+    pub id: i64,
+    pub program_id: String,
+    pub before_signature: String,
+    pub before_slot: u64,
+    pub until_signature: String,
+    pub until_slot: u64,
+    pub until_datetime: DateTime<Utc>,
+    pub signatures_found: i64,
+}
+
+impl ScrapeGap {
+    /// The gap between the oldest signature of a poll and the cursor it didn't page back to
+    pub fn between(oldest: &PgTransaction, cursor: &ScrapeCursor) -> Self {
+        ScrapeGap {
+            id: 0,
+            program_id: cursor.program_id.clone(),
+            before_signature: oldest.signature.clone(),
+            before_slot: oldest.slot,
+            until_signature: cursor.signature.clone(),
+            until_slot: cursor.slot,
+            until_datetime: cursor.block_datetime,
+            signatures_found: 0,
+        }
+    }
+
+    pub fn from_row(row: Row) -> Self {
+        ScrapeGap {
+            id: row.get(0),
+            program_id: row.get(1),
+            before_signature: row.get(2),
+            before_slot: row.get::<usize, i64>(3) as u64,
+            until_signature: row.get(4),
+            until_slot: row.get::<usize, i64>(5) as u64,
+            until_datetime: row.get(6),
+            signatures_found: row.get(7),
+        }
+    }
+}
+
+/// The signatures a trade backfill covers, by block time or slot, both inclusive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackfillRange {
+    Time {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+    Slot {
+        start: u64,
+        end: u64,
+    },
+}
+
+impl BackfillRange {
+    pub fn contains(&self, slot: u64, block_datetime: DateTime<Utc>) -> bool {
+        match *self {
+            BackfillRange::Time { start, end } => start <= block_datetime && block_datetime <= end,
+            BackfillRange::Slot { start, end } => start <= slot && slot <= end,
+        }
+    }
+
+    /// Whether the range starts after a signature, so paging back can stop
+    pub fn starts_after(&self, slot: u64, block_datetime: DateTime<Utc>) -> bool {
+        match *self {
+            BackfillRange::Time { start, .. } => block_datetime < start,
+            BackfillRange::Slot { start, .. } => slot < start,
+        }
+    }
+
+    /// How much of the range has been paged through once back at a signature, from 0 to 1
+    pub fn progress(&self, slot: u64, block_datetime: DateTime<Utc>) -> f64 {
+        let (done, total) = match *self {
+            BackfillRange::Time { start, end } => (
+                (end - block_datetime).num_seconds() as f64,
+                (end - start).num_seconds() as f64,
+            ),
+            BackfillRange::Slot { start, end } => {
+                (end as f64 - slot as f64, end as f64 - start as f64)
+            }
+        };
+        if total <= 0.0 {
+            return 1.0;
+        }
+        (done / total).clamp(0.0, 1.0)
+    }
+}
+
+impl fmt::Display for BackfillRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackfillRange::Time { start, end } => write!(f, "{} to {}", start, end),
+            BackfillRange::Slot { start, end } => write!(f, "slots {} to {}", start, end),
+        }
+    }
+}
+
+/// Where a named trade backfill has paged back to. `before_*` are unset until the first page
+/// is written, and an empty `markets` means every market in the markets file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackfillCheckpoint {
+    pub name: String,
+    /// The program whose signatures are paged through
+    pub program_id: String,
+    pub range: BackfillRange,
+    pub markets: Vec<String>,
+    pub before_signature: Option<String>,
+    pub before_slot: Option<u64>,
+    pub before_datetime: Option<DateTime<Utc>>,
+    pub signatures_found: i64,
+    /// Whether paging reached the start of the range
+    pub completed: bool,
+}
+
+impl BackfillCheckpoint {
+    pub fn new(
+        name: String,
+        program_id: String,
+        range: BackfillRange,
+        markets: Vec<String>,
+    ) -> Self {
+        BackfillCheckpoint {
+            name,
+            program_id,
+            range,
+            markets,
+            before_signature: None,
+            before_slot: None,
+            before_datetime: None,
+            signatures_found: 0,
+            completed: false,
+        }
+    }
+
+    /// Moves the checkpoint back to the oldest signature of a page
+    pub fn page_back_to(&mut self, oldest: &PgTransaction) {
+        self.before_signature = Some(oldest.signature.clone());
+        self.before_slot = Some(oldest.slot);
+        self.before_datetime = Some(oldest.block_datetime);
+    }
+
+    pub fn progress(&self) -> f64 {
+        match (self.before_slot, self.before_datetime) {
+            (Some(slot), Some(block_datetime)) => self.range.progress(slot, block_datetime),
+            _ => 0.0,
+        }
+    }
+
+    pub fn from_row(row: Row) -> Self {
+        let start_slot: Option<i64> = row.get(3);
+        let end_slot: Option<i64> = row.get(4);
+        let range = match (start_slot, end_slot) {
+            (Some(start), Some(end)) => BackfillRange::Slot {
+                start: start as u64,
+                end: end as u64,
+            },
+            _ => BackfillRange::Time {
+                start: row.get(1),
+                end: row.get(2),
+            },
+        };
+        BackfillCheckpoint {
+            name: row.get(0),
+            program_id: row.get(11),
+            range,
+            markets: row.get(5),
+            before_signature: row.get(6),
+            before_slot: row.get::<usize, Option<i64>>(7).map(|s| s as u64),
+            before_datetime: row.get(8),
+            signatures_found: row.get(9),
+            completed: row.get(10),
+        }
+    }
+}
+
 pub enum ProcessState {
     Processed,
     Unprocessed,
@@ -3,10 +3,14 @@ use chrono::{NaiveDateTime, Utc};
 use deadpool_postgres::Pool;
 use serde_derive::Deserialize;
 use solana_sdk::pubkey;
+use std::sync::Arc;
 
-use crate::structs::markets::MarketInfo;
+use crate::structs::{markets::MarketInfo, orderbook::OrderBookCache};
 
+/// The OpenBook program, which owns markets that don't set a `program_id` in the markets file
 pub const OPENBOOK_KEY: Pubkey = pubkey!("srmqPvymJeFKQ4zGQed1GFppgkRHL9kaELCbyksJtPX");
+/// The OpenBook v2 program, the default owner of markets with `"version": 2`
+pub const OPENBOOK_V2_KEY: Pubkey = pubkey!("opnb2LAfJYbRMAHHvqjCwQxanZn7ReEHp1k81EohpZb");
 
 pub trait AnyhowWrap {
     type Value;
@@ -48,6 +52,9 @@ pub struct WebContext {
     pub rpc_url: String,
     pub markets: Vec<MarketInfo>,
     pub pool: Pool,
+    pub orderbooks: Arc<OrderBookCache>,
+    /// Markets whose candles value other quote tokens in USD, e.g. SOL/USDC
+    pub usd_reference_markets: Vec<String>,
 }
 
 #[allow(deprecated)]
@@ -0,0 +1,317 @@
+use chrono::{DateTime, Duration, Utc};
+use deadpool_postgres::Pool;
+use log::debug;
+use std::cmp::min;
+use strum::IntoEnumIterator;
+
+use crate::{
+    database::{
+        fetch::{
+            fetch_candle_before, fetch_candle_dirty_ranges, fetch_candles_from, fetch_fills_from,
+        },
+        insert::delete_candle_dirty_ranges,
+    },
+    structs::{
+        candle::CandleDirtyRange,
+        markets::MarketInfo,
+        resolution::{day, ChartResolution, Resolution},
+    },
+};
+
+use super::{
+    higher_order_candles::{
+        combine_into_higher_order_candles, constituent_resolution, seed_from_first,
+    },
+    minute_candles::combine_fills_into_1m_candles,
+    save_candles,
+};
+
+/// Rebuilds the candles covering any dirty ranges of the market, first the 1M candles from
+/// fills and then every higher resolution from the one below it. Ranges are only deleted once
+/// all of their candles are written, so they're retried if the batcher fails part way.
+pub async fn recompute_dirty_candles(pool: &Pool, market: &MarketInfo) -> anyhow::Result<()> {
+    let dirty_ranges = fetch_candle_dirty_ranges(pool, &market.name).await?;
+    if dirty_ranges.is_empty() {
+        return Ok(());
+    }
+
+    for (start_time, end_time) in merge_ranges(&dirty_ranges) {
+        debug!(
+            "Recomputing {} candles from {} to {}",
+            market.name, start_time, end_time
+        );
+        recompute_1m_candles(pool, market, start_time, end_time).await?;
+        for resolution in Resolution::iter().filter(|r| *r != Resolution::R1m) {
+            recompute_higher_order_candles(pool, &market.name, resolution, start_time, end_time)
+                .await?;
+        }
+    }
+
+    let ids: Vec<i64> = dirty_ranges.iter().map(|r| r.id).collect();
+    let client = pool.get().await?;
+    delete_candle_dirty_ranges(&client, &ids).await?;
+    Ok(())
+}
+
+/// Merges overlapping or touching ranges, which must be sorted by start time
+fn merge_ranges(ranges: &[CandleDirtyRange]) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut merged: Vec<(DateTime<Utc>, DateTime<Utc>)> = vec![];
+    for range in ranges {
+        match merged.last_mut() {
+            Some((_, end)) if range.start_time <= *end => {
+                *end = (*end).max(range.end_time);
+            }
+            _ => merged.push((range.start_time, range.end_time)),
+        }
+    }
+    merged
+}
+
+/// A day of fills at a time, like the 1M backfill
+async fn recompute_1m_candles(
+    pool: &Pool,
+    market: &MarketInfo,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let mut chunk_start = start_time;
+    while chunk_start < end_time {
+        let chunk_end = min(chunk_start + day(), end_time);
+        let mut fills = fetch_fills_from(pool, &market.address, chunk_start, chunk_end).await?;
+        let last_price = fetch_candle_before(pool, &market.name, Resolution::R1m, chunk_start)
+            .await?
+            .map(|c| c.close);
+        if !fills.is_empty() || last_price.is_some() {
+            let candles = combine_fills_into_1m_candles(
+                &mut fills,
+                market,
+                chunk_start,
+                chunk_end,
+                last_price,
+            );
+            save_candles(pool, candles).await?;
+        }
+        chunk_start = chunk_end;
+    }
+    Ok(())
+}
+
+/// Rebuilds every bucket of `resolution` that overlaps the range
+async fn recompute_higher_order_candles(
+    pool: &Pool,
+    market_name: &str,
+    resolution: Resolution,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let chart_resolution = ChartResolution::from(resolution);
+    let range_start = chart_resolution.bucket_start(start_time);
+    let last_bucket_start = chart_resolution.bucket_start(end_time - Duration::seconds(1));
+    let range_end = chart_resolution.next_bucket_start(last_bucket_start);
+
+    let mut constituent_candles = fetch_candles_from(
+        pool,
+        market_name,
+        constituent_resolution(resolution)?,
+        range_start,
+        range_end,
+    )
+    .await?;
+    if constituent_candles.is_empty() {
+        return Ok(());
+    }
+    let seed_candle = match fetch_candle_before(pool, market_name, resolution, range_start).await? {
+        Some(candle) => candle,
+        None => seed_from_first(&constituent_candles[0]),
+    };
+
+    let candles = combine_into_higher_order_candles(
+        &mut constituent_candles,
+        resolution,
+        range_start,
+        seed_candle,
+        last_bucket_start,
+    );
+    save_candles(pool, candles).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::{
+            initialize::connect_to_database, insert::insert_streamed_fills,
+            migrations::run_migrations,
+        },
+        structs::{
+            candle::Candle, markets::test_market, openbook::OpenBookFillEvent,
+            transaction::PgTransaction,
+        },
+    };
+    use chrono::TimeZone;
+    use solana_sdk::pubkey::Pubkey;
+    use std::collections::HashMap;
+
+    fn range(id: i64, start_minute: i64, end_minute: i64) -> CandleDirtyRange {
+        let minute = |m: i64| Utc.timestamp_opt(m * 60, 0).unwrap();
+        CandleDirtyRange {
+            id,
+            start_time: minute(start_minute),
+            end_time: minute(end_minute),
+        }
+    }
+
+    #[test]
+    fn touching_ranges_are_merged() {
+        let ranges = vec![
+            range(1, 0, 2),
+            range(2, 1, 3),
+            range(3, 3, 4),
+            range(4, 9, 10),
+        ];
+        let merged: Vec<(i64, i64)> = merge_ranges(&ranges)
+            .into_iter()
+            .map(|(s, e)| (s.timestamp() / 60, e.timestamp() / 60))
+            .collect();
+        assert_eq!(merged, vec![(0, 4), (9, 10)]);
+    }
+
+    /// A fill that arrives after its minute's 1M candle is complete is marked dirty, and
+    /// rebuilding it carries the fill up into the 1H and 1D candles. Needs a database from the
+    /// PG_* variables, run with `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn late_fills_rebuild_higher_order_candles() {
+        let pool = connect_to_database().await.unwrap();
+        run_migrations(&pool).await.unwrap();
+
+        let address = Pubkey::new_unique();
+        let mut market = test_market(&format!("LATE-{}", address));
+        market.address = address.to_string();
+        let markets = HashMap::from([(address, market.clone())]);
+
+        // A day of complete, flat 1M candles and every resolution built from them
+        let day_start = Utc.with_ymd_and_hms(2023, 6, 5, 0, 0, 0).unwrap();
+        let day_end = day_start + day();
+        let minutes = (0..24 * 60)
+            .map(|i| {
+                let mut candle = Candle::create_empty_candle(market.name.clone(), Resolution::R1m);
+                candle.start_time = day_start + Duration::minutes(i);
+                candle.end_time = candle.start_time + Duration::minutes(1);
+                candle.open = 20.0;
+                candle.close = 20.0;
+                candle.high = 20.0;
+                candle.low = 20.0;
+                candle.complete = true;
+                candle
+            })
+            .collect::<Vec<Candle>>();
+        save_candles(&pool, minutes).await.unwrap();
+        for resolution in Resolution::iter().filter(|r| *r != Resolution::R1m) {
+            recompute_higher_order_candles(&pool, &market.name, resolution, day_start, day_end)
+                .await
+                .unwrap();
+        }
+
+        // A maker sells 2 base for 50 quote at 05:30
+        let fill_time = day_start + Duration::minutes(5 * 60 + 30) + Duration::seconds(15);
+        let fill = OpenBookFillEvent {
+            signature: format!("late-{}", address),
+            slot: 1,
+            market: address,
+            open_orders: Pubkey::new_unique(),
+            open_orders_owner: Some(Pubkey::new_unique()),
+            bid: false,
+            maker: true,
+            native_qty_paid: 2_000_000_000,
+            native_qty_received: 50_000_000,
+            native_fee_or_rebate: 0,
+            order_id: 1,
+            owner_slot: 0,
+            fee_tier: 0,
+            client_order_id: None,
+            referrer_rebate: None,
+            block_time: fill_time.timestamp(),
+            log_index: 0,
+        };
+        let transaction = PgTransaction {
+            signature: fill.signature.clone(),
+            program_pk: String::new(),
+            block_datetime: fill_time,
+            slot: 1,
+            err: false,
+            processed: false,
+            worker_partition: 0,
+        };
+        // Inserting the same fill again writes nothing, so it isn't marked dirty twice
+        for _ in 0..2 {
+            insert_streamed_fills(
+                &pool,
+                vec![fill.clone()],
+                vec![transaction.clone()],
+                &markets,
+            )
+            .await
+            .unwrap();
+        }
+        let dirty = fetch_candle_dirty_ranges(&pool, &market.name)
+            .await
+            .unwrap();
+        let fill_minute = day_start + Duration::minutes(5 * 60 + 30);
+        assert_eq!(dirty.len(), 1);
+        assert_eq!(
+            (dirty[0].start_time, dirty[0].end_time),
+            (fill_minute, fill_minute + Duration::minutes(1))
+        );
+
+        recompute_dirty_candles(&pool, &market).await.unwrap();
+        assert!(fetch_candle_dirty_ranges(&pool, &market.name)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let candle = |resolution: Resolution, start_time: DateTime<Utc>| {
+            let pool = pool.clone();
+            let market_name = market.name.clone();
+            async move {
+                fetch_candles_from(&pool, &market_name, resolution, start_time, day_end)
+                    .await
+                    .unwrap()
+                    .remove(0)
+            }
+        };
+        let minute = candle(Resolution::R1m, fill_minute).await;
+        assert_eq!(minute.high, 25.0);
+        assert!(minute.volume > 0.0);
+        for (resolution, start_time) in [
+            (Resolution::R1h, day_start + Duration::hours(5)),
+            (Resolution::R1d, day_start),
+        ] {
+            let higher = candle(resolution, start_time).await;
+            assert_eq!(higher.start_time, start_time, "{}", resolution);
+            assert_eq!(higher.high, minute.high, "{}", resolution);
+            assert_eq!(higher.volume, minute.volume, "{}", resolution);
+            assert!(higher.complete, "{}", resolution);
+        }
+
+        let client = pool.get().await.unwrap();
+        client
+            .execute("DELETE FROM fills WHERE market = $1", &[&market.address])
+            .await
+            .unwrap();
+        client
+            .execute(
+                "DELETE FROM transactions WHERE signature = $1",
+                &[&fill.signature],
+            )
+            .await
+            .unwrap();
+        client
+            .execute(
+                "DELETE FROM candles WHERE market_name = $1",
+                &[&market.name],
+            )
+            .await
+            .unwrap();
+    }
+}
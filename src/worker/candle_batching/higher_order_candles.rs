@@ -7,7 +7,7 @@ use crate::{
     database::fetch::{fetch_candles_from, fetch_earliest_candles, fetch_latest_finished_candle},
     structs::{
         candle::Candle,
-        resolution::{day, Resolution},
+        resolution::{day, ChartResolution, Resolution},
     },
     utils::{f64_max, f64_min},
 };
@@ -17,16 +17,21 @@ pub async fn batch_higher_order_candles(
     market_name: &str,
     resolution: Resolution,
 ) -> anyhow::Result<Vec<Candle>> {
+    let constituent_resolution = constituent_resolution(resolution)?;
     let latest_candle = fetch_latest_finished_candle(pool, market_name, resolution).await?;
 
     match latest_candle {
         Some(candle) => {
             let start_time = candle.end_time;
-            let end_time = start_time + day();
+            // At least a whole bucket, so weeks and months see all of their days
+            let end_time = max(
+                start_time + day(),
+                ChartResolution::from(resolution).next_bucket_start(start_time),
+            );
             let mut constituent_candles = fetch_candles_from(
                 pool,
                 market_name,
-                resolution.get_constituent_resolution(),
+                constituent_resolution,
                 start_time,
                 end_time,
             )
@@ -39,34 +44,30 @@ pub async fn batch_higher_order_candles(
                 resolution,
                 start_time,
                 candle,
+                current_minute(),
             );
             Ok(combined_candles)
         }
         None => {
             let mut constituent_candles =
-                fetch_earliest_candles(pool, market_name, resolution.get_constituent_resolution())
-                    .await?;
+                fetch_earliest_candles(pool, market_name, constituent_resolution).await?;
             if constituent_candles.is_empty() {
                 debug!(
                     "Batching {}, but no candles found for: {:?}, {}",
-                    resolution,
-                    market_name,
-                    resolution.get_constituent_resolution()
+                    resolution, market_name, constituent_resolution
                 );
                 return Ok(Vec::new());
             }
-            let start_time = constituent_candles[0].start_time.duration_trunc(day())?;
-
-            if constituent_candles.is_empty() {
-                return Ok(Vec::new());
-            }
+            let start_time =
+                ChartResolution::from(resolution).bucket_start(constituent_candles[0].start_time);
 
-            let seed_candle = constituent_candles[0].clone();
+            let seed_candle = seed_from_first(&constituent_candles[0]);
             let combined_candles = combine_into_higher_order_candles(
                 &mut constituent_candles,
                 resolution,
                 start_time,
                 seed_candle,
+                current_minute(),
             );
 
             Ok(trim_candles(
@@ -77,58 +78,74 @@ pub async fn batch_higher_order_candles(
     }
 }
 
-fn combine_into_higher_order_candles(
+fn current_minute() -> DateTime<Utc> {
+    Utc::now().duration_trunc(Duration::minutes(1)).unwrap()
+}
+
+/// With no earlier candle, the first bucket opens at the first constituent's open
+pub(crate) fn seed_from_first(first: &Candle) -> Candle {
+    let mut seed_candle = first.clone();
+    seed_candle.close = seed_candle.open;
+    seed_candle
+}
+
+pub(crate) fn constituent_resolution(resolution: Resolution) -> anyhow::Result<Resolution> {
+    resolution
+        .get_constituent_resolution()
+        .ok_or_else(|| anyhow::anyhow!("{} candles are built from fills", resolution))
+}
+
+/// Buckets are aligned by `ChartResolution`, so weeks start on Monday and months on the first.
+/// The last bucket is the one that starts at or before `until`.
+pub(crate) fn combine_into_higher_order_candles(
     constituent_candles: &mut Vec<Candle>,
     target_resolution: Resolution,
     st: DateTime<Utc>,
     seed_candle: Candle,
+    until: DateTime<Utc>,
 ) -> Vec<Candle> {
     debug!("combining for target_resolution: {}", target_resolution);
 
-    let duration = target_resolution.get_duration();
+    let chart_resolution = ChartResolution::from(target_resolution);
 
     let empty_candle = Candle::create_empty_candle(
         constituent_candles[0].market_name.clone(),
         target_resolution,
     );
-    let now = Utc::now().duration_trunc(Duration::minutes(1)).unwrap();
-    let candle_window = now - st;
-    let num_candles = max(
-        1,
-        (candle_window.num_minutes() / duration.num_minutes()) as usize + 1,
-    );
-
-    let mut combined_candles = vec![empty_candle; num_candles];
+    let mut combined_candles = vec![];
 
     let mut con_iter = constituent_candles.iter_mut().peekable();
     let mut start_time = st;
-    let mut end_time = start_time + duration;
 
     let mut last_candle = seed_candle;
 
-    for i in 0..combined_candles.len() {
-        combined_candles[i].open = last_candle.close;
-        combined_candles[i].low = last_candle.close;
-        combined_candles[i].close = last_candle.close;
-        combined_candles[i].high = last_candle.close;
+    while start_time <= until || combined_candles.is_empty() {
+        let end_time = chart_resolution.next_bucket_start(start_time);
+        let mut candle = empty_candle.clone();
+        candle.open = last_candle.close;
+        candle.low = last_candle.close;
+        candle.close = last_candle.close;
+        candle.high = last_candle.close;
 
         while matches!(con_iter.peek(), Some(c) if c.end_time <= end_time) {
             let unit_candle = con_iter.next().unwrap();
-            combined_candles[i].high = f64_max(combined_candles[i].high, unit_candle.high);
-            combined_candles[i].low = f64_min(combined_candles[i].low, unit_candle.low);
-            combined_candles[i].close = unit_candle.close;
-            combined_candles[i].volume += unit_candle.volume;
-            combined_candles[i].complete = unit_candle.complete;
-            combined_candles[i].end_time = unit_candle.end_time;
+            candle.high = f64_max(candle.high, unit_candle.high);
+            candle.low = f64_min(candle.low, unit_candle.low);
+            candle.close = unit_candle.close;
+            candle.volume += unit_candle.volume;
+            candle.complete = unit_candle.complete;
+            candle.end_time = unit_candle.end_time;
         }
+        // Only complete once the constituents reach the end of the bucket
+        candle.complete = candle.complete && candle.end_time == end_time;
 
-        combined_candles[i].start_time = start_time;
-        combined_candles[i].end_time = end_time;
+        candle.start_time = start_time;
+        candle.end_time = end_time;
 
         start_time = end_time;
-        end_time += duration;
 
-        last_candle = combined_candles[i].clone();
+        last_candle = candle.clone();
+        combined_candles.push(candle);
     }
 
     combined_candles
@@ -152,18 +169,20 @@ pub async fn backfill_batch_higher_order_candles(
     resolution: Resolution,
 ) -> anyhow::Result<Vec<Candle>> {
     let mut constituent_candles =
-        fetch_earliest_candles(pool, market_name, resolution.get_constituent_resolution()).await?;
+        fetch_earliest_candles(pool, market_name, constituent_resolution(resolution)?).await?;
     if constituent_candles.is_empty() {
         return Ok(vec![]);
     }
-    let start_time = constituent_candles[0].start_time.duration_trunc(day())?;
+    let start_time =
+        ChartResolution::from(resolution).bucket_start(constituent_candles[0].start_time);
 
-    let seed_candle = constituent_candles[0].clone();
+    let seed_candle = seed_from_first(&constituent_candles[0]);
     let combined_candles = combine_into_higher_order_candles(
         &mut constituent_candles,
         resolution,
         start_time,
         seed_candle,
+        current_minute(),
     );
 
     Ok(trim_candles(
@@ -171,3 +190,138 @@ pub async fn backfill_batch_higher_order_candles(
         constituent_candles[0].start_time,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn date(y: i32, m: u32, d: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, 0, 0, 0).unwrap()
+    }
+
+    /// A complete 1D candle opening on the given day
+    fn day_candle(start: DateTime<Utc>, open: f64, high: f64, low: f64, close: f64) -> Candle {
+        let mut candle = Candle::create_empty_candle("SOL/USDC".to_string(), Resolution::R1d);
+        candle.start_time = start;
+        candle.end_time = start + day();
+        candle.open = open;
+        candle.high = high;
+        candle.low = low;
+        candle.close = close;
+        candle.volume = 1.0;
+        candle.complete = true;
+        candle
+    }
+
+    fn combine(mut days: Vec<Candle>, resolution: Resolution, until: DateTime<Utc>) -> Vec<Candle> {
+        let start_time = ChartResolution::from(resolution).bucket_start(days[0].start_time);
+        let seed_candle = seed_from_first(&days[0]);
+        combine_into_higher_order_candles(&mut days, resolution, start_time, seed_candle, until)
+    }
+
+    fn ohlcv(candle: &Candle) -> (f64, f64, f64, f64, f64) {
+        (
+            candle.open,
+            candle.high,
+            candle.low,
+            candle.close,
+            candle.volume,
+        )
+    }
+
+    #[test]
+    fn first_buckets_open_at_the_first_constituent() {
+        // Opens above its low and closes above its open, so seeding with its close or an
+        // empty candle would both show up
+        let first = day_candle(date(2023, 6, 7), 10.0, 12.0, 9.5, 11.0);
+        let seed_candle = seed_from_first(&first);
+        assert_eq!(seed_candle.close, 10.0);
+
+        let days = vec![first, day_candle(date(2023, 6, 8), 11.0, 11.5, 10.5, 11.2)];
+        let weeks = combine(days, Resolution::R1w, date(2023, 6, 8));
+        assert_eq!(weeks.len(), 1);
+        assert_eq!(weeks[0].start_time, date(2023, 6, 5));
+        assert_eq!(ohlcv(&weeks[0]), (10.0, 12.0, 9.5, 11.2, 2.0));
+    }
+
+    #[test]
+    fn weeks_split_between_sunday_and_monday() {
+        // Saturday 2023-06-03 through Tuesday 2023-06-06
+        let days = vec![
+            day_candle(date(2023, 6, 3), 10.0, 12.0, 9.0, 11.0),
+            day_candle(date(2023, 6, 4), 11.0, 15.0, 10.0, 14.0),
+            day_candle(date(2023, 6, 5), 14.0, 16.0, 8.0, 9.0),
+            day_candle(date(2023, 6, 6), 9.0, 10.0, 7.0, 8.0),
+        ];
+        let weeks = combine(days, Resolution::R1w, date(2023, 6, 6));
+
+        assert_eq!(weeks.len(), 2);
+        assert_eq!(weeks[0].start_time, date(2023, 5, 29));
+        assert_eq!(weeks[0].end_time, date(2023, 6, 5));
+        assert_eq!(ohlcv(&weeks[0]), (10.0, 15.0, 9.0, 14.0, 2.0));
+        assert!(weeks[0].complete);
+
+        assert_eq!(weeks[1].start_time, date(2023, 6, 5));
+        assert_eq!(weeks[1].end_time, date(2023, 6, 12));
+        assert_eq!(ohlcv(&weeks[1]), (14.0, 16.0, 7.0, 8.0, 2.0));
+        assert!(!weeks[1].complete);
+    }
+
+    #[test]
+    fn months_end_after_thirty_days() {
+        // April has 30 days
+        let days = vec![
+            day_candle(date(2023, 4, 29), 10.0, 11.0, 9.0, 10.5),
+            day_candle(date(2023, 4, 30), 10.5, 13.0, 10.0, 12.0),
+            day_candle(date(2023, 5, 1), 12.0, 12.5, 6.0, 7.0),
+        ];
+        let months = combine(days, Resolution::R1mo, date(2023, 5, 1));
+
+        assert_eq!(months.len(), 2);
+        assert_eq!(months[0].start_time, date(2023, 4, 1));
+        assert_eq!(months[0].end_time, date(2023, 5, 1));
+        assert_eq!(ohlcv(&months[0]), (10.0, 13.0, 9.0, 12.0, 2.0));
+        assert!(months[0].complete);
+
+        assert_eq!(months[1].start_time, date(2023, 5, 1));
+        assert_eq!(months[1].end_time, date(2023, 6, 1));
+        assert_eq!(ohlcv(&months[1]), (12.0, 12.5, 6.0, 7.0, 1.0));
+        assert!(!months[1].complete);
+    }
+
+    #[test]
+    fn months_end_after_thirty_one_days() {
+        // May has 31 days, so May 31 still belongs to May
+        let days = vec![
+            day_candle(date(2023, 5, 30), 20.0, 21.0, 19.0, 20.5),
+            day_candle(date(2023, 5, 31), 20.5, 22.0, 18.0, 21.0),
+            day_candle(date(2023, 6, 1), 21.0, 25.0, 20.0, 24.0),
+        ];
+        let months = combine(days, Resolution::R1mo, date(2023, 6, 1));
+
+        assert_eq!(months.len(), 2);
+        assert_eq!(months[0].start_time, date(2023, 5, 1));
+        assert_eq!(months[0].end_time, date(2023, 6, 1));
+        assert_eq!(ohlcv(&months[0]), (20.0, 22.0, 18.0, 21.0, 2.0));
+        assert!(months[0].complete);
+
+        assert_eq!(months[1].start_time, date(2023, 6, 1));
+        assert_eq!(months[1].end_time, date(2023, 7, 1));
+        assert_eq!(ohlcv(&months[1]), (21.0, 25.0, 20.0, 24.0, 1.0));
+        assert!(!months[1].complete);
+    }
+
+    #[test]
+    fn months_missing_their_last_day_are_incomplete() {
+        let days = vec![
+            day_candle(date(2023, 4, 28), 10.0, 11.0, 9.0, 10.5),
+            day_candle(date(2023, 4, 29), 10.5, 13.0, 10.0, 12.0),
+        ];
+        let months = combine(days, Resolution::R1mo, date(2023, 4, 30));
+
+        assert_eq!(months.len(), 1);
+        assert_eq!(months[0].end_time, date(2023, 5, 1));
+        assert!(!months[0].complete);
+    }
+}
@@ -66,7 +66,7 @@ pub async fn batch_1m_candles(pool: &Pool, market: &MarketInfo) -> anyhow::Resul
     }
 }
 
-fn combine_fills_into_1m_candles(
+pub(crate) fn combine_fills_into_1m_candles(
     fills: &mut Vec<PgOpenBookFill>,
     market: &MarketInfo,
     st: DateTime<Utc>,
@@ -1,5 +1,9 @@
+pub mod dirty_ranges;
 pub mod higher_order_candles;
 pub mod minute_candles;
+pub mod sql_candles;
+
+use std::str::FromStr;
 
 use chrono::Duration;
 use deadpool_postgres::Pool;
@@ -8,21 +12,60 @@ use strum::IntoEnumIterator;
 use tokio::time::sleep;
 
 use crate::{
-    database::insert::build_candles_upsert_statement,
+    database::insert::upsert_candles,
     structs::{candle::Candle, markets::MarketInfo, resolution::Resolution},
-    utils::AnyhowWrap,
     worker::candle_batching::minute_candles::batch_1m_candles,
 };
 
-use self::higher_order_candles::batch_higher_order_candles;
+use self::{
+    dirty_ranges::recompute_dirty_candles, higher_order_candles::batch_higher_order_candles,
+    sql_candles::batch_higher_order_candles_sql,
+};
+
+/// How higher-order candles are derived from the resolution below them. All engines write
+/// the same candles; the SQL ones aggregate in the database instead of fetching constituents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CandleEngine {
+    Rust,
+    Sql,
+    /// The SQL engine with TimescaleDB's `time_bucket`, `first` and `last`
+    Timescale,
+}
+
+impl FromStr for CandleEngine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rust" => Ok(CandleEngine::Rust),
+            "sql" | "postgres" => Ok(CandleEngine::Sql),
+            "timescale" | "timescaledb" => Ok(CandleEngine::Timescale),
+            _ => Err(anyhow::anyhow!("unknown candle engine: {}", s)),
+        }
+    }
+}
+
+impl CandleEngine {
+    /// `CANDLE_ENGINE` picks the engine, defaulting to rust
+    pub fn from_env() -> anyhow::Result<Self> {
+        match dotenv::var("CANDLE_ENGINE") {
+            Ok(e) => CandleEngine::from_str(&e),
+            Err(_) => Ok(CandleEngine::Rust),
+        }
+    }
+}
 
-pub async fn batch_for_market(pool: &Pool, market: &MarketInfo) -> anyhow::Result<()> {
+pub async fn batch_for_market(
+    pool: &Pool,
+    market: &MarketInfo,
+    engine: CandleEngine,
+) -> anyhow::Result<()> {
     loop {
         let market_clone = market.clone();
 
         loop {
             sleep(Duration::milliseconds(5000).to_std()?).await;
-            match batch_inner(pool, &market_clone).await {
+            match batch_inner(pool, &market_clone, engine).await {
                 Ok(_) => {}
                 Err(e) => {
                     error!(
@@ -38,7 +81,7 @@ pub async fn batch_for_market(pool: &Pool, market: &MarketInfo) -> anyhow::Resul
     }
 }
 
-async fn batch_inner(pool: &Pool, market: &MarketInfo) -> anyhow::Result<()> {
+async fn batch_inner(pool: &Pool, market: &MarketInfo, engine: CandleEngine) -> anyhow::Result<()> {
     let market_name = &market.name.clone();
     let candles = batch_1m_candles(pool, market).await?;
     save_candles(pool, candles).await?;
@@ -46,9 +89,18 @@ async fn batch_inner(pool: &Pool, market: &MarketInfo) -> anyhow::Result<()> {
         if resolution == Resolution::R1m {
             continue;
         }
-        let candles = batch_higher_order_candles(pool, market_name, resolution).await?;
-        save_candles(pool, candles).await?;
+        match engine {
+            CandleEngine::Rust => {
+                let candles = batch_higher_order_candles(pool, market_name, resolution).await?;
+                save_candles(pool, candles).await?;
+            }
+            CandleEngine::Sql | CandleEngine::Timescale => {
+                let timescale = engine == CandleEngine::Timescale;
+                batch_higher_order_candles_sql(pool, market_name, resolution, timescale).await?;
+            }
+        }
     }
+    recompute_dirty_candles(pool, market).await?;
     Ok(())
 }
 
@@ -56,11 +108,7 @@ async fn save_candles(pool: &Pool, candles: Vec<Candle>) -> anyhow::Result<()> {
     if candles.is_empty() {
         return Ok(());
     }
-    let upsert_statement = build_candles_upsert_statement(&candles);
     let client = pool.get().await.unwrap();
-    client
-        .execute(&upsert_statement, &[])
-        .await
-        .map_err_anyhow()?;
+    upsert_candles(&client, &candles).await?;
     Ok(())
 }
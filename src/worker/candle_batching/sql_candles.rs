@@ -0,0 +1,319 @@
+use chrono::{TimeZone, Utc};
+use deadpool_postgres::{GenericClient, Pool};
+
+use crate::{
+    database::{fetch::fetch_latest_finished_candle, insert::CANDLES_ON_CONFLICT},
+    structs::resolution::{ChartResolution, Resolution},
+};
+
+use super::higher_order_candles::constituent_resolution;
+
+/// Whether the TimescaleDB extension is installed in the database
+pub async fn timescale_available(pool: &Pool) -> anyhow::Result<bool> {
+    let client = pool.get().await?;
+    let row = client
+        .query_one(
+            "SELECT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb')",
+            &[],
+        )
+        .await?;
+    Ok(row.get(0))
+}
+
+/// Whether the server has `date_bin`, which the sql engine buckets with. It was added in
+/// Postgres 14.
+pub async fn date_bin_available(pool: &Pool) -> anyhow::Result<bool> {
+    let client = pool.get().await?;
+    let row = client
+        .query_one(
+            "SELECT current_setting('server_version_num')::int >= 140000",
+            &[],
+        )
+        .await?;
+    Ok(row.get(0))
+}
+
+/// The SQL equivalent of `batch_higher_order_candles`, aggregating the constituent candles
+/// in the database instead of fetching them. Starts from the end of the latest finished
+/// candle, or from the first constituent if there isn't one. Returns the candles written.
+pub async fn batch_higher_order_candles_sql(
+    pool: &Pool,
+    market_name: &str,
+    resolution: Resolution,
+    timescale: bool,
+) -> anyhow::Result<u64> {
+    let latest_candle = fetch_latest_finished_candle(pool, market_name, resolution).await?;
+    let start_time = latest_candle.map(|c| c.end_time);
+
+    let client = pool.get().await?;
+    let stmt = higher_order_statement(resolution, timescale);
+    let written = client
+        .execute(
+            stmt.as_str(),
+            &[
+                &market_name,
+                &resolution.to_string(),
+                &constituent_resolution(resolution)?.to_string(),
+                &start_time,
+            ],
+        )
+        .await?;
+    Ok(written)
+}
+
+/// The start and end of the bucket each constituent `c` falls in, aligned the same way as
+/// `ChartResolution`
+fn bucket_expressions(resolution: Resolution, timescale: bool) -> (String, String) {
+    let chart_resolution = ChartResolution::from(resolution);
+    match chart_resolution.duration() {
+        Some(duration) => {
+            // Weeks line up with a Monday, everything else with the epoch
+            let origin = chart_resolution
+                .bucket_start(Utc.timestamp_opt(0, 0).unwrap())
+                .to_rfc3339();
+            let bucket_fn = if timescale { "time_bucket" } else { "date_bin" };
+            let interval = format!("interval '{} seconds'", duration.num_seconds());
+            (
+                format!(
+                    "{}({}, c.start_time, timestamptz '{}')",
+                    bucket_fn, interval, origin
+                ),
+                format!("b.bucket + {}", interval),
+            )
+        }
+        // Calendar months, in UTC whatever the session time zone is
+        None => (
+            "date_trunc('month', c.start_time AT TIME ZONE 'UTC') AT TIME ZONE 'UTC'".to_string(),
+            format!(
+                "(b.bucket AT TIME ZONE 'UTC' + interval '{} months') AT TIME ZONE 'UTC'",
+                chart_resolution.count
+            ),
+        ),
+    }
+}
+
+/// Params are the market name, the target and constituent resolutions, and the start time.
+/// A candle opens at its first constituent's open and closes at its last one's close, and is
+/// complete once its last constituent is complete and reaches the end of the bucket.
+fn higher_order_statement(resolution: Resolution, timescale: bool) -> String {
+    let (bucket, bucket_end) = bucket_expressions(resolution, timescale);
+    let (open, close, last_complete) = if timescale {
+        (
+            "first(open, start_time)",
+            "last(close, start_time)",
+            "last(complete, start_time)",
+        )
+    } else {
+        (
+            "(array_agg(open ORDER BY start_time))[1]",
+            "(array_agg(close ORDER BY start_time DESC))[1]",
+            "(array_agg(complete ORDER BY start_time DESC))[1]",
+        )
+    };
+    format!(
+        "INSERT INTO candles (market_name, start_time, end_time, resolution, open, close, high, low, volume, complete)
+        SELECT
+            market_name,
+            bucket,
+            bucket_end,
+            $2,
+            {open},
+            {close},
+            max(high),
+            min(low),
+            sum(volume),
+            {last_complete} AND max(end_time) = bucket_end
+          FROM (
+            SELECT c.*, b.bucket, {bucket_end} as bucket_end
+              FROM candles c, LATERAL (SELECT {bucket} as bucket) b
+             WHERE c.market_name = $1
+                AND c.resolution = $3
+                AND ($4::timestamptz IS NULL OR c.start_time >= $4)
+          ) constituents
+         GROUP BY market_name, bucket, bucket_end
+         {on_conflict}",
+        open = open,
+        close = close,
+        last_complete = last_complete,
+        bucket = bucket,
+        bucket_end = bucket_end,
+        on_conflict = CANDLES_ON_CONFLICT,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::{
+            fetch::fetch_candles_from, initialize::connect_to_database, insert::upsert_candles,
+            migrations::run_migrations,
+        },
+        structs::candle::Candle,
+        worker::candle_batching::higher_order_candles::batch_higher_order_candles,
+    };
+    use chrono::{DateTime, Duration, DurationRound};
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn buckets_align_like_chart_resolutions() {
+        let (bucket, end) = bucket_expressions(Resolution::R4h, false);
+        assert_eq!(
+            bucket,
+            "date_bin(interval '14400 seconds', c.start_time, timestamptz '1970-01-01T00:00:00+00:00')"
+        );
+        assert_eq!(end, "b.bucket + interval '14400 seconds'");
+
+        let (bucket, _) = bucket_expressions(Resolution::R1w, true);
+        assert_eq!(
+            bucket,
+            "time_bucket(interval '604800 seconds', c.start_time, timestamptz '1969-12-29T00:00:00+00:00')"
+        );
+    }
+
+    #[test]
+    fn weeks_and_months_are_bucketed_by_the_calendar() {
+        let week = higher_order_statement(Resolution::R1w, false);
+        assert!(week.contains(
+            "SELECT date_bin(interval '604800 seconds', c.start_time, timestamptz '1969-12-29T00:00:00+00:00') as bucket"
+        ));
+        assert!(week.contains("b.bucket + interval '604800 seconds' as bucket_end"));
+        assert!(week.contains("(array_agg(open ORDER BY start_time))[1]"));
+
+        let month = higher_order_statement(Resolution::R1mo, true);
+        assert!(month.contains(
+            "SELECT date_trunc('month', c.start_time AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' as bucket"
+        ));
+        assert!(month.contains(
+            "(b.bucket AT TIME ZONE 'UTC' + interval '1 months') AT TIME ZONE 'UTC' as bucket_end"
+        ));
+        assert!(month.contains("first(open, start_time)"));
+        // Months aren't a fixed length, so they can't use time_bucket
+        assert!(!month.contains("time_bucket"));
+    }
+
+    /// Deterministic 1M candles, each opening at the previous close like the fill batcher's
+    fn minute_candles(market_name: &str, start: DateTime<Utc>, minutes: i64) -> Vec<Candle> {
+        let mut seed: u64 = 42;
+        let mut next = move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) as f64 / (1u64 << 31) as f64
+        };
+        let mut close = 20.0;
+        (0..minutes)
+            .map(|i| {
+                let open = close;
+                close = open * (0.99 + next() * 0.02);
+                let start_time = start + Duration::minutes(i);
+                Candle {
+                    market_name: market_name.to_string(),
+                    start_time,
+                    end_time: start_time + Duration::minutes(1),
+                    resolution: Resolution::R1m.to_string(),
+                    open,
+                    close,
+                    high: open.max(close) * (1.0 + next() * 0.01),
+                    low: open.min(close) * (1.0 - next() * 0.01),
+                    volume: (next() * 1000.0).round(),
+                    // The latest few minutes are still open
+                    complete: i < minutes - 5,
+                }
+            })
+            .collect()
+    }
+
+    /// Builds every resolution from the same 1M candles with each engine and compares them.
+    /// Needs a Postgres 14+ database from the PG_* variables, run with `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn sql_engines_match_rust_batching() {
+        let pool = connect_to_database().await.unwrap();
+        run_migrations(&pool).await.unwrap();
+        let timescale = timescale_available(&pool).await.unwrap();
+
+        let run_id = Utc::now().timestamp_nanos();
+        let end = Utc::now().duration_trunc(Duration::minutes(1)).unwrap();
+        // Long enough to cover a couple of weeks and a month boundary
+        let start = end - Duration::days(40) + Duration::minutes(7);
+        let minutes = (end - start).num_minutes();
+
+        let mut engines = vec![("rust", false), ("sql", false)];
+        if timescale {
+            engines.push(("timescale", true));
+        }
+        let client = pool.get().await.unwrap();
+        for (engine, _) in engines.iter() {
+            let market_name = format!("DIFF-{}/{}", run_id, engine);
+            for chunk in minute_candles(&market_name, start, minutes).chunks(5000) {
+                upsert_candles(&client, chunk).await.unwrap();
+            }
+        }
+
+        for resolution in Resolution::iter().filter(|r| *r != Resolution::R1m) {
+            let rust_market = format!("DIFF-{}/rust", run_id);
+            let candles = batch_higher_order_candles(&pool, &rust_market, resolution)
+                .await
+                .unwrap();
+            upsert_candles(&client, &candles).await.unwrap();
+            for (engine, timescale) in engines.iter().skip(1) {
+                let market_name = format!("DIFF-{}/{}", run_id, engine);
+                batch_higher_order_candles_sql(&pool, &market_name, resolution, *timescale)
+                    .await
+                    .unwrap();
+            }
+
+            // The Rust batcher also writes empty candles up to the current minute, which
+            // the SQL engines leave to the next run once they have constituents
+            let fetch = |market_name: String| {
+                let pool = pool.clone();
+                async move {
+                    fetch_candles_from(
+                        &pool,
+                        &market_name,
+                        resolution,
+                        start - Duration::days(31),
+                        end + Duration::days(31),
+                    )
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .filter(|c| c.start_time < end)
+                    .collect::<Vec<Candle>>()
+                }
+            };
+            let expected = fetch(rust_market.clone()).await;
+            assert!(!expected.is_empty());
+            for (engine, _) in engines.iter().skip(1) {
+                let actual = fetch(format!("DIFF-{}/{}", run_id, engine)).await;
+                assert_eq!(actual.len(), expected.len(), "{} {}", engine, resolution);
+                for (a, e) in actual.iter().zip(expected.iter()) {
+                    let context = format!("{} {} {}", engine, resolution, e.start_time);
+                    assert_eq!(
+                        (a.start_time, a.end_time, a.complete),
+                        (e.start_time, e.end_time, e.complete),
+                        "{}",
+                        context
+                    );
+                    for (x, y) in [
+                        (a.open, e.open),
+                        (a.close, e.close),
+                        (a.high, e.high),
+                        (a.low, e.low),
+                        (a.volume, e.volume),
+                    ] {
+                        // Sums can differ in the last bit with a different order
+                        assert!((x - y).abs() <= 1e-9 * y.abs().max(1.0), "{}", context);
+                    }
+                }
+            }
+        }
+
+        client
+            .execute(
+                "DELETE FROM candles WHERE market_name LIKE $1",
+                &[&format!("DIFF-{}/%", run_id)],
+            )
+            .await
+            .unwrap();
+    }
+}
@@ -1,18 +1,31 @@
-use log::{error, info};
-use openbook_candles::structs::markets::{fetch_market_infos, load_markets};
+use log::{error, info, warn};
+use openbook_candles::structs::markets::{fetch_market_infos, load_markets, market_programs};
 use openbook_candles::structs::transaction::NUM_TRANSACTION_PARTITIONS;
 use openbook_candles::utils::Config;
+use openbook_candles::worker::metrics::METRIC_RPC_ERRORS_TOTAL;
 use openbook_candles::worker::metrics::{
     serve_metrics, METRIC_DB_POOL_AVAILABLE, METRIC_DB_POOL_SIZE,
 };
 use openbook_candles::worker::trade_fetching::scrape::{scrape_fills, scrape_signatures};
+use openbook_candles::worker::trade_fetching::source::{
+    build_source, run_source, IngestionConfig, SourceFilter,
+};
+use openbook_candles::worker::trade_fetching::streaming::write_streamed_fills;
 use openbook_candles::{
-    database::initialize::{connect_to_database, setup_database},
-    worker::candle_batching::batch_for_market,
+    database::{
+        initialize::connect_to_database,
+        migrations::{check_migrations, run_migrations},
+    },
+    worker::candle_batching::{
+        batch_for_market,
+        sql_candles::{date_bin_available, timescale_available},
+        CandleEngine,
+    },
 };
 use solana_sdk::pubkey::Pubkey;
 use std::env;
 use std::{collections::HashMap, str::FromStr, time::Duration as WaitDuration};
+use tokio::sync::mpsc;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() -> anyhow::Result<()> {
@@ -21,6 +34,10 @@ async fn main() -> anyhow::Result<()> {
 
     let args: Vec<String> = env::args().collect();
     assert!(args.len() == 2);
+    if args[1] == "migrate" {
+        let pool = connect_to_database().await?;
+        return run_migrations(&pool).await;
+    }
     let path_to_markets_json = &args[1];
     let rpc_url: String = dotenv::var("RPC_URL").unwrap();
 
@@ -32,20 +49,58 @@ async fn main() -> anyhow::Result<()> {
     let market_infos = fetch_market_infos(&config, markets.clone()).await?;
     let mut target_markets = HashMap::new();
     for m in market_infos.clone() {
-        target_markets.insert(Pubkey::from_str(&m.address)?, m.name);
+        target_markets.insert(Pubkey::from_str(&m.address)?, m);
     }
-    info!("{:?}", target_markets);
+    info!(
+        "{:?}",
+        target_markets
+            .iter()
+            .map(|(k, m)| (k, &m.name))
+            .collect::<HashMap<_, _>>()
+    );
+    let programs = market_programs(&market_infos);
 
     let pool = connect_to_database().await?;
-    setup_database(&pool).await?;
+    check_migrations(&pool).await?;
     let mut handles = vec![];
 
-    // signature scraping
-    let rpc_clone = rpc_url.clone();
-    let pool_clone = pool.clone();
-    handles.push(tokio::spawn(async move {
-        scrape_signatures(rpc_clone, &pool_clone).await.unwrap();
-    }));
+    // real-time fills from a websocket or geyser source, if configured.
+    // Signature scraping still runs to fill any gaps
+    let ingestion_config = IngestionConfig::from_env()?;
+    if let Some(source) = build_source(&ingestion_config)? {
+        info!("Streaming fills from {}", source.name());
+        let (sender, receiver) = mpsc::channel(1000);
+        let filter = SourceFilter {
+            target_markets: target_markets.clone(),
+            // The worker only stores fills, order book accounts are streamed by the server
+            accounts: vec![],
+            programs: programs.clone(),
+        };
+        handles.push(tokio::spawn(async move {
+            run_source(source, filter, sender, |name| {
+                METRIC_RPC_ERRORS_TOTAL.with_label_values(&[name]).inc()
+            })
+            .await;
+        }));
+        let pool_clone = pool.clone();
+        let markets_clone = target_markets.clone();
+        handles.push(tokio::spawn(async move {
+            write_streamed_fills(&pool_clone, receiver, &markets_clone)
+                .await
+                .unwrap();
+        }));
+    }
+
+    // signature scraping, one scraper per program
+    for program_id in programs {
+        let rpc_clone = rpc_url.clone();
+        let pool_clone = pool.clone();
+        handles.push(tokio::spawn(async move {
+            scrape_signatures(rpc_clone, &pool_clone, program_id)
+                .await
+                .unwrap();
+        }));
+    }
 
     // transaction/fill scraping
     for id in 0..NUM_TRANSACTION_PARTITIONS {
@@ -60,10 +115,22 @@ async fn main() -> anyhow::Result<()> {
     }
 
     // candle batching
+    let mut candle_engine = CandleEngine::from_env()?;
+    if candle_engine == CandleEngine::Timescale && !timescale_available(&pool).await? {
+        warn!("timescaledb extension not installed, using the sql candle engine");
+        candle_engine = CandleEngine::Sql;
+    }
+    if candle_engine == CandleEngine::Sql && !date_bin_available(&pool).await? {
+        warn!("date_bin needs Postgres 14+, using the rust candle engine");
+        candle_engine = CandleEngine::Rust;
+    }
+    info!("Batching candles with the {:?} engine", candle_engine);
     for market in market_infos.into_iter() {
         let batch_pool = pool.clone();
         handles.push(tokio::spawn(async move {
-            batch_for_market(&batch_pool, &market).await.unwrap();
+            batch_for_market(&batch_pool, &market, candle_engine)
+                .await
+                .unwrap();
             error!("batching halted for market {}", &market.name);
         }));
     }
@@ -3,7 +3,8 @@ use actix_web_prom::PrometheusMetricsBuilder;
 use lazy_static::lazy_static;
 use prometheus::{
     register_int_counter_vec_with_registry, register_int_counter_with_registry,
-    register_int_gauge_with_registry, IntCounter, IntCounterVec, IntGauge, Registry,
+    register_int_gauge_vec_with_registry, register_int_gauge_with_registry, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Registry,
 };
 
 lazy_static! {
@@ -44,6 +45,27 @@ lazy_static! {
             METRIC_REGISTRY
         )
         .unwrap();
+    pub static ref METRIC_SCRAPE_LAG_SECONDS: IntGaugeVec = register_int_gauge_vec_with_registry!(
+        "scrape_lag_seconds",
+        "Seconds since the start of the oldest open signature gap, or since the newest scraped signature",
+        &["program"],
+        METRIC_REGISTRY
+    )
+    .unwrap();
+    pub static ref METRIC_SCRAPE_GAPS_OPEN: IntGaugeVec = register_int_gauge_vec_with_registry!(
+        "scrape_gaps_open",
+        "Signature gaps still being paged through",
+        &["program"],
+        METRIC_REGISTRY
+    )
+    .unwrap();
+    pub static ref METRIC_SCRAPE_GAPS_TOTAL: IntCounterVec = register_int_counter_vec_with_registry!(
+        "scrape_gaps_total",
+        "Signature gaps detected while scraping",
+        &["program"],
+        METRIC_REGISTRY
+    )
+    .unwrap();
     pub static ref METRIC_DB_POOL_SIZE: IntGauge = register_int_gauge_with_registry!(
         "db_pool_size",
         "Current size of the DB connection pool",
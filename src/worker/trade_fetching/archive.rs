@@ -0,0 +1,308 @@
+use deadpool_postgres::Pool;
+use flate2::read::GzDecoder;
+use log::warn;
+use serde::Deserialize;
+use solana_sdk::pubkey::Pubkey;
+use solana_transaction_status::{
+    option_serializer::OptionSerializer, EncodedConfirmedTransactionWithStatusMeta,
+    EncodedTransaction, EncodedTransactionWithStatusMeta,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+
+use crate::{
+    database::insert::insert_streamed_fills,
+    structs::{markets::MarketInfo, openbook::OpenBookFillEvent, transaction::PgTransaction},
+};
+
+use super::{logs::invoked_program, parsing::parse_trades_from_openbook_txns};
+
+/// Transactions parsed and written at a time
+const ARCHIVE_BATCH_SIZE: usize = 1000;
+
+/// A `getBlock` result with full transaction details, and the slot it was fetched for
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArchivedBlock {
+    slot: u64,
+    block_time: Option<i64>,
+    transactions: Vec<EncodedTransactionWithStatusMeta>,
+}
+
+/// Each line of an archive is a `getTransaction` result or a block
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ArchiveRecord {
+    Block(ArchivedBlock),
+    Transaction(EncodedConfirmedTransactionWithStatusMeta),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ArchiveStats {
+    pub transactions: usize,
+    pub fills: usize,
+    /// Lines that couldn't be parsed and transactions without a block time
+    pub skipped: usize,
+}
+
+/// Opens a JSONL archive, decompressing it if it ends in `.gz` or `.zst`
+pub fn open_archive(path: &Path) -> anyhow::Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    let reader: Box<dyn Read> = match path.extension().and_then(|e| e.to_str()) {
+        Some("gz") => Box::new(GzDecoder::new(file)),
+        Some("zst") => Box::new(zstd::Decoder::new(file)?),
+        _ => Box::new(file),
+    };
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+/// Stores the fills of the target markets found in an archive. Transactions with fills are
+/// recorded as processed so the scraper doesn't fetch them again.
+/// Transactions that don't invoke one of `programs` are ignored.
+pub async fn backfill_from_archive(
+    pool: &Pool,
+    path: &Path,
+    target_markets: &HashMap<Pubkey, MarketInfo>,
+    programs: &[Pubkey],
+) -> anyhow::Result<ArchiveStats> {
+    let mut stats = ArchiveStats::default();
+    let mut batch = vec![];
+    for (idx, line) in open_archive(path)?.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_archive_line(&line) {
+            Ok(txns) => {
+                for (signature, txn) in txns {
+                    if txn.block_time.is_none() {
+                        stats.skipped += 1;
+                        continue;
+                    }
+                    batch.push((signature, txn));
+                }
+            }
+            Err(e) => {
+                warn!("skipping line {} of {}: {}", idx + 1, path.display(), e);
+                stats.skipped += 1;
+            }
+        }
+        if batch.len() >= ARCHIVE_BATCH_SIZE {
+            write_archive_batch(
+                pool,
+                std::mem::take(&mut batch),
+                target_markets,
+                programs,
+                &mut stats,
+            )
+            .await?;
+        }
+    }
+    write_archive_batch(pool, batch, target_markets, programs, &mut stats).await?;
+    Ok(stats)
+}
+
+async fn write_archive_batch(
+    pool: &Pool,
+    batch: Vec<(String, EncodedConfirmedTransactionWithStatusMeta)>,
+    target_markets: &HashMap<Pubkey, MarketInfo>,
+    programs: &[Pubkey],
+    stats: &mut ArchiveStats,
+) -> anyhow::Result<()> {
+    stats.transactions += batch.len();
+    let (fills, transactions) = parse_archive_batch(batch, target_markets, programs);
+    stats.fills += fills.len();
+    insert_streamed_fills(pool, fills, transactions, target_markets).await
+}
+
+/// The transactions of a line, each with its signature
+fn parse_archive_line(
+    line: &str,
+) -> anyhow::Result<Vec<(String, EncodedConfirmedTransactionWithStatusMeta)>> {
+    let txns = match serde_json::from_str::<ArchiveRecord>(line)? {
+        ArchiveRecord::Transaction(txn) => vec![txn],
+        ArchiveRecord::Block(block) => block
+            .transactions
+            .into_iter()
+            .map(|transaction| EncodedConfirmedTransactionWithStatusMeta {
+                slot: block.slot,
+                transaction,
+                block_time: block.block_time,
+            })
+            .collect(),
+    };
+    Ok(txns
+        .into_iter()
+        .filter_map(|txn| transaction_signature(&txn).map(|s| (s, txn)))
+        .collect())
+}
+
+fn transaction_signature(txn: &EncodedConfirmedTransactionWithStatusMeta) -> Option<String> {
+    match &txn.transaction.transaction {
+        EncodedTransaction::Json(ui_txn) => ui_txn.signatures.first().cloned(),
+        encoded => encoded
+            .decode()
+            .and_then(|t| t.signatures.first().map(|s| s.to_string())),
+    }
+}
+
+/// The fills of a batch, and the transactions they came from
+fn parse_archive_batch(
+    batch: Vec<(String, EncodedConfirmedTransactionWithStatusMeta)>,
+    target_markets: &HashMap<Pubkey, MarketInfo>,
+    programs: &[Pubkey],
+) -> (Vec<OpenBookFillEvent>, Vec<PgTransaction>) {
+    let mut transactions = vec![];
+    let mut sig_strings = vec![];
+    let mut txns = vec![];
+    for (signature, txn) in batch {
+        let (program_id, err) = match &txn.transaction.meta {
+            Some(meta) => match &meta.log_messages {
+                OptionSerializer::Some(logs) => {
+                    (invoked_program(logs, programs), meta.err.is_some())
+                }
+                _ => (None, false),
+            },
+            None => (None, false),
+        };
+        let program_id = match program_id {
+            Some(p) => p,
+            None => continue,
+        };
+        transactions.push(PgTransaction::from_archived_transaction(
+            signature.clone(),
+            txn.slot,
+            txn.block_time.unwrap(),
+            err,
+            &program_id,
+        ));
+        sig_strings.push(signature);
+        txns.push(Ok(txn));
+    }
+
+    let (fills, _, _) = parse_trades_from_openbook_txns(&mut txns, sig_strings, target_markets);
+    let with_fills: HashSet<&str> = fills.iter().map(|f| f.signature.as_str()).collect();
+    transactions.retain(|t| with_fills.contains(t.signature.as_str()));
+    (fills, transactions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{structs::markets::test_market, worker::trade_fetching::logs::test_fill_log};
+    use flate2::{write::GzEncoder, Compression};
+    use serde_json::json;
+    use std::io::Write;
+
+    fn invoke_log(program_id: Pubkey) -> String {
+        format!("Program {} invoke [1]", program_id)
+    }
+
+    /// A transaction with the given logs, as a `getTransaction` call with json encoding returns it
+    fn transaction_json(signature: &str, logs: Vec<String>) -> serde_json::Value {
+        json!({
+            "transaction": {
+                "signatures": [signature],
+                "message": {
+                    "header": {
+                        "numRequiredSignatures": 1,
+                        "numReadonlySignedAccounts": 0,
+                        "numReadonlyUnsignedAccounts": 0
+                    },
+                    "accountKeys": [],
+                    "recentBlockhash": "11111111111111111111111111111111",
+                    "instructions": []
+                }
+            },
+            "meta": {
+                "err": null,
+                "status": { "Ok": null },
+                "fee": 5000,
+                "preBalances": [],
+                "postBalances": [],
+                "innerInstructions": [],
+                "logMessages": logs,
+                "preTokenBalances": [],
+                "postTokenBalances": [],
+                "rewards": [],
+                "loadedAddresses": { "writable": [], "readonly": [] },
+                "computeUnitsConsumed": 20000
+            }
+        })
+    }
+
+    fn read_fixture(
+        lines: &[serde_json::Value],
+    ) -> Vec<(String, EncodedConfirmedTransactionWithStatusMeta)> {
+        let path = std::env::temp_dir().join(format!(
+            "openbook-candles-archive-{}.jsonl.gz",
+            std::process::id()
+        ));
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        for line in lines {
+            writeln!(encoder, "{}", line).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        let mut txns = vec![];
+        for line in open_archive(&path).unwrap().lines() {
+            txns.extend(parse_archive_line(&line.unwrap()).unwrap());
+        }
+        std::fs::remove_file(&path).unwrap();
+        txns
+    }
+
+    #[test]
+    fn parses_fills_from_compressed_transactions_and_blocks() {
+        let market = Pubkey::new_unique();
+        let other_market = Pubkey::new_unique();
+        let target_markets = HashMap::from([(market, test_market("SOL/USDC"))]);
+        let program_id = Pubkey::new_unique();
+
+        let mut transaction =
+            transaction_json("sig-a", vec![invoke_log(program_id), test_fill_log(market)]);
+        transaction["slot"] = json!(100);
+        transaction["blockTime"] = json!(1_680_000_000);
+        let block = json!({
+            "slot": 200,
+            "blockTime": 1_680_000_100,
+            "blockhash": "11111111111111111111111111111111",
+            "previousBlockhash": "11111111111111111111111111111111",
+            "parentSlot": 199,
+            "transactions": [
+                transaction_json("sig-b", vec![invoke_log(program_id), test_fill_log(other_market)]),
+                transaction_json("sig-c", vec![invoke_log(program_id), test_fill_log(market)]),
+                // Not one of the configured programs
+                transaction_json("sig-d", vec![invoke_log(Pubkey::new_unique()), test_fill_log(market)]),
+            ]
+        });
+
+        let txns = read_fixture(&[transaction, block]);
+        let signatures: Vec<&str> = txns.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(signatures, vec!["sig-a", "sig-b", "sig-c", "sig-d"]);
+
+        let (fills, transactions) = parse_archive_batch(txns, &target_markets, &[program_id]);
+        let parsed: Vec<(&str, u64, i64, usize)> = fills
+            .iter()
+            .map(|f| (f.signature.as_str(), f.slot, f.block_time, f.log_index))
+            .collect();
+        assert_eq!(
+            parsed,
+            vec![
+                ("sig-a", 100, 1_680_000_000, 0),
+                ("sig-c", 200, 1_680_000_100, 1)
+            ]
+        );
+        // Only transactions with fills of the target markets are recorded
+        let recorded: Vec<&str> = transactions.iter().map(|t| t.signature.as_str()).collect();
+        assert_eq!(recorded, vec!["sig-a", "sig-c"]);
+        assert!(transactions.iter().all(|t| t.processed));
+        assert!(transactions
+            .iter()
+            .all(|t| t.program_pk == program_id.to_string()));
+    }
+}
@@ -0,0 +1,368 @@
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use log::debug;
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use std::collections::HashMap;
+use tokio::sync::mpsc::Sender;
+use tonic::{
+    metadata::AsciiMetadataValue,
+    transport::{ClientTlsConfig, Endpoint},
+    Request, Status,
+};
+
+use self::proto::{
+    geyser_client::GeyserClient, subscribe_update::UpdateOneof, CommitmentLevel, SubscribeRequest,
+    SubscribeRequestFilterAccounts, SubscribeRequestFilterTransactions, SubscribeUpdateAccount,
+    SubscribeUpdateTransaction,
+};
+
+use crate::structs::markets::MarketInfo;
+
+use super::{
+    logs::invoked_program,
+    source::{parse_streamed_transaction, IngestionSource, IngestionUpdate, SourceFilter},
+};
+
+pub mod proto {
+    tonic::include_proto!("geyser");
+}
+
+/// Streams transactions and accounts from a Yellowstone compatible geyser gRPC endpoint
+pub struct GeyserSource {
+    grpc_url: String,
+    x_token: Option<String>,
+}
+
+impl GeyserSource {
+    pub fn new(grpc_url: String, x_token: Option<String>) -> Self {
+        GeyserSource { grpc_url, x_token }
+    }
+}
+
+#[async_trait]
+impl IngestionSource for GeyserSource {
+    fn name(&self) -> &'static str {
+        "geyser"
+    }
+
+    async fn subscribe(
+        &self,
+        filter: &SourceFilter,
+        sender: &Sender<IngestionUpdate>,
+    ) -> anyhow::Result<()> {
+        let mut endpoint = Endpoint::from_shared(self.grpc_url.clone())?;
+        if self.grpc_url.starts_with("https") {
+            endpoint = endpoint.tls_config(ClientTlsConfig::new())?;
+        }
+        let channel = endpoint.connect().await?;
+        let x_token = match &self.x_token {
+            Some(t) => Some(t.parse::<AsciiMetadataValue>()?),
+            None => None,
+        };
+        let mut client = GeyserClient::with_interceptor(
+            channel,
+            move |mut req: Request<()>| -> Result<Request<()>, Status> {
+                if let Some(token) = x_token.clone() {
+                    req.metadata_mut().insert("x-token", token);
+                }
+                Ok(req)
+            },
+        );
+
+        // Keep the request stream open, some servers end the subscription when it closes
+        let requests = stream::iter(vec![build_subscribe_request(filter)]).chain(stream::pending());
+        let mut updates = client.subscribe(requests).await?.into_inner();
+        debug!("Subscribed to geyser at {}", self.grpc_url);
+
+        while let Some(update) = updates.message().await? {
+            let update = match update.update_oneof {
+                Some(UpdateOneof::Transaction(txn)) => {
+                    parse_transaction_update(txn, &filter.target_markets, &filter.programs)
+                }
+                Some(UpdateOneof::Account(account)) => parse_account_update(account),
+                _ => None,
+            };
+            if let Some(u) = update {
+                if sender.send(u).await.is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn build_subscribe_request(filter: &SourceFilter) -> SubscribeRequest {
+    let mut accounts = HashMap::new();
+    if !filter.accounts.is_empty() {
+        accounts.insert(
+            "openbook_accounts".to_string(),
+            SubscribeRequestFilterAccounts {
+                account: filter.accounts.iter().map(|a| a.to_string()).collect(),
+                owner: vec![],
+            },
+        );
+    }
+
+    let mut transactions = HashMap::new();
+    if !filter.target_markets.is_empty() && !filter.programs.is_empty() {
+        transactions.insert(
+            "openbook_transactions".to_string(),
+            SubscribeRequestFilterTransactions {
+                vote: Some(false),
+                failed: Some(false),
+                account_include: filter.programs.iter().map(|p| p.to_string()).collect(),
+                account_exclude: vec![],
+            },
+        );
+    }
+
+    SubscribeRequest {
+        accounts,
+        transactions,
+        commitment: Some(CommitmentLevel::Confirmed as i32),
+    }
+}
+
+/// Transactions are matched to the program they invoke, since the subscription covers them all
+fn parse_transaction_update(
+    update: SubscribeUpdateTransaction,
+    target_markets: &HashMap<Pubkey, MarketInfo>,
+    programs: &[Pubkey],
+) -> Option<IngestionUpdate> {
+    let info = update.transaction?;
+    if info.signature.len() != 64 {
+        return None;
+    }
+    let signature = Signature::new(&info.signature).to_string();
+    let meta = info.meta?;
+    let program_id = invoked_program(&meta.log_messages, programs)?;
+    Some(parse_streamed_transaction(
+        signature,
+        update.slot,
+        meta.err.is_some(),
+        &meta.log_messages,
+        target_markets,
+        &program_id,
+    ))
+}
+
+fn parse_account_update(update: SubscribeUpdateAccount) -> Option<IngestionUpdate> {
+    let info = update.account?;
+    Some(IngestionUpdate::Account {
+        pubkey: Pubkey::new_from_array(info.pubkey.try_into().ok()?),
+        slot: update.slot,
+        data: info.data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::{
+        geyser_server::{Geyser, GeyserServer},
+        SubscribeUpdate, SubscribeUpdateAccountInfo, SubscribeUpdatePing,
+        SubscribeUpdateTransactionInfo, TransactionError, TransactionStatusMeta,
+    };
+    use super::*;
+    use crate::{structs::markets::test_market, worker::trade_fetching::logs::test_fill_log};
+    use futures::Stream;
+    use std::pin::Pin;
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tonic::{transport::Server, Response, Streaming};
+
+    /// Records the first request of a subscription and its `x-token`, then replays `updates`
+    struct ReplayGeyser {
+        updates: Vec<SubscribeUpdate>,
+        requests: mpsc::UnboundedSender<(SubscribeRequest, Option<String>)>,
+    }
+
+    #[async_trait]
+    impl Geyser for ReplayGeyser {
+        type SubscribeStream =
+            Pin<Box<dyn Stream<Item = Result<SubscribeUpdate, Status>> + Send + 'static>>;
+
+        async fn subscribe(
+            &self,
+            request: Request<Streaming<SubscribeRequest>>,
+        ) -> Result<Response<Self::SubscribeStream>, Status> {
+            let x_token = request
+                .metadata()
+                .get("x-token")
+                .and_then(|t| t.to_str().ok())
+                .map(|t| t.to_string());
+            let first = request
+                .into_inner()
+                .message()
+                .await?
+                .ok_or_else(|| Status::invalid_argument("no subscribe request"))?;
+            self.requests.send((first, x_token)).unwrap();
+            let updates = self.updates.clone().into_iter().map(Ok);
+            Ok(Response::new(Box::pin(stream::iter(updates))))
+        }
+    }
+
+    /// Serves `geyser` on a local port, returning its URL
+    async fn serve(geyser: ReplayGeyser) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = Box::pin(stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(s, _)| s);
+            Some((stream, listener))
+        }));
+        tokio::spawn(
+            Server::builder()
+                .add_service(GeyserServer::new(geyser))
+                .serve_with_incoming(incoming),
+        );
+        url
+    }
+
+    fn transaction_update(
+        slot: u64,
+        signature: Vec<u8>,
+        logs: Vec<String>,
+        failed: bool,
+    ) -> SubscribeUpdate {
+        SubscribeUpdate {
+            filters: vec!["openbook_transactions".to_string()],
+            update_oneof: Some(UpdateOneof::Transaction(SubscribeUpdateTransaction {
+                transaction: Some(SubscribeUpdateTransactionInfo {
+                    signature,
+                    is_vote: false,
+                    meta: Some(TransactionStatusMeta {
+                        err: failed.then(|| TransactionError { err: vec![1] }),
+                        fee: 5000,
+                        log_messages: logs,
+                    }),
+                    index: 0,
+                }),
+                slot,
+            })),
+        }
+    }
+
+    fn account_update(pubkey: &Pubkey, slot: u64, data: Vec<u8>) -> SubscribeUpdate {
+        SubscribeUpdate {
+            filters: vec!["openbook_accounts".to_string()],
+            update_oneof: Some(UpdateOneof::Account(SubscribeUpdateAccount {
+                account: Some(SubscribeUpdateAccountInfo {
+                    pubkey: pubkey.to_bytes().to_vec(),
+                    lamports: 1,
+                    owner: Pubkey::new_unique().to_bytes().to_vec(),
+                    executable: false,
+                    rent_epoch: 0,
+                    data,
+                    write_version: 1,
+                }),
+                slot,
+                is_startup: false,
+            })),
+        }
+    }
+
+    fn test_filter(market: Pubkey, program_id: Pubkey, accounts: Vec<Pubkey>) -> SourceFilter {
+        SourceFilter {
+            target_markets: HashMap::from([(market, test_market("SOL/USDC"))]),
+            programs: vec![program_id],
+            accounts,
+        }
+    }
+
+    #[test]
+    fn subscribe_request_only_filters_what_is_needed() {
+        let market = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+        let bids = Pubkey::new_unique();
+
+        let request = build_subscribe_request(&test_filter(market, program_id, vec![bids]));
+        assert_eq!(request.commitment, Some(CommitmentLevel::Confirmed as i32));
+        assert_eq!(
+            request.accounts["openbook_accounts"].account,
+            vec![bids.to_string()]
+        );
+        let transactions = &request.transactions["openbook_transactions"];
+        assert_eq!(transactions.account_include, vec![program_id.to_string()]);
+        assert_eq!(
+            (transactions.vote, transactions.failed),
+            (Some(false), Some(false))
+        );
+
+        // The order book feed only wants accounts, and the worker only transactions
+        let accounts_only = build_subscribe_request(&SourceFilter {
+            accounts: vec![bids],
+            ..SourceFilter::default()
+        });
+        assert!(accounts_only.transactions.is_empty());
+        let transactions_only = build_subscribe_request(&test_filter(market, program_id, vec![]));
+        assert!(transactions_only.accounts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replays_updates_from_a_geyser_server() {
+        let market = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+        let bids = Pubkey::new_unique();
+        let invoke = format!("Program {} invoke [1]", program_id);
+        let signature = Signature::new_unique();
+        let updates = vec![
+            transaction_update(
+                100,
+                signature.as_ref().to_vec(),
+                vec![invoke.clone(), test_fill_log(market)],
+                false,
+            ),
+            // Not a full signature
+            transaction_update(
+                101,
+                vec![1; 32],
+                vec![invoke.clone(), test_fill_log(market)],
+                false,
+            ),
+            // Doesn't invoke a configured program
+            transaction_update(
+                102,
+                Signature::new_unique().as_ref().to_vec(),
+                vec![test_fill_log(market)],
+                false,
+            ),
+            SubscribeUpdate {
+                filters: vec![],
+                update_oneof: Some(UpdateOneof::Ping(SubscribeUpdatePing {})),
+            },
+            account_update(&bids, 103, vec![1, 2, 3]),
+        ];
+
+        let (requests, mut received_requests) = mpsc::unbounded_channel();
+        let url = serve(ReplayGeyser { updates, requests }).await;
+        let filter = test_filter(market, program_id, vec![bids]);
+        let (sender, mut receiver) = mpsc::channel(10);
+        // The server closes the stream once it has replayed everything
+        GeyserSource::new(url, Some("secret".to_string()))
+            .subscribe(&filter, &sender)
+            .await
+            .unwrap();
+
+        let (request, x_token) = received_requests.recv().await.unwrap();
+        assert_eq!(request, build_subscribe_request(&filter));
+        assert_eq!(x_token, Some("secret".to_string()));
+
+        match receiver.try_recv().unwrap() {
+            IngestionUpdate::Transaction { transaction, fills } => {
+                assert_eq!(transaction.signature, signature.to_string());
+                assert_eq!(transaction.slot, 100);
+                assert_eq!(transaction.program_pk, program_id.to_string());
+                assert!(!transaction.processed);
+                assert_eq!(fills.len(), 1);
+                assert_eq!((fills[0].market, fills[0].log_index), (market, 1));
+            }
+            IngestionUpdate::Account { .. } => panic!("expected a transaction first"),
+        }
+        match receiver.try_recv().unwrap() {
+            IngestionUpdate::Account { pubkey, slot, data } => {
+                assert_eq!((pubkey, slot, data), (bids, 103, vec![1, 2, 3]));
+            }
+            IngestionUpdate::Transaction { .. } => panic!("expected an account update"),
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use anchor_lang::Discriminator;
+use log::warn;
+use solana_sdk::pubkey::Pubkey;
+use std::{collections::HashMap, io::Error};
+
+use crate::structs::{
+    markets::MarketInfo,
+    openbook::{FillLog, OpenBookFillEvent, OpenBookFillEventRaw},
+};
+
+const PROGRAM_DATA: &str = "Program data: ";
+
+/// Fills are decoded from `Program data` logs. Those starting with the discriminator of v2's
+/// `FillLog` hold both sides of a match, the taker's is given the log index after the last log
+/// so the two don't collide. Anything else is tried as a v1 fill event.
+pub fn parse_openbook_fills_from_logs(
+    logs: &Vec<String>,
+    target_markets: &HashMap<Pubkey, MarketInfo>,
+    signature: String,
+    slot: u64,
+    block_time: i64,
+) -> Option<Vec<OpenBookFillEvent>> {
+    let mut fills_vector = Vec::<OpenBookFillEvent>::new();
+    for (idx, l) in logs.iter().enumerate() {
+        match l.strip_prefix(PROGRAM_DATA) {
+            Some(log) => {
+                let borsh_bytes = match anchor_lang::__private::base64::decode(log) {
+                    Ok(borsh_bytes) if borsh_bytes.len() >= 8 => borsh_bytes,
+                    _ => continue,
+                };
+                let mut slice: &[u8] = &borsh_bytes[8..];
+                if borsh_bytes[..8] == FillLog::DISCRIMINATOR[..] {
+                    let event: Result<FillLog, Error> =
+                        anchor_lang::AnchorDeserialize::deserialize(&mut slice);
+                    if let Ok(e) = event {
+                        if let Some(market) = target_markets.get(&e.market) {
+                            match e.into_events(
+                                market,
+                                signature.clone(),
+                                slot,
+                                block_time,
+                                idx,
+                                logs.len() + idx,
+                            ) {
+                                Some(events) => fills_vector.extend(events),
+                                None => warn!(
+                                    "dropping fill log {} of {}: amounts out of range",
+                                    idx, signature
+                                ),
+                            }
+                        }
+                    }
+                    continue;
+                }
+                let event: Result<OpenBookFillEventRaw, Error> =
+                    anchor_lang::AnchorDeserialize::deserialize(&mut slice);
+
+                match event {
+                    Ok(e) => {
+                        let fill_event = e.into_event(signature.clone(), slot, block_time, idx);
+                        if target_markets.contains_key(&fill_event.market) {
+                            fills_vector.push(fill_event);
+                        }
+                    }
+                    _ => continue,
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if !fills_vector.is_empty() {
+        Some(fills_vector)
+    } else {
+        None
+    }
+}
+
+/// The first of `programs` a transaction's logs show being invoked
+pub fn invoked_program(logs: &[String], programs: &[Pubkey]) -> Option<Pubkey> {
+    logs.iter().find_map(|l| {
+        programs
+            .iter()
+            .find(|p| l.starts_with(&format!("Program {} invoke", p)))
+            .copied()
+    })
+}
+
+/// The `Program data` log of a v1 taker fill in `market`, for tests of the sources
+#[cfg(test)]
+pub(crate) fn test_fill_log(market: Pubkey) -> String {
+    let event = OpenBookFillEventRaw {
+        market,
+        open_orders: Pubkey::new_unique(),
+        open_orders_owner: Pubkey::new_unique(),
+        bid: true,
+        maker: false,
+        native_qty_paid: 2_000_000,
+        native_qty_received: 100_000_000,
+        native_fee_or_rebate: 800,
+        order_id: 7,
+        owner_slot: 0,
+        fee_tier: 0,
+        client_order_id: None,
+        referrer_rebate: None,
+    };
+    format!(
+        "{}{}",
+        PROGRAM_DATA,
+        anchor_lang::__private::base64::encode(anchor_lang::Event::data(&event))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{markets::test_market, openbook::FillLog};
+
+    fn program_data(event: &impl anchor_lang::Event) -> String {
+        format!(
+            "{}{}",
+            PROGRAM_DATA,
+            anchor_lang::__private::base64::encode(event.data())
+        )
+    }
+
+    #[test]
+    fn v2_fill_logs_give_both_sides_distinct_log_indexes() {
+        let market = Pubkey::new_unique();
+        let target_markets = HashMap::from([(market, test_market("SOL/USDC"))]);
+        let log = FillLog {
+            market,
+            taker_side: 1,
+            maker_slot: 0,
+            maker_out: true,
+            timestamp: 1_680_000_000,
+            seq_num: 7,
+            maker: Pubkey::new_unique(),
+            maker_client_order_id: 0,
+            maker_fee: 0,
+            maker_timestamp: 1_680_000_000,
+            taker: Pubkey::new_unique(),
+            taker_client_order_id: 0,
+            taker_fee_ceil: 1,
+            price: 20,
+            quantity: 3,
+        };
+        let untracked = FillLog {
+            market: Pubkey::new_unique(),
+            ..log.clone()
+        };
+        let logs = vec![
+            "Program log: Instruction: PlaceOrder".to_string(),
+            program_data(&log),
+            program_data(&untracked),
+        ];
+
+        let fills = parse_openbook_fills_from_logs(&logs, &target_markets, "sig".to_string(), 1, 2)
+            .unwrap();
+        let parsed: Vec<(bool, bool, usize)> = fills
+            .iter()
+            .map(|f| (f.maker, f.bid, f.log_index))
+            .collect();
+        assert_eq!(parsed, vec![(true, true, 1), (false, false, 4)]);
+    }
+
+    #[test]
+    fn invoked_programs_are_read_from_invoke_logs() {
+        let openbook = Pubkey::new_unique();
+        let fork = Pubkey::new_unique();
+        let programs = [openbook, fork];
+        let logs = vec![
+            "Program ComputeBudget111111111111111111111111111111 invoke [1]".to_string(),
+            format!("Program log: {} invoke [1]", openbook),
+            format!("Program {} invoke [2]", fork),
+            format!("Program {} invoke [1]", openbook),
+        ];
+        // The first program of `programs` to be invoked, not just mentioned
+        assert_eq!(invoked_program(&logs, &programs), Some(fork));
+        assert_eq!(invoked_program(&logs[..2], &programs), None);
+        assert_eq!(invoked_program(&logs, &[openbook]), Some(openbook));
+        assert_eq!(invoked_program(&[], &programs), None);
+    }
+}
@@ -1,2 +1,9 @@
+pub mod archive;
+pub mod geyser;
+pub mod logs;
 pub mod parsing;
 pub mod scrape;
+pub mod signatures;
+pub mod source;
+pub mod streaming;
+pub mod websocket;
@@ -4,20 +4,22 @@ use solana_sdk::pubkey::Pubkey;
 use solana_transaction_status::{
     option_serializer::OptionSerializer, EncodedConfirmedTransactionWithStatusMeta,
 };
-use std::{collections::HashMap, io::Error};
+use std::collections::HashMap;
 
 use crate::{
-    structs::openbook::{OpenBookFillEvent, OpenBookFillEventRaw},
+    structs::{markets::MarketInfo, openbook::OpenBookFillEvent},
     worker::metrics::METRIC_RPC_ERRORS_TOTAL,
 };
 
-const PROGRAM_DATA: &str = "Program data: ";
+use super::logs::parse_openbook_fills_from_logs;
 
+/// The fills of the transactions, the signatures of those that were fetched, and the
+/// signatures and errors of those that weren't
 pub fn parse_trades_from_openbook_txns(
     txns: &mut Vec<ClientResult<EncodedConfirmedTransactionWithStatusMeta>>,
     mut sig_strings: Vec<String>,
-    target_markets: &HashMap<Pubkey, String>,
-) -> (Vec<OpenBookFillEvent>, Vec<String>) {
+    target_markets: &HashMap<Pubkey, MarketInfo>,
+) -> (Vec<OpenBookFillEvent>, Vec<String>, Vec<(String, String)>) {
     let mut fills_vector = Vec::<OpenBookFillEvent>::new();
     let mut failed_sigs = vec![];
     for (idx, txn) in txns.iter_mut().enumerate() {
@@ -30,6 +32,7 @@ pub fn parse_trades_from_openbook_txns(
                                 logs,
                                 target_markets,
                                 sig_strings[idx].clone(),
+                                t.slot,
                                 t.block_time.unwrap(),
                             ) {
                                 Some(mut events) => fills_vector.append(&mut events),
@@ -43,52 +46,40 @@ pub fn parse_trades_from_openbook_txns(
             }
             Err(e) => {
                 warn!("rpc error in get_transaction {}", e);
-                failed_sigs.push(sig_strings[idx].clone());
+                failed_sigs.push((sig_strings[idx].clone(), e.to_string()));
                 METRIC_RPC_ERRORS_TOTAL
                     .with_label_values(&["getTransaction"])
                     .inc();
             }
         }
     }
-    sig_strings.retain(|s| !failed_sigs.contains(s));
-    (fills_vector, sig_strings)
+    sig_strings.retain(|s| !failed_sigs.iter().any(|(f, _)| f == s));
+    (fills_vector, sig_strings, failed_sigs)
 }
 
-fn parse_openbook_fills_from_logs(
-    logs: &Vec<String>,
-    target_markets: &HashMap<Pubkey, String>,
-    signature: String,
-    block_time: i64,
-) -> Option<Vec<OpenBookFillEvent>> {
-    let mut fills_vector = Vec::<OpenBookFillEvent>::new();
-    for (idx, l) in logs.iter().enumerate() {
-        match l.strip_prefix(PROGRAM_DATA) {
-            Some(log) => {
-                let borsh_bytes = match anchor_lang::__private::base64::decode(log) {
-                    Ok(borsh_bytes) => borsh_bytes,
-                    _ => continue,
-                };
-                let mut slice: &[u8] = &borsh_bytes[8..];
-                let event: Result<OpenBookFillEventRaw, Error> =
-                    anchor_lang::AnchorDeserialize::deserialize(&mut slice);
-
-                match event {
-                    Ok(e) => {
-                        let fill_event = e.into_event(signature.clone(), block_time, idx);
-                        if target_markets.contains_key(&fill_event.market) {
-                            fills_vector.push(fill_event);
-                        }
-                    }
-                    _ => continue,
-                }
-            }
-            _ => (),
-        }
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_client::client_error::{ClientError, ClientErrorKind};
 
-    if !fills_vector.is_empty() {
-        Some(fills_vector)
-    } else {
-        None
+    #[test]
+    fn failed_fetches_are_returned_with_their_errors() {
+        let mut txns = vec![
+            Err(ClientError::from(ClientErrorKind::Custom(
+                "rate limited".to_string(),
+            ))),
+            Err(ClientError::from(ClientErrorKind::Custom(
+                "not found".to_string(),
+            ))),
+        ];
+        let sig_strings = vec!["sig-1".to_string(), "sig-2".to_string()];
+        let (fills, completed, failed) =
+            parse_trades_from_openbook_txns(&mut txns, sig_strings, &HashMap::new());
+        assert!(fills.is_empty());
+        assert!(completed.is_empty());
+        assert_eq!(failed.len(), 2);
+        assert_eq!(failed[0].0, "sig-1");
+        assert!(failed[0].1.contains("rate limited"));
+        assert_eq!(failed[1].0, "sig-2");
     }
 }
@@ -1,119 +1,270 @@
+use chrono::Utc;
 use deadpool_postgres::Pool;
 use futures::future::join_all;
-use log::{debug, warn};
-use solana_client::{
-    nonblocking::rpc_client::RpcClient, rpc_client::GetConfirmedSignaturesForAddress2Config,
-    rpc_config::RpcTransactionConfig,
-};
+use log::{debug, info, warn};
+use solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcTransactionConfig};
 use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature};
 use solana_transaction_status::UiTransactionEncoding;
 use std::{collections::HashMap, time::Duration as WaitDuration};
 
 use crate::{
     database::{
-        fetch::fetch_worker_transactions,
-        insert::{build_transactions_insert_statement, insert_fills_atomically},
+        fetch::{fetch_open_scrape_gaps, fetch_scrape_cursor, fetch_worker_transactions},
+        insert::{
+            insert_fills_atomically, insert_gap_signatures, insert_head_signatures,
+            record_failed_fetches,
+        },
+    },
+    structs::{
+        markets::MarketInfo,
+        transaction::{PgTransaction, ScrapeCursor, ScrapeGap},
+    },
+    worker::metrics::{
+        METRIC_FILLS_TOTAL, METRIC_SCRAPE_GAPS_OPEN, METRIC_SCRAPE_GAPS_TOTAL,
+        METRIC_SCRAPE_LAG_SECONDS, METRIC_TRANSACTIONS_TOTAL,
     },
-    structs::transaction::PgTransaction,
-    utils::{AnyhowWrap, OPENBOOK_KEY},
-    worker::metrics::{METRIC_FILLS_TOTAL, METRIC_RPC_ERRORS_TOTAL, METRIC_TRANSACTIONS_TOTAL},
 };
 
-use super::parsing::parse_trades_from_openbook_txns;
+use super::{
+    parsing::parse_trades_from_openbook_txns,
+    signatures::{newest_finalized, page_signatures, RpcSignatureSource, SignatureSource},
+};
 
-pub async fn scrape_signatures(rpc_url: String, pool: &Pool) -> anyhow::Result<()> {
-    let rpc_client = RpcClient::new_with_commitment(rpc_url.clone(), CommitmentConfig::confirmed());
+/// Full pages read back from the newest signature towards the cursor on each poll. If that
+/// isn't enough to reach the cursor, the rest is recorded as a gap and paged separately.
+const HEAD_MAX_PAGES: usize = 5;
+/// Pages read from each open gap on each poll, so closing gaps doesn't hold up the head
+const GAP_MAX_PAGES: usize = 5;
+
+/// Scrapes the signatures of one program, the worker runs one of these per program
+pub async fn scrape_signatures(
+    rpc_url: String,
+    pool: &Pool,
+    program_id: Pubkey,
+) -> anyhow::Result<()> {
+    let source = RpcSignatureSource::new(rpc_url, program_id);
+    let program = program_id.to_string();
 
     loop {
-        let rpc_config = GetConfirmedSignaturesForAddress2Config {
-            before: None,
-            until: None,
-            limit: None,
-            commitment: Some(CommitmentConfig::confirmed()),
+        let cursor = match fetch_scrape_cursor(pool, &program).await {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("error fetching the scrape cursor of {}: {:?}", program, e);
+                tokio::time::sleep(WaitDuration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let cursor = match scrape_head(&source, pool, &program_id, cursor).await {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("error scraping new signatures of {}: {:?}", program, e);
+                tokio::time::sleep(WaitDuration::from_secs(1)).await;
+                continue;
+            }
         };
+        let open_gaps = match scrape_gaps(&source, pool, &program_id).await {
+            Ok(g) => g,
+            Err(e) => {
+                warn!("error scraping signature gaps of {}: {:?}", program, e);
+                tokio::time::sleep(WaitDuration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        // Lag is measured from the start of the oldest open gap, or from the cursor if there is none
+        let oldest_unscraped = open_gaps
+            .iter()
+            .map(|g| g.until_datetime)
+            .chain(cursor.map(|c| c.block_datetime))
+            .min();
+        if let Some(oldest) = oldest_unscraped {
+            METRIC_SCRAPE_LAG_SECONDS
+                .with_label_values(&[&program])
+                .set((Utc::now() - oldest).num_seconds());
+        }
+        METRIC_SCRAPE_GAPS_OPEN
+            .with_label_values(&[&program])
+            .set(open_gaps.len() as i64);
+    }
+    // TODO: graceful shutdown
+}
+
+/// Scrapes signatures newer than the cursor, returning the new cursor
+async fn scrape_head(
+    source: &dyn SignatureSource,
+    pool: &Pool,
+    program_id: &Pubkey,
+    cursor: Option<ScrapeCursor>,
+) -> anyhow::Result<Option<ScrapeCursor>> {
+    let until = cursor.as_ref().map(|c| c.signature.as_str());
+    let pages = page_signatures(source, None, until, HEAD_MAX_PAGES).await?;
+    if pages.signatures.is_empty() {
+        debug!("No new signatures found");
+        return Ok(cursor);
+    }
+    let finalized = newest_finalized(&pages.signatures);
+    let transactions: Vec<PgTransaction> = pages
+        .signatures
+        .into_iter()
+        .map(|s| PgTransaction::from_rpc_confirmed_transaction(s, program_id))
+        .collect();
+
+    // Without a cursor there's nothing to page back to, older signatures are left to backfills
+    let gap = match &cursor {
+        Some(c) if !pages.reached_until => {
+            let gap = ScrapeGap::between(transactions.last().unwrap(), c);
+            warn!(
+                "More than {} new signatures since slot {}, recording a gap back to it",
+                transactions.len(),
+                gap.until_slot
+            );
+            METRIC_SCRAPE_GAPS_TOTAL
+                .with_label_values(&[&c.program_id])
+                .inc();
+            Some(gap)
+        }
+        _ => None,
+    };
+    // Signatures newer than the cursor are paged again on the next poll until they're finalized
+    let new_cursor = finalized.map(|i| ScrapeCursor::from_transaction(&transactions[i]));
 
-        let sigs = match rpc_client
-            .get_signatures_for_address_with_config(&OPENBOOK_KEY, rpc_config)
-            .await
+    debug!("Scraper writing: {:?} txns to DB\n", transactions.len());
+    let num_txns =
+        insert_head_signatures(pool, &transactions, new_cursor.as_ref(), gap.as_ref()).await?;
+    METRIC_TRANSACTIONS_TOTAL.inc_by(num_txns);
+    Ok(new_cursor.or(cursor))
+}
+
+/// Pages through each open gap, returning the gaps that are still open
+async fn scrape_gaps(
+    source: &dyn SignatureSource,
+    pool: &Pool,
+    program_id: &Pubkey,
+) -> anyhow::Result<Vec<ScrapeGap>> {
+    let mut open_gaps = vec![];
+    for mut gap in fetch_open_scrape_gaps(pool, &program_id.to_string()).await? {
+        let pages = match page_signatures(
+            source,
+            Some(gap.before_signature.clone()),
+            Some(&gap.until_signature),
+            GAP_MAX_PAGES,
+        )
+        .await
         {
-            Ok(sigs) => sigs,
+            Ok(p) => p,
             Err(e) => {
-                warn!("rpc error in get_signatures_for_address_with_config: {}", e);
-                METRIC_RPC_ERRORS_TOTAL
-                    .with_label_values(&["getSignaturesForAddress"])
-                    .inc();
+                warn!("error scraping signature gap {}: {:?}", gap.id, e);
+                open_gaps.push(gap);
                 continue;
             }
         };
-        if sigs.is_empty() {
-            debug!("No signatures found, trying again");
-            continue;
-        }
-        let transactions: Vec<PgTransaction> = sigs
+        let transactions: Vec<PgTransaction> = pages
+            .signatures
             .into_iter()
-            .map(PgTransaction::from_rpc_confirmed_transaction)
+            .map(|s| PgTransaction::from_rpc_confirmed_transaction(s, program_id))
             .collect();
+        if let Some(oldest) = transactions.last() {
+            gap.before_signature = oldest.signature.clone();
+            gap.before_slot = oldest.slot;
+        }
+        gap.signatures_found += transactions.len() as i64;
 
-        debug!("Scraper writing: {:?} txns to DB\n", transactions.len());
-        let upsert_statement = build_transactions_insert_statement(transactions);
-        let client = pool.get().await?;
-        let num_txns = client
-            .execute(&upsert_statement, &[])
-            .await
-            .map_err_anyhow()?;
+        let num_txns =
+            insert_gap_signatures(pool, &transactions, &gap, pages.reached_until).await?;
         METRIC_TRANSACTIONS_TOTAL.inc_by(num_txns);
+        if pages.reached_until {
+            info!(
+                "Closed signature gap {} after {} signatures",
+                gap.id, gap.signatures_found
+            );
+        } else {
+            open_gaps.push(gap);
+        }
     }
-    // TODO: graceful shutdown
+    Ok(open_gaps)
 }
 
 pub async fn scrape_fills(
     worker_id: i32,
     rpc_url: String,
     pool: &Pool,
-    target_markets: &HashMap<Pubkey, String>,
+    target_markets: &HashMap<Pubkey, MarketInfo>,
 ) -> anyhow::Result<()> {
     debug!("Worker {} started \n", worker_id);
     let rpc_client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed());
 
     loop {
-        let transactions = fetch_worker_transactions(worker_id, pool).await?;
-        if transactions.is_empty() {
+        let num_txns =
+            scrape_fills_batch(worker_id, None, true, &rpc_client, pool, target_markets).await?;
+        if num_txns == 0 {
             debug!("No signatures found by worker {}", worker_id);
             tokio::time::sleep(WaitDuration::from_secs(1)).await;
-            continue;
-        };
+        }
+    }
+}
 
-        // for each signature, fetch the transaction
-        let txn_config = RpcTransactionConfig {
-            encoding: Some(UiTransactionEncoding::Json),
-            commitment: Some(CommitmentConfig::confirmed()),
-            max_supported_transaction_version: Some(0),
-        };
+/// Fetches and parses one batch of the worker's unprocessed transactions, returning how many
+/// there were. Backfill workers only fetch the transactions their backfill inserted, and if
+/// `target_markets` isn't every market they're handed back to the scraper once parsed.
+pub async fn scrape_fills_batch(
+    worker_id: i32,
+    backfill_name: Option<&str>,
+    all_markets: bool,
+    rpc_client: &RpcClient,
+    pool: &Pool,
+    target_markets: &HashMap<Pubkey, MarketInfo>,
+) -> anyhow::Result<usize> {
+    let transactions = fetch_worker_transactions(worker_id, backfill_name, pool).await?;
+    if transactions.is_empty() {
+        return Ok(0);
+    };
+    let num_txns = transactions.len();
 
-        let sig_strings = transactions
-            .iter()
-            .map(|t| t.signature.clone())
-            .collect::<Vec<String>>();
+    // for each signature, fetch the transaction
+    let txn_config = RpcTransactionConfig {
+        encoding: Some(UiTransactionEncoding::Json),
+        commitment: Some(CommitmentConfig::confirmed()),
+        max_supported_transaction_version: Some(0),
+    };
 
-        let signatures: Vec<_> = transactions
-            .into_iter()
-            .map(|t| t.signature.parse::<Signature>().unwrap())
-            .collect();
+    let sig_strings = transactions
+        .iter()
+        .map(|t| t.signature.clone())
+        .collect::<Vec<String>>();
 
-        let txn_futs: Vec<_> = signatures
-            .iter()
-            .map(|s| rpc_client.get_transaction_with_config(s, txn_config))
-            .collect();
+    let signatures: Vec<_> = transactions
+        .into_iter()
+        .map(|t| t.signature.parse::<Signature>().unwrap())
+        .collect();
 
-        let mut txns = join_all(txn_futs).await;
+    let txn_futs: Vec<_> = signatures
+        .iter()
+        .map(|s| rpc_client.get_transaction_with_config(s, txn_config))
+        .collect();
 
-        let (fills, completed_sigs) =
-            parse_trades_from_openbook_txns(&mut txns, sig_strings, target_markets);
-        for fill in fills.iter() {
-            let market_name = target_markets.get(&fill.market).unwrap();
-            METRIC_FILLS_TOTAL.with_label_values(&[market_name]).inc();
-        }
-        // Write fills to the database, and update properly fetched transactions as processed
-        insert_fills_atomically(pool, worker_id, fills, completed_sigs).await?;
+    let mut txns = join_all(txn_futs).await;
+
+    let (fills, completed_sigs, failed_sigs) =
+        parse_trades_from_openbook_txns(&mut txns, sig_strings, target_markets);
+    for fill in fills.iter() {
+        let market_name = &target_markets.get(&fill.market).unwrap().name;
+        METRIC_FILLS_TOTAL.with_label_values(&[market_name]).inc();
+    }
+    // Write fills to the database, and update properly fetched transactions as processed
+    insert_fills_atomically(
+        pool,
+        worker_id,
+        fills,
+        completed_sigs,
+        all_markets,
+        target_markets,
+    )
+    .await?;
+    // Failed transactions are retried a few times, backing off in case the node is rate
+    // limiting
+    if !failed_sigs.is_empty() {
+        record_failed_fetches(pool, worker_id, &failed_sigs).await?;
+        tokio::time::sleep(WaitDuration::from_secs(1)).await;
     }
+    Ok(num_txns)
 }
@@ -0,0 +1,256 @@
+use async_trait::async_trait;
+use solana_client::{
+    nonblocking::rpc_client::RpcClient, rpc_client::GetConfirmedSignaturesForAddress2Config,
+    rpc_response::RpcConfirmedTransactionStatusWithSignature,
+};
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature};
+use solana_transaction_status::TransactionConfirmationStatus;
+
+use crate::worker::metrics::METRIC_RPC_ERRORS_TOTAL;
+
+/// The most signatures `getSignaturesForAddress` returns in one call
+pub const SIGNATURE_PAGE_LIMIT: usize = 1000;
+
+/// Where the scraper pages signatures from, so pagination can be tested without a node
+#[async_trait]
+pub trait SignatureSource: Send + Sync {
+    /// Up to `limit` signatures older than `before` and newer than `until`, newest first
+    async fn get_signatures(
+        &self,
+        before: Option<&str>,
+        until: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<RpcConfirmedTransactionStatusWithSignature>>;
+}
+
+pub struct RpcSignatureSource {
+    rpc_client: RpcClient,
+    address: Pubkey,
+}
+
+impl RpcSignatureSource {
+    pub fn new(rpc_url: String, address: Pubkey) -> Self {
+        RpcSignatureSource {
+            rpc_client: RpcClient::new_with_commitment(rpc_url, CommitmentConfig::confirmed()),
+            address,
+        }
+    }
+}
+
+#[async_trait]
+impl SignatureSource for RpcSignatureSource {
+    async fn get_signatures(
+        &self,
+        before: Option<&str>,
+        until: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<RpcConfirmedTransactionStatusWithSignature>> {
+        let rpc_config = GetConfirmedSignaturesForAddress2Config {
+            before: before.map(|s| s.parse::<Signature>()).transpose()?,
+            until: until.map(|s| s.parse::<Signature>()).transpose()?,
+            limit: Some(limit),
+            commitment: Some(CommitmentConfig::confirmed()),
+        };
+        self.rpc_client
+            .get_signatures_for_address_with_config(&self.address, rpc_config)
+            .await
+            .map_err(|e| {
+                METRIC_RPC_ERRORS_TOTAL
+                    .with_label_values(&["getSignaturesForAddress"])
+                    .inc();
+                e.into()
+            })
+    }
+}
+
+#[derive(Debug)]
+pub struct SignaturePages {
+    /// Newest first
+    pub signatures: Vec<RpcConfirmedTransactionStatusWithSignature>,
+    /// Whether paging stopped at `until` (or the first signature of the address), rather than
+    /// after `max_pages` full pages. If not, paging can resume before the last signature.
+    pub reached_until: bool,
+}
+
+/// Pages backwards from `before` towards `until`, reading at most `max_pages` pages
+pub async fn page_signatures(
+    source: &dyn SignatureSource,
+    before: Option<String>,
+    until: Option<&str>,
+    max_pages: usize,
+) -> anyhow::Result<SignaturePages> {
+    let mut signatures = vec![];
+    let mut before = before;
+    for _ in 0..max_pages {
+        let page = source
+            .get_signatures(before.as_deref(), until, SIGNATURE_PAGE_LIMIT)
+            .await?;
+        let full_page = page.len() >= SIGNATURE_PAGE_LIMIT;
+        if let Some(last) = page.last() {
+            before = Some(last.signature.clone());
+        }
+        signatures.extend(page);
+        if !full_page {
+            return Ok(SignaturePages {
+                signatures,
+                reached_until: true,
+            });
+        }
+    }
+    Ok(SignaturePages {
+        signatures,
+        reached_until: false,
+    })
+}
+
+/// The position of the newest finalized signature. Only these become the scrape cursor, since
+/// a confirmed one can still be dropped by a fork, and an `until` that was dropped is never
+/// reached, so paging would carry on back through the whole history.
+pub fn newest_finalized(
+    signatures: &[RpcConfirmedTransactionStatusWithSignature],
+) -> Option<usize> {
+    signatures.iter().position(|s| {
+        matches!(
+            s.confirmation_status,
+            Some(TransactionConfirmationStatus::Finalized)
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Serves signatures `sig-{slot}` like `getSignaturesForAddress`, newest first
+    struct MockSignatureSource {
+        signatures: Vec<RpcConfirmedTransactionStatusWithSignature>,
+        calls: Mutex<usize>,
+    }
+
+    impl MockSignatureSource {
+        fn new(num_signatures: u64) -> Self {
+            let signatures = (0..num_signatures)
+                .rev()
+                .map(|slot| RpcConfirmedTransactionStatusWithSignature {
+                    signature: format!("sig-{}", slot),
+                    slot,
+                    err: None,
+                    memo: None,
+                    block_time: Some(slot as i64),
+                    confirmation_status: None,
+                })
+                .collect();
+            MockSignatureSource {
+                signatures,
+                calls: Mutex::new(0),
+            }
+        }
+
+        fn position(&self, signature: Option<&str>) -> Option<usize> {
+            signature.map(|s| {
+                self.signatures
+                    .iter()
+                    .position(|t| t.signature == s)
+                    .unwrap()
+            })
+        }
+    }
+
+    #[async_trait]
+    impl SignatureSource for MockSignatureSource {
+        async fn get_signatures(
+            &self,
+            before: Option<&str>,
+            until: Option<&str>,
+            limit: usize,
+        ) -> anyhow::Result<Vec<RpcConfirmedTransactionStatusWithSignature>> {
+            *self.calls.lock().unwrap() += 1;
+            let start = self.position(before).map_or(0, |i| i + 1);
+            let end = self.position(until).unwrap_or(self.signatures.len());
+            Ok(self.signatures[start..end.max(start)]
+                .iter()
+                .take(limit)
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn slots(pages: &SignaturePages) -> Vec<u64> {
+        pages.signatures.iter().map(|s| s.slot).collect()
+    }
+
+    #[tokio::test]
+    async fn pages_back_to_until() {
+        let source = MockSignatureSource::new(2500);
+        let pages = page_signatures(&source, None, Some("sig-100"), 5)
+            .await
+            .unwrap();
+        assert!(pages.reached_until);
+        assert_eq!(slots(&pages), (101..2500).rev().collect::<Vec<u64>>());
+        assert_eq!(*source.calls.lock().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn partial_page_reaches_the_start_of_history() {
+        let source = MockSignatureSource::new(10);
+        let pages = page_signatures(&source, None, None, 5).await.unwrap();
+        assert!(pages.reached_until);
+        assert_eq!(pages.signatures.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn resumes_a_gap_without_missing_signatures() {
+        let source = MockSignatureSource::new(5000);
+
+        // The head poll stops after two full pages, leaving a gap back to the cursor
+        let head = page_signatures(&source, None, Some("sig-500"), 2)
+            .await
+            .unwrap();
+        assert!(!head.reached_until);
+        assert_eq!(slots(&head), (3000..5000).rev().collect::<Vec<u64>>());
+
+        // Paging the gap from the oldest signature of the head poll closes it
+        let mut scraped = slots(&head);
+        let mut before = head.signatures.last().map(|s| s.signature.clone());
+        loop {
+            let gap = page_signatures(&source, before, Some("sig-500"), 1)
+                .await
+                .unwrap();
+            scraped.extend(slots(&gap));
+            if gap.reached_until {
+                break;
+            }
+            before = gap.signatures.last().map(|s| s.signature.clone());
+        }
+        assert_eq!(scraped, (501..5000).rev().collect::<Vec<u64>>());
+    }
+
+    #[tokio::test]
+    async fn nothing_new_since_the_cursor() {
+        let source = MockSignatureSource::new(100);
+        let pages = page_signatures(&source, None, Some("sig-99"), 5)
+            .await
+            .unwrap();
+        assert!(pages.reached_until);
+        assert!(pages.signatures.is_empty());
+    }
+
+    #[test]
+    fn cursors_are_only_finalized_signatures() {
+        let mut signatures = MockSignatureSource::new(4).signatures;
+        assert_eq!(newest_finalized(&signatures), None);
+
+        let statuses = [
+            Some(TransactionConfirmationStatus::Processed),
+            Some(TransactionConfirmationStatus::Confirmed),
+            Some(TransactionConfirmationStatus::Finalized),
+            Some(TransactionConfirmationStatus::Finalized),
+        ];
+        for (signature, status) in signatures.iter_mut().zip(statuses) {
+            signature.confirmation_status = status;
+        }
+        assert_eq!(newest_finalized(&signatures), Some(2));
+        assert_eq!(signatures[2].signature, "sig-1");
+    }
+}
@@ -0,0 +1,177 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use log::warn;
+use solana_sdk::pubkey::Pubkey;
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration as WaitDuration};
+use tokio::sync::mpsc::Sender;
+
+use crate::structs::{
+    markets::MarketInfo, openbook::OpenBookFillEvent, transaction::PgTransaction,
+};
+
+use super::{
+    geyser::GeyserSource, logs::parse_openbook_fills_from_logs, websocket::WebsocketSource,
+};
+
+pub enum IngestionUpdate {
+    Transaction {
+        transaction: PgTransaction,
+        fills: Vec<OpenBookFillEvent>,
+    },
+    Account {
+        pubkey: Pubkey,
+        slot: u64,
+        data: Vec<u8>,
+    },
+}
+
+/// What a source should subscribe to
+#[derive(Clone, Debug, Default)]
+pub struct SourceFilter {
+    /// Markets to parse fills for, leave empty to skip transactions entirely
+    pub target_markets: HashMap<Pubkey, MarketInfo>,
+    /// Programs whose transactions are streamed, those that own the target markets
+    pub programs: Vec<Pubkey>,
+    /// Accounts to stream updates for, e.g. the bids and asks of each market
+    pub accounts: Vec<Pubkey>,
+}
+
+/// A push based alternative to scraping signatures over RPC
+#[async_trait]
+pub trait IngestionSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Sends updates until the underlying connection closes or errors
+    async fn subscribe(
+        &self,
+        filter: &SourceFilter,
+        sender: &Sender<IngestionUpdate>,
+    ) -> anyhow::Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IngestionMode {
+    Rpc,
+    Websocket,
+    Geyser,
+}
+
+impl FromStr for IngestionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rpc" => Ok(IngestionMode::Rpc),
+            "websocket" | "ws" => Ok(IngestionMode::Websocket),
+            "geyser" | "grpc" => Ok(IngestionMode::Geyser),
+            _ => Err(anyhow::anyhow!("unknown ingestion source: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IngestionConfig {
+    pub mode: IngestionMode,
+    pub ws_url: Option<String>,
+    pub grpc_url: Option<String>,
+    pub grpc_x_token: Option<String>,
+}
+
+impl IngestionConfig {
+    /// `INGESTION_SOURCE` picks the source, defaulting to websocket if `WS_URL` is set and RPC otherwise
+    pub fn from_env() -> anyhow::Result<Self> {
+        let ws_url = dotenv::var("WS_URL").ok();
+        let grpc_url = dotenv::var("GRPC_URL").ok();
+        let grpc_x_token = dotenv::var("GRPC_X_TOKEN").ok();
+        let mode = match dotenv::var("INGESTION_SOURCE") {
+            Ok(m) => IngestionMode::from_str(&m)?,
+            Err(_) if ws_url.is_some() => IngestionMode::Websocket,
+            Err(_) => IngestionMode::Rpc,
+        };
+        Ok(IngestionConfig {
+            mode,
+            ws_url,
+            grpc_url,
+            grpc_x_token,
+        })
+    }
+}
+
+/// Returns None for RPC mode, which only uses the signature scraper
+pub fn build_source(config: &IngestionConfig) -> anyhow::Result<Option<Arc<dyn IngestionSource>>> {
+    match config.mode {
+        IngestionMode::Rpc => Ok(None),
+        IngestionMode::Websocket => {
+            let ws_url = config
+                .ws_url
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("WS_URL is required for websocket ingestion"))?;
+            Ok(Some(Arc::new(WebsocketSource::new(ws_url))))
+        }
+        IngestionMode::Geyser => {
+            let grpc_url = config
+                .grpc_url
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("GRPC_URL is required for geyser ingestion"))?;
+            Ok(Some(Arc::new(GeyserSource::new(
+                grpc_url,
+                config.grpc_x_token.clone(),
+            ))))
+        }
+    }
+}
+
+/// Keeps a source subscribed, reconnecting whenever it drops or errors. Returns once the
+/// receiver is gone. `on_error` is called with the source's name whenever the subscription
+/// errors, so callers can count errors in their own metrics.
+pub async fn run_source(
+    source: Arc<dyn IngestionSource>,
+    filter: SourceFilter,
+    sender: Sender<IngestionUpdate>,
+    on_error: fn(&str),
+) {
+    if filter.target_markets.is_empty() && filter.accounts.is_empty() {
+        return;
+    }
+    while !sender.is_closed() {
+        match source.subscribe(&filter, &sender).await {
+            Ok(_) => warn!("{} subscription closed, reconnecting", source.name()),
+            Err(e) => {
+                warn!(
+                    "error in {} subscription: {}, reconnecting",
+                    source.name(),
+                    e
+                );
+                on_error(source.name());
+            }
+        }
+        tokio::time::sleep(WaitDuration::from_secs(1)).await;
+    }
+}
+
+/// Streamed transactions don't carry a block time, so the time they were received is used
+/// until the scraper fetches the transaction and restamps its fills with the block time
+pub fn parse_streamed_transaction(
+    signature: String,
+    slot: u64,
+    failed: bool,
+    logs: &Vec<String>,
+    target_markets: &HashMap<Pubkey, MarketInfo>,
+    program_id: &Pubkey,
+) -> IngestionUpdate {
+    let block_time = Utc::now().timestamp();
+    let transaction = PgTransaction::from_logs_notification(
+        signature.clone(),
+        slot,
+        block_time,
+        failed,
+        program_id,
+    );
+    let fills = if failed {
+        Vec::new()
+    } else {
+        parse_openbook_fills_from_logs(logs, target_markets, signature, slot, block_time)
+            .unwrap_or_default()
+    };
+    IngestionUpdate::Transaction { transaction, fills }
+}
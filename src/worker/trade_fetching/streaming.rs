@@ -0,0 +1,61 @@
+use deadpool_postgres::Pool;
+use log::{debug, warn};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use tokio::sync::mpsc::Receiver;
+
+use crate::{
+    database::insert::insert_streamed_fills, structs::markets::MarketInfo,
+    worker::metrics::METRIC_FILLS_TOTAL,
+};
+
+use super::source::IngestionUpdate;
+
+/// Max number of streamed transactions written to the database at once
+const STREAMED_BATCH_SIZE: usize = 100;
+
+/// Writes streamed fills to the database, batching whatever has queued up since the last write.
+/// Account updates are ignored. Only returns once every sender is gone.
+pub async fn write_streamed_fills(
+    pool: &Pool,
+    mut receiver: Receiver<IngestionUpdate>,
+    target_markets: &HashMap<Pubkey, MarketInfo>,
+) -> anyhow::Result<()> {
+    while let Some(update) = receiver.recv().await {
+        let mut fills = vec![];
+        let mut transactions = vec![];
+        let mut next = Some(update);
+        while let Some(update) = next {
+            if let IngestionUpdate::Transaction {
+                transaction,
+                fills: mut txn_fills,
+            } = update
+            {
+                transactions.push(transaction);
+                fills.append(&mut txn_fills);
+            }
+            next = if transactions.len() < STREAMED_BATCH_SIZE {
+                receiver.try_recv().ok()
+            } else {
+                None
+            };
+        }
+
+        for fill in fills.iter() {
+            let market_name = &target_markets.get(&fill.market).unwrap().name;
+            METRIC_FILLS_TOTAL.with_label_values(&[market_name]).inc();
+        }
+        let num_txns = transactions.len();
+        debug!(
+            "Writing {} streamed fills from {} txns",
+            fills.len(),
+            num_txns
+        );
+        // The signature scraper picks up whatever a failed write leaves out, so the batch is
+        // dropped rather than stopping the stream
+        if let Err(e) = insert_streamed_fills(pool, fills, transactions, target_markets).await {
+            warn!("failed to write {} streamed txns: {}", num_txns, e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use async_trait::async_trait;
+use futures::{
+    stream::{select_all, BoxStream},
+    StreamExt,
+};
+use log::debug;
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::{
+    nonblocking::pubsub_client::PubsubClient,
+    rpc_config::{RpcAccountInfoConfig, RpcTransactionLogsConfig, RpcTransactionLogsFilter},
+};
+use solana_sdk::{account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey};
+use tokio::sync::mpsc::Sender;
+
+use super::source::{parse_streamed_transaction, IngestionSource, IngestionUpdate, SourceFilter};
+
+/// Streams program logs with `logsSubscribe` and accounts with `accountSubscribe`
+pub struct WebsocketSource {
+    ws_url: String,
+}
+
+impl WebsocketSource {
+    pub fn new(ws_url: String) -> Self {
+        WebsocketSource { ws_url }
+    }
+}
+
+#[async_trait]
+impl IngestionSource for WebsocketSource {
+    fn name(&self) -> &'static str {
+        "websocket"
+    }
+
+    async fn subscribe(
+        &self,
+        filter: &SourceFilter,
+        sender: &Sender<IngestionUpdate>,
+    ) -> anyhow::Result<()> {
+        let pubsub_client = PubsubClient::new(&self.ws_url).await?;
+        let mut streams: Vec<BoxStream<IngestionUpdate>> = vec![];
+        let mut unsubscribes = vec![];
+
+        // Mentions only takes a single address, so each program gets its own subscription
+        let target_markets = &filter.target_markets;
+        let programs: &[Pubkey] = if target_markets.is_empty() {
+            &[]
+        } else {
+            &filter.programs
+        };
+        for program_id in programs.iter() {
+            let (logs, unsubscribe) = pubsub_client
+                .logs_subscribe(
+                    RpcTransactionLogsFilter::Mentions(vec![program_id.to_string()]),
+                    RpcTransactionLogsConfig {
+                        commitment: Some(CommitmentConfig::confirmed()),
+                    },
+                )
+                .await?;
+            streams.push(
+                logs.map(move |notification| {
+                    parse_streamed_transaction(
+                        notification.value.signature,
+                        notification.context.slot,
+                        notification.value.err.is_some(),
+                        &notification.value.logs,
+                        target_markets,
+                        program_id,
+                    )
+                })
+                .boxed(),
+            );
+            unsubscribes.push(unsubscribe);
+            debug!("Subscribed to logs for {}", program_id);
+        }
+
+        for pubkey in filter.accounts.iter() {
+            let account_config = RpcAccountInfoConfig {
+                encoding: Some(UiAccountEncoding::Base64),
+                data_slice: None,
+                commitment: Some(CommitmentConfig::confirmed()),
+                min_context_slot: None,
+            };
+            let (accounts, unsubscribe) = pubsub_client
+                .account_subscribe(pubkey, Some(account_config))
+                .await?;
+            let pubkey = *pubkey;
+            streams.push(
+                accounts
+                    .filter_map(move |notification| async move {
+                        let account = notification.value.decode::<Account>()?;
+                        Some(IngestionUpdate::Account {
+                            pubkey,
+                            slot: notification.context.slot,
+                            data: account.data,
+                        })
+                    })
+                    .boxed(),
+            );
+            unsubscribes.push(unsubscribe);
+        }
+
+        let mut updates = select_all(streams);
+        while let Some(update) = updates.next().await {
+            if sender.send(update).await.is_err() {
+                break;
+            }
+        }
+        drop(updates);
+
+        for unsubscribe in unsubscribes {
+            unsubscribe().await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{structs::markets::test_market, worker::trade_fetching::logs::test_fill_log};
+    use futures::SinkExt;
+    use serde_json::{json, Value};
+    use std::{collections::HashMap, time::Duration};
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+    const SUBSCRIPTION_ID: u64 = 42;
+
+    /// A `logsNotification` frame as an RPC node sends it
+    fn logs_notification(slot: u64, signature: &str, err: Value, logs: Vec<String>) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "logsNotification",
+            "params": {
+                "result": {
+                    "context": { "slot": slot },
+                    "value": { "signature": signature, "err": err, "logs": logs }
+                },
+                "subscription": SUBSCRIPTION_ID
+            }
+        })
+    }
+
+    /// Serves a single connection, answering every request and replaying `notifications`
+    /// once logs are subscribed to. Returns the server's URL.
+    async fn mock_server(notifications: Vec<Value>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            while let Some(Ok(message)) = ws.next().await {
+                let request: Value = match message {
+                    Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                    _ => continue,
+                };
+                let method = request["method"].as_str().unwrap_or_default().to_string();
+                let result = match method.as_str() {
+                    "logsSubscribe" => json!(SUBSCRIPTION_ID),
+                    "getVersion" => json!({ "solana-core": "1.14.13" }),
+                    _ => json!(true),
+                };
+                let response = json!({ "jsonrpc": "2.0", "result": result, "id": request["id"] });
+                ws.send(Message::Text(response.to_string())).await.unwrap();
+                if method == "logsSubscribe" {
+                    for notification in notifications.iter() {
+                        ws.send(Message::Text(notification.to_string()))
+                            .await
+                            .unwrap();
+                    }
+                }
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn replays_recorded_log_notifications() {
+        let market = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+        let invoke = format!("Program {} invoke [1]", program_id);
+        let url = mock_server(vec![
+            logs_notification(
+                100,
+                "sig-a",
+                Value::Null,
+                vec![invoke.clone(), test_fill_log(market)],
+            ),
+            // Failed transactions are recorded without their fills
+            logs_notification(
+                101,
+                "sig-b",
+                json!({ "InstructionError": [0, { "Custom": 1 }] }),
+                vec![invoke.clone(), test_fill_log(market)],
+            ),
+            logs_notification(
+                102,
+                "sig-c",
+                Value::Null,
+                vec![invoke, test_fill_log(Pubkey::new_unique())],
+            ),
+        ])
+        .await;
+
+        let filter = SourceFilter {
+            target_markets: HashMap::from([(market, test_market("SOL/USDC"))]),
+            programs: vec![program_id],
+            accounts: vec![],
+        };
+        let (sender, mut receiver) = mpsc::channel(10);
+        let subscription = tokio::spawn(async move {
+            WebsocketSource::new(url)
+                .subscribe(&filter, &sender)
+                .await
+                .unwrap();
+        });
+
+        let mut received = vec![];
+        for _ in 0..3 {
+            let update = tokio::time::timeout(Duration::from_secs(10), receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match update {
+                IngestionUpdate::Transaction { transaction, fills } => {
+                    received.push((transaction, fills))
+                }
+                IngestionUpdate::Account { .. } => panic!("no accounts were subscribed to"),
+            }
+        }
+        subscription.abort();
+
+        let transactions: Vec<(&str, u64, bool, usize)> = received
+            .iter()
+            .map(|(t, fills)| (t.signature.as_str(), t.slot, t.err, fills.len()))
+            .collect();
+        assert_eq!(
+            transactions,
+            vec![
+                ("sig-a", 100, false, 1),
+                ("sig-b", 101, true, 0),
+                ("sig-c", 102, false, 0)
+            ]
+        );
+        // Left for the scraper to fetch the block time
+        assert!(received.iter().all(|(t, _)| !t.processed));
+        assert!(received
+            .iter()
+            .all(|(t, _)| t.program_pk == program_id.to_string()));
+
+        let fill = &received[0].1[0];
+        assert_eq!((fill.market, fill.slot, fill.log_index), (market, 100, 1));
+    }
+}